@@ -1,12 +1,13 @@
-use crate::key::Bytes;
+use crate::borker;
+use crate::codec::Encoding;
+use crate::network::Network;
+use crate::store::ReadStore;
 use failure::Error;
-use leveldb::database::Database;
-use leveldb::kv::KV;
-use leveldb::options::*;
 use std::collections::HashMap;
 
 pub fn handle_request(
-    db: &Database<Bytes>,
+    db: &dyn ReadStore,
+    network: Network,
     path_and_query: &http::uri::PathAndQuery,
 ) -> Result<UTXORes, Error> {
     match path_and_query.path() {
@@ -18,7 +19,16 @@ pub fn handle_request(
             let address = qparams
                 .get(&std::borrow::Cow::Borrowed("address"))
                 .ok_or(format_err!("missing address"))?;
-            Ok(UTXORes::Balance(get_balance(db, &address)?))
+            let by_maturity = match qparams.get(&std::borrow::Cow::Borrowed("byMaturity")) {
+                Some(a) => str::parse(&a)?,
+                None => false,
+            };
+            let balance = get_balance(db, network, &address)?;
+            if by_maturity {
+                Ok(UTXORes::SplitBalance(balance))
+            } else {
+                Ok(UTXORes::Balance(balance.total()))
+            }
         }
         "/utxos" => {
             let url = url::Url::parse(&format!("http://localhost/{}", path_and_query.as_str()))?;
@@ -37,87 +47,340 @@ pub fn handle_request(
                 Some(a) => Some(str::parse(&a)?),
                 None => None,
             };
-            Ok(UTXORes::UTXOs(get_utxos(db, &address, amount, min_count)?))
+            let include_immature = match qparams.get(&std::borrow::Cow::Borrowed("includeImmature"))
+            {
+                Some(a) => str::parse(&a)?,
+                None => false,
+            };
+            let utxos = get_utxos(db, network, &address, amount, min_count, include_immature)?;
+            if include_immature {
+                Ok(UTXORes::MarkedUTXOs(utxos))
+            } else {
+                Ok(UTXORes::UTXOs(utxos))
+            }
+        }
+        "/headers" => {
+            let url = url::Url::parse(&format!("http://localhost/{}", path_and_query.as_str()))?;
+            let qparams = url
+                .query_pairs()
+                .collect::<HashMap<std::borrow::Cow<'_, str>, std::borrow::Cow<'_, str>>>();
+            let from = qparams
+                .get(&std::borrow::Cow::Borrowed("from"))
+                .ok_or(format_err!("missing from"))?;
+            let from = str::parse(&from)?;
+            let count = match qparams.get(&std::borrow::Cow::Borrowed("count")) {
+                Some(a) => str::parse(&a)?,
+                None => MAX_HEADERS,
+            };
+            Ok(UTXORes::Headers(get_headers(db, from, count)?))
+        }
+        "/borker/posts" => {
+            let url = url::Url::parse(&format!("http://localhost/{}", path_and_query.as_str()))?;
+            let qparams = url
+                .query_pairs()
+                .collect::<HashMap<std::borrow::Cow<'_, str>, std::borrow::Cow<'_, str>>>();
+            let address = qparams
+                .get(&std::borrow::Cow::Borrowed("address"))
+                .ok_or(format_err!("missing address"))?;
+            let address = parse_address(network, &address)?;
+            let (cursor, limit) = feed_params(&qparams)?;
+            Ok(UTXORes::Messages(borker::posts(
+                db, &address, cursor, limit,
+            )?))
+        }
+        "/borker/replies" => {
+            let url = url::Url::parse(&format!("http://localhost/{}", path_and_query.as_str()))?;
+            let qparams = url
+                .query_pairs()
+                .collect::<HashMap<std::borrow::Cow<'_, str>, std::borrow::Cow<'_, str>>>();
+            let txid = hex::decode(
+                qparams
+                    .get(&std::borrow::Cow::Borrowed("txid"))
+                    .ok_or(format_err!("missing txid"))?
+                    .as_bytes(),
+            )?;
+            if txid.len() != 32 {
+                bail!("invalid txid length");
+            }
+            let (cursor, limit) = feed_params(&qparams)?;
+            Ok(UTXORes::Messages(borker::replies(
+                db, &txid, cursor, limit,
+            )?))
+        }
+        "/borker/timeline" => {
+            let url = url::Url::parse(&format!("http://localhost/{}", path_and_query.as_str()))?;
+            let qparams = url
+                .query_pairs()
+                .collect::<HashMap<std::borrow::Cow<'_, str>, std::borrow::Cow<'_, str>>>();
+            let (cursor, limit) = feed_params(&qparams)?;
+            Ok(UTXORes::Messages(borker::timeline(db, cursor, limit)?))
+        }
+        path if path.starts_with("/block/") => {
+            Ok(UTXORes::Block(get_block(db, &path["/block/".len()..])?))
         }
         _ => bail!("unsupported endpoint"),
     }
 }
 
-fn get_balance(db: &Database<Bytes>, address: &str) -> Result<u64, Error> {
-    let mut address_vec = bitcoin::util::base58::from_check(address)?;
+// The height and hash of the last indexed block, if any.
+pub fn tip(db: &dyn ReadStore) -> Result<Option<(u32, Vec<u8>)>, Error> {
+    let next = match db.get(&[0_u8])? {
+        Some(b) => {
+            let mut buf = [0_u8; 4];
+            if b.len() != 4 {
+                bail!("invalid size for u32");
+            }
+            buf.clone_from_slice(&b);
+            u32::from_ne_bytes(buf)
+        }
+        None => return Ok(None),
+    };
+    let mut block_key = Vec::with_capacity(5);
+    block_key.push(3_u8);
+    block_key.extend(&(next - 1).to_ne_bytes());
+    let hash = db
+        .get(&block_key)?
+        .ok_or(format_err!("missing block_hash"))?;
+    Ok(Some((next - 1, hash)))
+}
+
+fn read_u32(b: &[u8]) -> Result<u32, Error> {
+    let mut buf = [0_u8; 4];
+    if b.len() != 4 {
+        bail!("invalid size for u32");
+    }
+    buf.clone_from_slice(b);
+    Ok(u32::from_ne_bytes(buf))
+}
+
+// A block by height or hash, from the header chain and the txids indexed with it. Blocks indexed
+// before headers and txids were, or imported from a snapshot, have neither.
+pub fn get_block(db: &dyn ReadStore, id: &str) -> Result<BlockData, Error> {
+    let height = if id.len() == 64 {
+        let mut height_key = Vec::with_capacity(33);
+        height_key.push(9_u8);
+        height_key.extend(hex::decode(id)?);
+        read_u32(
+            &db.get(&height_key)?
+                .ok_or(format_err!("block {} is not indexed", id))?,
+        )?
+    } else {
+        str::parse(id)?
+    };
+    let mut block_key = Vec::with_capacity(5);
+    block_key.push(3_u8);
+    block_key.extend(&height.to_ne_bytes());
+    let hash = db
+        .get(&block_key)?
+        .ok_or(format_err!("block {} is not indexed", id))?;
+    block_key[0] = 8;
+    let header = db
+        .get(&block_key)?
+        .ok_or(format_err!("header of block {} is not indexed", height))?;
+    block_key[0] = 10;
+    let txids = db
+        .get(&block_key)?
+        .ok_or(format_err!("txids of block {} are not indexed", height))?;
+    let parsed: bitcoin::BlockHeader = bitcoin::consensus::encode::deserialize(&header)?;
+    let display = |h: &bitcoin_hashes::sha256d::Hash| {
+        let mut buf = [0_u8; 32];
+        buf.clone_from_slice(&h[..]);
+        buf.reverse();
+        buf
+    };
+    let mut hash_buf = [0_u8; 32];
+    hash_buf.clone_from_slice(&hash);
+    Ok(BlockData {
+        height,
+        hash: hash_buf,
+        version: parsed.version,
+        prev_hash: display(&parsed.prev_blockhash),
+        merkle_root: display(&parsed.merkle_root),
+        time: parsed.time,
+        bits: parsed.bits,
+        nonce: parsed.nonce,
+        header,
+        txids: txids
+            .chunks(32)
+            .map(|c| {
+                let mut txid = [0_u8; 32];
+                txid.clone_from_slice(c);
+                txid
+            })
+            .collect(),
+    })
+}
+
+pub const MAX_HEADERS: u32 = 2000;
+
+// Raw headers from height `from`, up to `count` of them and no further than the tip.
+pub fn get_headers(db: &dyn ReadStore, from: u32, count: u32) -> Result<Vec<Vec<u8>>, Error> {
+    if from < 1 {
+        bail!("headers start at height 1");
+    }
+    let last = match tip(db)? {
+        Some((height, _)) => height,
+        None => return Ok(Vec::new()),
+    };
+    let end = from.saturating_add(count.min(MAX_HEADERS)).min(last + 1);
+    let mut headers = Vec::new();
+    let mut header_key = Vec::with_capacity(5);
+    header_key.push(8_u8);
+    header_key.extend(&from.to_ne_bytes());
+    for height in from..end {
+        header_key[1..].clone_from_slice(&height.to_ne_bytes());
+        headers.push(
+            db.get(&header_key)?
+                .ok_or(format_err!("header of block {} is not indexed", height))?,
+        );
+    }
+    Ok(headers)
+}
+
+// Whether `txid` is a coinbase that cannot be spent in the next block. Dogecoin's maturity
+// depends on the height of the coinbase.
+fn is_immature(
+    db: &dyn ReadStore,
+    network: Network,
+    next: u32,
+    txid: &[u8],
+) -> Result<bool, Error> {
+    let mut coinbase_key = Vec::with_capacity(33);
+    coinbase_key.push(11_u8);
+    coinbase_key.extend(txid);
+    match db.get(&coinbase_key)? {
+        Some(b) => {
+            let height = read_u32(&b)?;
+            Ok(next.saturating_sub(height) < network.coinbase_maturity(height))
+        }
+        None => Ok(false),
+    }
+}
+
+pub(crate) fn next_height(db: &dyn ReadStore) -> Result<u32, Error> {
+    match db.get(&[0_u8])? {
+        Some(b) => read_u32(&b),
+        None => Ok(1),
+    }
+}
+
+pub const MAX_MESSAGES: usize = 500;
+
+// Borker feeds page by a cursor: `before` is the time of the last message of the previous page
+// and `afterTxid` its txid, so that messages sharing its block time are not skipped. Without
+// `afterTxid`, `before` is exclusive and defaults to now. `limit` defaults to 50.
+fn feed_params(
+    qparams: &HashMap<std::borrow::Cow<'_, str>, std::borrow::Cow<'_, str>>,
+) -> Result<(borker::Cursor, usize), Error> {
+    let mut cursor = borker::Cursor::default();
+    if let Some(a) = qparams.get(&std::borrow::Cow::Borrowed("before")) {
+        cursor.time = str::parse(&a)?;
+    }
+    if let Some(a) = qparams.get(&std::borrow::Cow::Borrowed("afterTxid")) {
+        if !qparams.contains_key(&std::borrow::Cow::Borrowed("before")) {
+            bail!("afterTxid needs before");
+        }
+        let txid = hex::decode(a.as_bytes())?;
+        if txid.len() != 32 {
+            bail!("invalid txid length");
+        }
+        let mut buf = [0_u8; 32];
+        buf.copy_from_slice(&txid);
+        cursor.after = Some(buf);
+    }
+    let limit = match qparams.get(&std::borrow::Cow::Borrowed("limit")) {
+        Some(a) => str::parse::<usize>(&a)?.min(MAX_MESSAGES),
+        None => 50,
+    };
+    Ok((cursor, limit))
+}
+
+pub fn parse_address(network: Network, address: &str) -> Result<[u8; 21], Error> {
+    let address_vec = bitcoin::util::base58::from_check(address)?;
     if address_vec.len() != 21 {
         bail!("invalid address length")
     }
+    if address_vec[0] != network.p2pkh() && address_vec[0] != network.p2sh() {
+        bail!("address is not for {:?}", network)
+    }
+    let mut buf = [0_u8; 21];
+    buf.clone_from_slice(&address_vec);
+    Ok(buf)
+}
+
+pub fn get_balance(db: &dyn ReadStore, network: Network, address: &str) -> Result<Balance, Error> {
     let mut addr_key = Vec::with_capacity(26);
     addr_key.push(1_u8);
-    addr_key.append(&mut address_vec);
-    let len = ldb_try!(db.get(ReadOptions::new(), Bytes::from(&addr_key))).unwrap_or([0_u8; 4].to_vec());
+    addr_key.extend(parse_address(network, address)?.as_ref());
+    let len = db.get(&addr_key)?.unwrap_or([0_u8; 4].to_vec());
     let mut buf = [0_u8; 4];
     if len.len() == 4 {
         buf.clone_from_slice(&len);
     }
     let len = u32::from_ne_bytes(buf);
-    let mut bal = 0_u64;
+    let encoding = Encoding::load(db)?;
+    let next = next_height(db)?;
+    let mut bal = Balance::default();
     addr_key.append(&mut u32::to_ne_bytes(0).to_vec());
     for i in 0..len {
         let i_buf = u32::to_ne_bytes(i);
         addr_key[22..].clone_from_slice(&i_buf);
-        let addr_value = ldb_try!(db.get(ReadOptions::new(), Bytes::from(&addr_key))).ok_or(format_err!("utxo missing"))?;
-        let mut val_buf = [0_u8; 8];
-        val_buf.clone_from_slice(addr_value.get(36..44).ok_or(format_err!("value missing"))?);
-        let val = u64::from_ne_bytes(val_buf);
-        bal += val;
+        let addr_value = db.get(&addr_key)?.ok_or(format_err!("utxo missing"))?;
+        let (txid, _, val) = encoding.decode_slot(&addr_value)?;
+        if is_immature(db, network, next, &txid)? {
+            bal.immature += val;
+        } else {
+            bal.mature += val;
+        }
     }
     Ok(bal)
 }
 
-fn get_utxos(
-    db: &Database<Bytes>,
+pub fn get_utxos(
+    db: &dyn ReadStore,
+    network: Network,
     address: &str,
     amount: u64,
     min_count: Option<usize>,
+    include_immature: bool,
 ) -> Result<Vec<UTXOData>, Error> {
     let min_count = min_count.unwrap_or(20);
-    let mut address_vec = bitcoin::util::base58::from_check(address)?;
-    if address_vec.len() != 21 {
-        bail!("invalid address length")
-    }
     let mut addr_key = Vec::with_capacity(26);
     addr_key.push(1_u8);
-    addr_key.append(&mut address_vec);
-    let len = ldb_try!(db.get(ReadOptions::new(), Bytes::from(&addr_key))).unwrap_or([0_u8; 4].to_vec());
+    addr_key.extend(parse_address(network, address)?.as_ref());
+    let len = db.get(&addr_key)?.unwrap_or([0_u8; 4].to_vec());
     let mut buf = [0_u8; 4];
     if len.len() == 4 {
         buf.clone_from_slice(&len);
     }
     let len = u32::from_ne_bytes(buf);
+    let encoding = Encoding::load(db)?;
+    let next = next_height(db)?;
     let mut bal = 0_u64;
     let mut utxos = Vec::new();
     addr_key.append(&mut u32::to_ne_bytes(0).to_vec());
     for i in 0..len {
         let i_buf = u32::to_ne_bytes(i);
         addr_key[22..].clone_from_slice(&i_buf);
-        let addr_value = ldb_try!(db.get(ReadOptions::new(), Bytes::from(&addr_key))).ok_or(format_err!("utxo missing"))?;
-        let mut txid = [0_u8; 32];
-        txid.clone_from_slice(addr_value.get(0..32).ok_or(format_err!("txid missing"))?);
-        let mut vout_buf = [0_u8; 4];
-        vout_buf.clone_from_slice(addr_value.get(32..36).ok_or(format_err!("vout missing"))?);
-        let vout = u32::from_ne_bytes(vout_buf);
-        let mut val_buf = [0_u8; 8];
-        val_buf.clone_from_slice(addr_value.get(36..44).ok_or(format_err!("value missing"))?);
-        let value = u64::from_ne_bytes(val_buf);
+        let addr_value = db.get(&addr_key)?.ok_or(format_err!("utxo missing"))?;
+        let (txid, vout, value) = encoding.decode_slot(&addr_value)?;
+        let immature = is_immature(db, network, next, &txid)?;
+        if immature && !include_immature {
+            continue;
+        }
         let mut tx_key = Vec::with_capacity(33);
         tx_key.push(4_u8);
         tx_key.extend(&txid);
-        let raw = ldb_try!(db.get(ReadOptions::new(), Bytes::from(&tx_key))).ok_or(format_err!("raw missing"))?;
+        let raw = encoding.raw_tx(&db.get(&tx_key)?.ok_or(format_err!("raw missing"))?)?;
         bal += value;
         utxos.push(UTXOData {
             txid,
             vout,
             value,
             raw,
+            immature,
         });
-        if i as usize >= min_count && bal >= amount {
+        if utxos.len() > min_count && bal >= amount {
             break;
         }
     }
@@ -126,10 +389,11 @@ fn get_utxos(
 
 #[derive(Debug, Serialize)]
 pub struct UTXOData {
-    txid: [u8; 32],
-    vout: u32,
-    value: u64,
-    raw: Vec<u8>,
+    pub txid: [u8; 32],
+    pub vout: u32,
+    pub value: u64,
+    pub raw: Vec<u8>,
+    pub immature: bool,
 }
 #[derive(Serialize)]
 struct UTXODataJSON {
@@ -137,25 +401,124 @@ struct UTXODataJSON {
     vout: u32,
     value: u64,
     raw: String,
+    immature: bool,
+}
+
+// Coinbase outputs are immature until they can be spent in the next block.
+#[derive(Debug, Default, Serialize)]
+pub struct Balance {
+    pub mature: u64,
+    pub immature: u64,
+}
+
+impl Balance {
+    pub fn total(&self) -> u64 {
+        self.mature + self.immature
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct BlockData {
+    pub height: u32,
+    pub hash: [u8; 32],
+    pub version: u32,
+    pub prev_hash: [u8; 32],
+    pub merkle_root: [u8; 32],
+    pub time: u32,
+    pub bits: u32,
+    pub nonce: u32,
+    pub header: Vec<u8>,
+    pub txids: Vec<[u8; 32]>,
+}
+#[derive(Serialize)]
+struct BlockDataJSON {
+    height: u32,
+    hash: String,
+    version: u32,
+    prev_hash: String,
+    merkle_root: String,
+    time: u32,
+    bits: String,
+    nonce: u32,
+    header: String,
+    tx_count: usize,
+    txids: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct MessageJSON {
+    txid: String,
+    kind: borker::Kind,
+    author: String,
+    height: u32,
+    time: u32,
+    nonce: u8,
+    reference: Option<String>,
+    content: String,
+}
+
+fn utxos_to_bytes(utxos: Vec<UTXOData>, marked: bool) -> Vec<u8> {
+    let mut ret = u64::to_be_bytes(utxos.len() as u64).to_vec();
+    for mut utxo in utxos {
+        ret.append(&mut utxo.txid.to_vec());
+        ret.append(&mut u32::to_be_bytes(utxo.vout).to_vec());
+        ret.append(&mut u64::to_be_bytes(utxo.value).to_vec());
+        if marked {
+            ret.push(utxo.immature as u8);
+        }
+        ret.append(&mut utxo.raw)
+    }
+    ret
 }
 
 #[derive(Serialize)]
 #[serde(untagged)]
 pub enum UTXORes {
     Balance(u64),
+    SplitBalance(Balance),
     UTXOs(Vec<UTXOData>),
+    // with immature outputs, which the octet stream encoding marks
+    MarkedUTXOs(Vec<UTXOData>),
+    Block(BlockData),
+    Headers(Vec<Vec<u8>>),
+    Messages(Vec<borker::Entry>),
 }
 impl UTXORes {
     pub fn to_bytes(self) -> Vec<u8> {
         match self {
             UTXORes::Balance(toshis) => u64::to_be_bytes(toshis).to_vec(),
-            UTXORes::UTXOs(utxos) => {
-                let mut ret = u64::to_be_bytes(utxos.len() as u64).to_vec();
-                for mut utxo in utxos {
-                    ret.append(&mut utxo.txid.to_vec());
-                    ret.append(&mut u32::to_be_bytes(utxo.vout).to_vec());
-                    ret.append(&mut u64::to_be_bytes(utxo.value).to_vec());
-                    ret.append(&mut utxo.raw)
+            UTXORes::SplitBalance(bal) => {
+                let mut ret = u64::to_be_bytes(bal.mature).to_vec();
+                ret.extend(&u64::to_be_bytes(bal.immature));
+                ret
+            }
+            UTXORes::UTXOs(utxos) => utxos_to_bytes(utxos, false),
+            UTXORes::MarkedUTXOs(utxos) => utxos_to_bytes(utxos, true),
+            UTXORes::Block(block) => {
+                let mut ret = u32::to_be_bytes(block.height).to_vec();
+                ret.extend(&block.hash);
+                ret.extend(&block.header);
+                ret.extend(&u64::to_be_bytes(block.txids.len() as u64));
+                for txid in block.txids {
+                    ret.extend(&txid);
+                }
+                ret
+            }
+            UTXORes::Headers(headers) => headers.concat(),
+            UTXORes::Messages(entries) => {
+                let mut ret = u64::to_be_bytes(entries.len() as u64).to_vec();
+                for entry in entries {
+                    ret.extend(&entry.txid);
+                    ret.push(entry.message.kind as u8);
+                    ret.push(entry.message.nonce);
+                    ret.extend(entry.author.as_ref());
+                    ret.extend(&u32::to_be_bytes(entry.height));
+                    ret.extend(&u32::to_be_bytes(entry.time));
+                    if let Some(reference) = entry.message.reference {
+                        ret.extend(&reference);
+                    }
+                    ret.extend(&u32::to_be_bytes(entry.message.content.len() as u32));
+                    ret.extend(entry.message.content);
                 }
                 ret
             }
@@ -165,7 +528,8 @@ impl UTXORes {
     pub fn to_json(self) -> Result<String, Error> {
         match self {
             UTXORes::Balance(toshis) => Ok(serde_json::to_string(&toshis)?),
-            UTXORes::UTXOs(utxos) => Ok(serde_json::to_string(
+            UTXORes::SplitBalance(bal) => Ok(serde_json::to_string(&bal)?),
+            UTXORes::UTXOs(utxos) | UTXORes::MarkedUTXOs(utxos) => Ok(serde_json::to_string(
                 &utxos
                     .into_iter()
                     .map(|u| UTXODataJSON {
@@ -173,10 +537,41 @@ impl UTXORes {
                         vout: u.vout,
                         value: u.value,
                         raw: hex::encode(u.raw),
+                        immature: u.immature,
+                    })
+                    .collect::<Vec<_>>(),
+            )?),
+            UTXORes::Block(block) => Ok(serde_json::to_string(&BlockDataJSON {
+                height: block.height,
+                hash: hex::encode(block.hash),
+                version: block.version,
+                prev_hash: hex::encode(block.prev_hash),
+                merkle_root: hex::encode(block.merkle_root),
+                time: block.time,
+                bits: format!("{:08x}", block.bits),
+                nonce: block.nonce,
+                header: hex::encode(block.header),
+                tx_count: block.txids.len(),
+                txids: block.txids.iter().map(hex::encode).collect(),
+            })?),
+            UTXORes::Headers(headers) => Ok(serde_json::to_string(
+                &headers.iter().map(hex::encode).collect::<Vec<_>>(),
+            )?),
+            UTXORes::Messages(entries) => Ok(serde_json::to_string(
+                &entries
+                    .into_iter()
+                    .map(|e| MessageJSON {
+                        txid: hex::encode(e.txid),
+                        kind: e.message.kind,
+                        author: bitcoin::util::base58::check_encode_slice(&e.author),
+                        height: e.height,
+                        time: e.time,
+                        nonce: e.message.nonce,
+                        reference: e.message.reference.map(hex::encode),
+                        content: String::from_utf8_lossy(&e.message.content).into_owned(),
                     })
                     .collect::<Vec<_>>(),
             )?),
         }
-
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,154 @@
+use crate::error::HttpError;
+use failure::Error;
+use hyper::header::{HeaderMap, AUTHORIZATION};
+use hyper::StatusCode;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+pub const API_KEY_HEADER: &str = "X-Api-Key";
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum Permission {
+    Index,
+    Proxy,
+    Metrics,
+    Admin,
+}
+
+#[derive(Clone, Deserialize)]
+pub struct ClientConfig {
+    pub id: String,
+    #[serde(default)]
+    pub key: Option<String>,
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+    #[serde(default)]
+    pub certificate: Option<String>,
+    pub permissions: HashSet<Permission>,
+}
+
+#[derive(Clone, Default, Deserialize)]
+pub struct AuthConfig {
+    #[serde(default)]
+    pub clients: Vec<ClientConfig>,
+}
+
+pub struct Identity {
+    pub id: String,
+    pub authenticated: bool,
+    permissions: HashSet<Permission>,
+}
+impl Identity {
+    pub fn has(&self, permission: Permission) -> bool {
+        self.permissions.contains(&Permission::Admin) || self.permissions.contains(&permission)
+    }
+
+    pub fn require(&self, permission: Permission) -> Result<(), Error> {
+        if self.has(permission) {
+            Ok(())
+        } else {
+            Err(HttpError::new(
+                StatusCode::FORBIDDEN,
+                format!("{} lacks {:?} permission", self.id, permission),
+            )
+            .into())
+        }
+    }
+}
+
+pub struct Auth {
+    keys: HashMap<String, Arc<Identity>>,
+    users: HashMap<String, (String, Arc<Identity>)>,
+    certificates: HashMap<String, Arc<Identity>>,
+    anonymous: Option<Arc<Identity>>,
+}
+
+impl Auth {
+    pub fn new(conf: AuthConfig) -> Self {
+        let mut auth = Auth {
+            keys: HashMap::new(),
+            users: HashMap::new(),
+            certificates: HashMap::new(),
+            anonymous: None,
+        };
+        if conf.clients.is_empty() {
+            auth.anonymous = Some(Arc::new(Identity {
+                id: "anonymous".to_owned(),
+                authenticated: false,
+                permissions: std::iter::once(Permission::Admin).collect(),
+            }));
+        }
+        for client in conf.clients {
+            let ident = Arc::new(Identity {
+                id: client.id,
+                authenticated: true,
+                permissions: client.permissions,
+            });
+            if let Some(key) = client.key {
+                auth.keys.insert(key, ident.clone());
+            }
+            if let Some(certificate) = client.certificate {
+                auth.certificates
+                    .insert(certificate.to_lowercase().replace(':', ""), ident.clone());
+            }
+            if let Some(username) = client.username {
+                auth.users
+                    .insert(username, (client.password.unwrap_or_default(), ident));
+            }
+        }
+        auth
+    }
+
+    // `certificate` is the sha256 fingerprint of the verified tls client certificate, if any.
+    pub fn authenticate(
+        &self,
+        headers: &HeaderMap,
+        certificate: Option<&str>,
+    ) -> Result<Arc<Identity>, Error> {
+        if let Some(ref anonymous) = self.anonymous {
+            return Ok(anonymous.clone());
+        }
+        if let Some(ident) = certificate.and_then(|c| self.certificates.get(c)) {
+            return Ok(ident.clone());
+        }
+        if let Some(key) = headers.get(API_KEY_HEADER) {
+            return self
+                .keys
+                .iter()
+                .find(|(k, _)| constant_time_eq(k.as_bytes(), key.as_bytes()))
+                .map(|(_, ident)| ident.clone())
+                .ok_or_else(|| HttpError::new(StatusCode::UNAUTHORIZED, "invalid api key").into());
+        }
+        if let Some(auth) = headers.get(AUTHORIZATION) {
+            let creds = auth
+                .to_str()
+                .ok()
+                .and_then(|a| {
+                    if a.starts_with("Basic ") {
+                        base64::decode(&a[6..]).ok()
+                    } else {
+                        None
+                    }
+                })
+                .and_then(|c| String::from_utf8(c).ok())
+                .ok_or_else(|| HttpError::new(StatusCode::UNAUTHORIZED, "invalid authorization"))?;
+            let mut creds = creds.splitn(2, ':');
+            let username = creds.next().unwrap_or("");
+            let password = creds.next().unwrap_or("");
+            return match self.users.get(username) {
+                Some((p, ident)) if constant_time_eq(p.as_bytes(), password.as_bytes()) => {
+                    Ok(ident.clone())
+                }
+                _ => Err(HttpError::new(StatusCode::UNAUTHORIZED, "invalid credentials").into()),
+            };
+        }
+        Err(HttpError::new(StatusCode::UNAUTHORIZED, "missing credentials").into())
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
@@ -0,0 +1,307 @@
+use crate::network::Network;
+use bitcoin::consensus::encode::serialize;
+use bitcoin::consensus::Decodable;
+use bitcoin::util::hash::BitcoinHash;
+use bitcoin::util::uint::Uint256;
+use bitcoin::{BlockHeader, Transaction, VarInt};
+use bitcoin_hashes::{sha256d, Hash};
+use failure::Error;
+
+pub const CHAIN_ID: u32 = 0x62;
+pub const VERSION_AUXPOW: u32 = 1 << 8;
+
+pub const MERGED_MINING_HEADER: [u8; 4] = [0xfa, 0xbe, b'm', b'm'];
+const MAX_CHAIN_BRANCH: usize = 30;
+
+pub fn chain_id(version: u32) -> u32 {
+    version >> 16
+}
+
+// The scrypt hash Dogecoin and Litecoin use for proof of work, in internal byte order.
+pub fn pow_hash(header: &BlockHeader) -> [u8; 32] {
+    let data = serialize(header);
+    let params = scrypt::ScryptParams::new(10, 1, 1).unwrap();
+    let mut out = [0_u8; 32];
+    scrypt::scrypt(&data, &data, &params, &mut out).unwrap();
+    out
+}
+
+// The target encoded in `bits`.
+pub fn target(bits: u32) -> Uint256 {
+    let header = BlockHeader {
+        version: 0,
+        prev_blockhash: Default::default(),
+        merkle_root: Default::default(),
+        time: 0,
+        bits,
+        nonce: 0,
+    };
+    header.target()
+}
+
+// Whether `hash` meets the target encoded in `bits`.
+pub fn meets_target(hash: &[u8; 32], bits: u32) -> bool {
+    let mut words = [0_u64; 4];
+    for (i, word) in words.iter_mut().enumerate() {
+        let mut buf = [0_u8; 8];
+        buf.clone_from_slice(&hash[i * 8..i * 8 + 8]);
+        *word = u64::from_le_bytes(buf);
+    }
+    Uint256(words) <= target(bits)
+}
+
+fn merkle_root(leaf: sha256d::Hash, branch: &[sha256d::Hash], mut index: u32) -> sha256d::Hash {
+    let mut hash = leaf;
+    for other in branch {
+        let mut buf = Vec::with_capacity(64);
+        if index & 1 == 1 {
+            buf.extend(&other[..]);
+            buf.extend(&hash[..]);
+        } else {
+            buf.extend(&hash[..]);
+            buf.extend(&other[..]);
+        }
+        hash = sha256d::Hash::hash(&buf);
+        index >>= 1;
+    }
+    hash
+}
+
+// The slot in the chain merkle tree a merged mined chain must use, so that a parent block cannot
+// commit to two blocks of the same chain.
+pub fn expected_index(nonce: u32, chain_id: u32, height: usize) -> u32 {
+    let mut rand = nonce;
+    rand = rand.wrapping_mul(1_103_515_245).wrapping_add(12345);
+    rand = rand.wrapping_add(chain_id);
+    rand = rand.wrapping_mul(1_103_515_245).wrapping_add(12345);
+    rand % (1 << height)
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+// Proof that a block was merge mined: the parent chain's coinbase commits to the root of a
+// merkle tree of merged mined blocks, one of which is ours, and the parent header meets our
+// target.
+#[derive(Clone, Debug)]
+pub struct AuxPow {
+    pub coinbase: Transaction,
+    // not checked, and not always the parent's hash
+    pub parent_hash: sha256d::Hash,
+    pub coinbase_branch: Vec<sha256d::Hash>,
+    pub coinbase_index: u32,
+    pub chain_branch: Vec<sha256d::Hash>,
+    pub chain_index: u32,
+    pub parent: BlockHeader,
+}
+
+impl AuxPow {
+    pub fn decode(cur: &mut std::io::Cursor<&[u8]>) -> Result<Self, Error> {
+        Ok(AuxPow {
+            coinbase: Decodable::consensus_decode(cur)?,
+            parent_hash: Decodable::consensus_decode(cur)?,
+            coinbase_branch: Decodable::consensus_decode(cur)?,
+            coinbase_index: Decodable::consensus_decode(cur)?,
+            chain_branch: Decodable::consensus_decode(cur)?,
+            chain_index: Decodable::consensus_decode(cur)?,
+            parent: Decodable::consensus_decode(cur)?,
+        })
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = serialize(&self.coinbase);
+        out.extend(&self.parent_hash[..]);
+        for (branch, index) in [
+            (&self.coinbase_branch, self.coinbase_index),
+            (&self.chain_branch, self.chain_index),
+        ]
+        .iter()
+        {
+            out.extend(serialize(&VarInt(branch.len() as u64)));
+            for hash in branch.iter() {
+                out.extend(&hash[..]);
+            }
+            out.extend(&index.to_le_bytes());
+        }
+        out.extend(serialize(&self.parent));
+        out
+    }
+
+    // The root of the chain merkle tree, and so the commitment, for the block `header`.
+    pub fn chain_root(&self, header: &BlockHeader) -> sha256d::Hash {
+        merkle_root(header.bitcoin_hash(), &self.chain_branch, self.chain_index)
+    }
+
+    // Checks the proof for `header` the way dogecoind does.
+    pub fn check(&self, header: &BlockHeader, network: Network) -> Result<(), Error> {
+        if self.coinbase_index != 0 {
+            bail!("auxpow coinbase is not the first transaction of the parent block");
+        }
+        if network.strict_chain_id() && chain_id(self.parent.version) == CHAIN_ID {
+            bail!("auxpow parent block has our chain id");
+        }
+        if self.chain_branch.len() > MAX_CHAIN_BRANCH {
+            bail!("auxpow chain merkle branch is too long");
+        }
+        let coinbase_root = merkle_root(
+            self.coinbase.txid(),
+            &self.coinbase_branch,
+            self.coinbase_index,
+        );
+        if coinbase_root != self.parent.merkle_root {
+            bail!("auxpow coinbase is not in the parent block");
+        }
+        self.check_commitment(&self.chain_root(header))?;
+        if !meets_target(&pow_hash(&self.parent), header.bits) {
+            bail!("auxpow parent block does not meet the target");
+        }
+        Ok(())
+    }
+
+    fn check_commitment(&self, root: &sha256d::Hash) -> Result<(), Error> {
+        let script = self
+            .coinbase
+            .input
+            .get(0)
+            .ok_or(format_err!("auxpow coinbase has no inputs"))?
+            .script_sig
+            .as_bytes();
+        // the root appears in the script as it is displayed, reversed
+        let mut root = root[..].to_vec();
+        root.reverse();
+        let pos = find(script, &root)
+            .ok_or(format_err!("auxpow coinbase does not commit to the block"))?;
+        match find(script, &MERGED_MINING_HEADER) {
+            Some(head) => {
+                if find(&script[head + 1..], &MERGED_MINING_HEADER).is_some() {
+                    bail!("auxpow coinbase has more than one merged mining header");
+                }
+                if head + MERGED_MINING_HEADER.len() != pos {
+                    bail!("auxpow chain merkle root does not follow the merged mining header");
+                }
+            }
+            // coinbases from before the header was introduced commit near the start
+            None if pos > 20 => bail!("auxpow chain merkle root starts too late in the coinbase"),
+            None => (),
+        }
+        let rest = &script[pos + root.len()..];
+        if rest.len() < 8 {
+            bail!("auxpow coinbase is missing the merkle tree size and nonce");
+        }
+        let mut buf = [0_u8; 4];
+        buf.clone_from_slice(&rest[0..4]);
+        let size = u32::from_le_bytes(buf);
+        buf.clone_from_slice(&rest[4..8]);
+        let nonce = u32::from_le_bytes(buf);
+        if size != 1 << self.chain_branch.len() {
+            bail!("auxpow merkle tree size does not match the chain merkle branch");
+        }
+        if self.chain_index != expected_index(nonce, CHAIN_ID, self.chain_branch.len()) {
+            bail!("auxpow chain index is not the expected one");
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::Block;
+    use crate::testing::*;
+
+    const NETWORK: Network = Network::Regtest;
+
+    fn merged_block() -> TestBlock {
+        let mut chain = Chain::new(NETWORK);
+        chain.mine_empty(1, NETWORK.auxpow_height());
+        chain.block(NETWORK.auxpow_height()).clone()
+    }
+
+    fn check(block: &TestBlock, auxpow: &AuxPow) -> Result<(), Error> {
+        let raw = raw_block(&block.header, Some(auxpow), &block.txs);
+        let parsed = Block::from_slice(&raw, NETWORK, NETWORK.auxpow_height())?;
+        parsed.check_auxpow()
+    }
+
+    fn regrind(auxpow: &mut AuxPow, bits: u32) {
+        auxpow.parent.merkle_root = auxpow.coinbase.txid();
+        while !meets_target(&pow_hash(&auxpow.parent), bits) {
+            auxpow.parent.nonce += 1;
+        }
+    }
+
+    #[test]
+    fn parses_and_accepts_merge_mined_blocks() -> Result<(), Error> {
+        let block = merged_block();
+        let parsed = Block::from_slice(&block.raw, NETWORK, NETWORK.auxpow_height())?;
+        let auxpow = parsed.auxpow.as_ref().unwrap();
+        assert_eq!(auxpow.encode(), block.auxpow.as_ref().unwrap().encode());
+        assert_eq!(parsed.tx_count, 1);
+        parsed.check_auxpow()
+    }
+
+    #[test]
+    fn rejects_auxpow_before_activation() {
+        let block = merged_block();
+        assert!(Block::from_slice(&block.raw, NETWORK, NETWORK.auxpow_height() - 1).is_err());
+    }
+
+    #[test]
+    fn rejects_commitment_to_another_block() {
+        let block = merged_block();
+        let mut auxpow = block.auxpow.clone().unwrap();
+        let mut script = auxpow.coinbase.input[0].script_sig.as_bytes().to_vec();
+        script[MERGED_MINING_HEADER.len()] ^= 1;
+        auxpow.coinbase.input[0].script_sig = script.into();
+        regrind(&mut auxpow, block.header.bits);
+        let e = check(&block, &auxpow).unwrap_err();
+        assert!(e.to_string().contains("does not commit"), "{}", e);
+    }
+
+    #[test]
+    fn rejects_coinbase_outside_parent() {
+        let block = merged_block();
+        let mut auxpow = block.auxpow.clone().unwrap();
+        auxpow.coinbase_branch.push(sha256d::Hash::hash(b"other"));
+        let e = check(&block, &auxpow).unwrap_err();
+        assert!(e.to_string().contains("not in the parent"), "{}", e);
+    }
+
+    #[test]
+    fn rejects_wrong_chain_index() {
+        let block = merged_block();
+        let mut auxpow = block.auxpow.clone().unwrap();
+        auxpow.chain_index = 1;
+        let e = check(&block, &auxpow).unwrap_err();
+        assert!(e.to_string().contains("expected"), "{}", e);
+    }
+
+    #[test]
+    fn rejects_parent_with_our_chain_id() {
+        let block = merged_block();
+        let mut auxpow = block.auxpow.clone().unwrap();
+        auxpow.parent.version |= CHAIN_ID << 16;
+        let e = check(&block, &auxpow).unwrap_err();
+        assert!(e.to_string().contains("our chain id"), "{}", e);
+    }
+
+    #[test]
+    fn rejects_parent_below_target() {
+        let block = merged_block();
+        let mut auxpow = block.auxpow.clone().unwrap();
+        while meets_target(&pow_hash(&auxpow.parent), block.header.bits) {
+            auxpow.parent.nonce += 1;
+        }
+        let e = check(&block, &auxpow).unwrap_err();
+        assert!(e.to_string().contains("target"), "{}", e);
+    }
+
+    #[test]
+    fn expected_index_matches_dogecoind() {
+        assert_eq!(expected_index(0, CHAIN_ID, 0), 0);
+        // rand = (0 * 1103515245 + 12345 + 0x62) * 1103515245 + 12345 mod 2^32
+        let rand = 12443_u32.wrapping_mul(1_103_515_245).wrapping_add(12345);
+        assert_eq!(expected_index(0, CHAIN_ID, 4), rand % 16);
+    }
+}
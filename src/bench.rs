@@ -0,0 +1,126 @@
+use crate::codec::{Compression, Encoding};
+use crate::network::Network;
+use crate::store::{LevelDb, ReadStore, Store};
+use failure::Error;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+const VARIANTS: [(Encoding, Compression); 4] = [
+    (Encoding::Legacy, Compression::None),
+    (Encoding::Legacy, Compression::Snappy),
+    (Encoding::Compact, Compression::None),
+    (Encoding::Compact, Compression::Snappy),
+];
+
+fn dir_size(path: &Path) -> Result<u64, Error> {
+    let mut size = 0;
+    for entry in std::fs::read_dir(path)? {
+        size += entry?.metadata()?.len();
+    }
+    Ok(size)
+}
+
+// Copies the database, re-encoding raw transactions and address slots, and compacts the copy
+// so that its size on disk is comparable with the others.
+fn copy(
+    db: &dyn Store,
+    from: Encoding,
+    path: &Path,
+    encoding: Encoding,
+    compression: Compression,
+) -> Result<LevelDb, Error> {
+    let copy = LevelDb::open(path, true, compression)?;
+    for (key, value) in db.iter(&[]) {
+        let value = match (key.get(0), key.len()) {
+            (Some(0), 2) if key[1] == 2 => continue,
+            (Some(1), 26) => {
+                let (txid, vout, value) = from.decode_slot(&value)?;
+                encoding.encode_slot(&txid, vout, value)
+            }
+            (Some(4), _) => encoding.encode_tx(&from.decode_tx(&value)?),
+            _ => value,
+        };
+        copy.put(&key, &value)?;
+    }
+    encoding.store(&copy)?;
+    copy.compact();
+    Ok(copy)
+}
+
+// Addresses with at least one utxo, in key order.
+fn sample_addresses(db: &dyn Store, network: Network, samples: usize) -> Vec<String> {
+    db.iter(&[1_u8])
+        .take_while(|(k, _)| k.get(0) == Some(&1))
+        .filter(|(k, v)| k.len() == 22 && v.as_slice() != [0_u8; 4])
+        .filter(|(k, _)| k[1] == network.p2pkh() || k[1] == network.p2sh())
+        .map(|(k, _)| bitcoin::util::base58::check_encode_slice(&k[1..22]))
+        .take(samples)
+        .collect()
+}
+
+fn time_utxos(
+    db: &dyn ReadStore,
+    network: Network,
+    addresses: &[String],
+) -> Result<(Duration, usize), Error> {
+    let start = Instant::now();
+    let mut utxos = 0;
+    for address in addresses {
+        utxos += crate::api::get_utxos(
+            db,
+            network,
+            address,
+            std::u64::MAX,
+            Some(std::usize::MAX),
+            true,
+        )?
+        .len();
+    }
+    Ok((start.elapsed(), utxos))
+}
+
+// Compares the size on disk and get_utxos latency of every combination of record encoding and
+// block compression, using copies of the database under `dir`, which are removed afterwards.
+pub fn run(db: &dyn Store, network: Network, dir: &Path, samples: usize) -> Result<(), Error> {
+    if dir.exists() {
+        bail!("{} already exists", dir.display());
+    }
+    std::fs::create_dir_all(dir)?;
+    let from = Encoding::load(db.as_read())?;
+    let addresses = sample_addresses(db, network, samples);
+    info!("timing get_utxos for {} addresses", addresses.len());
+    let mut results = Vec::new();
+    for &(encoding, compression) in VARIANTS.iter() {
+        let path = dir.join(format!("{:?}-{:?}", encoding, compression).to_lowercase());
+        let copy = copy(db, from, &path, encoding, compression)?;
+        let size = dir_size(&path)?;
+        // once to warm the block cache and the page cache, then timed
+        time_utxos(&copy, network, &addresses)?;
+        let (elapsed, utxos) = time_utxos(&copy, network, &addresses)?;
+        info!(
+            "measured {:?} records with {:?} compression",
+            encoding, compression
+        );
+        results.push((encoding, compression, size, elapsed, utxos));
+    }
+    std::fs::remove_dir_all(dir)?;
+
+    let base = results[0].2 as f64;
+    println!(
+        "{:<10} {:<12} {:>14} {:>8} {:>16} {:>16}",
+        "encoding", "compression", "size (bytes)", "ratio", "get_utxos (us)", "per utxo (us)"
+    );
+    for (encoding, compression, size, elapsed, utxos) in results {
+        let micros = elapsed.as_secs_f64() * 1e6;
+        println!(
+            "{:<10} {:<12} {:>14} {:>8.3} {:>16.1} {:>16.2}",
+            format!("{:?}", encoding).to_lowercase(),
+            format!("{:?}", compression).to_lowercase(),
+            size,
+            size as f64 / base,
+            micros / std::cmp::max(addresses.len(), 1) as f64,
+            micros / std::cmp::max(utxos, 1) as f64,
+        );
+    }
+    Ok(())
+}
@@ -1,95 +1,212 @@
-
-use crate::key::Bytes;
+use crate::auxpow::{self, AuxPow};
+use crate::borker;
+use crate::codec::Encoding;
+use crate::network::Network;
+use crate::store::Store;
 use crate::utxo::*;
 use crate::Rewind;
 use bitcoin::consensus::Decodable;
 use failure::Error;
-use leveldb::database::Database;
-use leveldb::kv::KV;
-use leveldb::options::*;
 use std::collections::HashMap;
 use throttled_bitcoin_rpc::BitcoinRpcClient;
 
+// The txids of the block at a height, concatenated as they are displayed.
+pub fn txids_key(idx: u32) -> Vec<u8> {
+    let mut key = Vec::with_capacity(5);
+    key.push(10_u8);
+    key.extend(&idx.to_ne_bytes());
+    key
+}
+
+// The height of a coinbase, so that its outputs can be held back until they mature.
+pub fn coinbase_key(txid: &[u8; 32]) -> Vec<u8> {
+    let mut key = Vec::with_capacity(33);
+    key.push(11_u8);
+    key.extend(txid);
+    key
+}
+
+// The height of a coinbase that is still immature at `next`, found among the txids of the blocks
+// below it. Coinbases deeper than their maturity are not looked for.
+fn immature_coinbase_height(
+    db: &dyn Store,
+    network: Network,
+    next: u32,
+    txid: &[u8; 32],
+) -> Result<Option<u32>, Error> {
+    for height in (1..next).rev() {
+        if next - height >= network.coinbase_maturity(height) {
+            break;
+        }
+        if let Some(txids) = db.get(&txids_key(height))? {
+            if txids.get(..32) == Some(&txid[..]) {
+                return Ok(Some(height));
+            }
+        }
+    }
+    Ok(None)
+}
+
+#[derive(Clone)]
 pub struct Block<'a> {
+    pub network: Network,
     pub header: bitcoin::BlockHeader,
+    pub auxpow: Option<AuxPow>,
     pub tx_count: u64,
     pub pos: u64,
     pub cur: std::io::Cursor<&'a [u8]>,
 }
 
 impl<'a> Block<'a> {
-    pub fn from_slice(raw: &'a [u8]) -> Result<Self, Error> {
+    pub fn from_slice(raw: &'a [u8], network: Network, height: u32) -> Result<Self, Error> {
         let mut cur = std::io::Cursor::new(raw);
         let header: bitcoin::BlockHeader = Decodable::consensus_decode(&mut cur)?;
-        if header.version & 1 << 8 != 0 {
-            let _: bitcoin::Transaction = Decodable::consensus_decode(&mut cur)?;
-            cur.set_position(cur.position() + 32);
-            let len: bitcoin::VarInt = Decodable::consensus_decode(&mut cur)?;
-            cur.set_position(cur.position() + 32 * len.0 + 4);
-            let len: bitcoin::VarInt = Decodable::consensus_decode(&mut cur)?;
-            cur.set_position(cur.position() + 32 * len.0 + 84);
+        let auxpow = if header.version & auxpow::VERSION_AUXPOW != 0 {
+            if height < network.auxpow_height() {
+                bail!(
+                    "auxpow block {} before activation at {}",
+                    height,
+                    network.auxpow_height()
+                );
+            }
+            Some(AuxPow::decode(&mut cur)?)
+        } else {
+            None
         };
         let tx_count: bitcoin::VarInt = Decodable::consensus_decode(&mut cur)?;
         Ok(Block {
+            network,
             header,
+            auxpow,
             tx_count: tx_count.0,
             pos: 0,
             cur,
         })
     }
 
-    pub fn exec(self, db: &Database<Bytes>, idx: u32, rewind: &mut Rewind) -> Result<(), Error> {
-        use bitcoin::consensus::encode::Encodable;
+    // Blocks with no auxpow are checked with the rest of their header.
+    pub fn check_auxpow(&self) -> Result<(), Error> {
+        match self.auxpow {
+            Some(ref auxpow) => auxpow.check(&self.header, self.network),
+            None => Ok(()),
+        }
+    }
+
+    pub(crate) fn exec(
+        self,
+        db: &dyn Store,
+        idx: u32,
+        rewind: &mut Rewind,
+        mode: StorageMode,
+    ) -> Result<(), Error> {
+        let encoding = Encoding::load(db.as_read())?;
         rewind[idx as usize % crate::CONFIRMATIONS] = HashMap::new();
+        let network = self.network;
+        let time = self.header.time;
+        let mut txids = Vec::with_capacity(self.tx_count as usize * 32);
         for tx in self {
             let tx = tx?;
             let mut txid = [0u8; 32];
             txid.clone_from_slice(&tx.txid()[..]);
             txid.reverse();
-            let mut tx_vec = Vec::new();
-            tx.consensus_encode(&mut tx_vec)?;
-            for i in tx.input {
+            txids.extend(&txid);
+            if tx.is_coin_base() {
+                db.put(&coinbase_key(&txid), &idx.to_ne_bytes())?;
+            }
+            let tx_vec = encoding.encode_tx(&tx);
+            let mut author = None;
+            for (n, i) in tx.input.into_iter().enumerate() {
                 if !i.previous_output.is_null() {
-                    UTXOID::from(&i).rem(db, idx, rewind)?;
+                    if let Some(address) = UTXOID::from(&i).rem(db, encoding, idx, rewind, mode)? {
+                        crate::history::add(db, &address, idx, &txid, 0)?;
+                        if n == 0 {
+                            author = Some(address);
+                        }
+                    }
                 }
             }
-            let mut tx_key = Vec::with_capacity(37);
-            tx_key.push(5_u8);
-            tx_key.extend(&txid);
-            ldb_try!(db.put(WriteOptions::new(), Bytes::from(&tx_key), &(tx.output.len() as u32).to_ne_bytes()));
-            tx_key[0] = 4;
-            ldb_try!(db.put(WriteOptions::new(), Bytes::from(&tx_key), &tx_vec));
+            // the first borker message of a transaction with an author
+            let message = tx
+                .output
+                .iter()
+                .find_map(|o| borker::Message::decode(&o.script_pubkey));
+            if let (Some(author), Some(message)) = (author, message) {
+                borker::add(
+                    db,
+                    &borker::Entry {
+                        txid,
+                        author,
+                        height: idx,
+                        time,
+                        message,
+                    },
+                )?;
+            }
+            // provably unspendable outputs are never spent, so they are not counted as unspent
+            let unspents = tx
+                .output
+                .iter()
+                .filter(|o| !o.script_pubkey.is_provably_unspendable())
+                .count() as u32;
+            if unspents > 0 || mode == StorageMode::Archive {
+                let mut tx_key = Vec::with_capacity(37);
+                tx_key.push(5_u8);
+                tx_key.extend(&txid);
+                db.put(&tx_key, &unspents.to_ne_bytes())?;
+                tx_key[0] = 4;
+                db.put(&tx_key, &tx_vec)?;
+            }
             for (i, o) in tx.output.into_iter().enumerate() {
-                UTXO::from_txout(&txid, &o, i as u32).add(db, None)?;
+                let utxo = UTXO::from_txout(network, &txid, &o, i as u32);
+                if let Some(address) = utxo.address() {
+                    crate::history::add(db, address, idx, &txid, o.value)?;
+                }
+                utxo.add(db, encoding, None)?;
             }
         }
+        db.put(&txids_key(idx), &txids)?;
 
         Ok(())
     }
 
-    pub fn undo(
+    pub(crate) fn undo(
         self,
         client: &BitcoinRpcClient,
-        db: &Database<Bytes>,
+        db: &dyn Store,
         idx: u32,
         rewind: &mut Rewind,
+        mode: StorageMode,
     ) -> Result<(), Error> {
+        let encoding = Encoding::load(db.as_read())?;
+        crate::history::undo(db, idx)?;
+        db.delete(&txids_key(idx))?;
+        let network = self.network;
         for (id, (data, raw)) in rewind[idx as usize % crate::CONFIRMATIONS].iter() {
-            let raw = match raw {
-                Some(raw) => std::borrow::Cow::Borrowed(raw),
-                None => std::borrow::Cow::Owned(hex::decode(
-                    client
-                        .getrawtransaction(&hex::encode(&id.txid), 0)?
-                        .Zero()?,
-                )?),
+            // the rewind holds transactions as they were stored under prefix 4
+            let (tx, raw) = match raw {
+                Some(raw) => (encoding.decode_tx(raw)?, std::borrow::Cow::Borrowed(raw)),
+                None => {
+                    let tx: bitcoin::Transaction =
+                        bitcoin::consensus::encode::deserialize(&hex::decode(
+                            client
+                                .getrawtransaction(&hex::encode(&id.txid), 0)?
+                                .Zero()?,
+                        )?)?;
+                    let raw = encoding.encode_tx(&tx);
+                    (tx, std::borrow::Cow::Owned(raw))
+                }
             };
-            let tx: bitcoin::Transaction =
-                Decodable::consensus_decode(&mut std::io::Cursor::new(raw.as_slice()))?;
             let utxo = match data {
                 Some(data) => UTXO::from((id, data.clone())),
-                None => UTXO::from_txout(&id.txid, &tx.output[id.vout as usize], id.vout),
+                None => UTXO::from_txout(network, &id.txid, &tx.output[id.vout as usize], id.vout),
             };
-            utxo.add(db, Some((raw.as_slice(), tx.output.len() as u32)))?;
+            utxo.add(db, encoding, Some(raw.as_slice()))?;
+            // a spent coinbase loses its height, which it needs again if it is immature below idx
+            if tx.is_coin_base() {
+                if let Some(height) = immature_coinbase_height(db, network, idx, &id.txid)? {
+                    db.put(&coinbase_key(&id.txid), &height.to_ne_bytes())?;
+                }
+            }
         }
         rewind[idx as usize % crate::CONFIRMATIONS] = HashMap::new();
         for tx in self {
@@ -97,12 +214,19 @@ impl<'a> Block<'a> {
             let mut txid = [0u8; 32];
             txid.clone_from_slice(&tx.txid()[..]);
             txid.reverse();
-            for (i, _) in tx.output.into_iter().enumerate() {
+            if tx.is_coin_base() {
+                db.delete(&coinbase_key(&txid))?;
+            }
+            borker::undo(db, &txid)?;
+            for (i, o) in tx.output.into_iter().enumerate() {
+                if o.script_pubkey.is_provably_unspendable() {
+                    continue;
+                }
                 UTXOID {
                     txid: txid.clone(),
                     vout: i as u32,
                 }
-                .rem(db, idx, rewind)?;
+                .rem(db, encoding, idx, rewind, mode)?;
             }
         }
 
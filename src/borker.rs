@@ -0,0 +1,341 @@
+use crate::store::{ReadStore, Store};
+use bitcoin::Script;
+use failure::Error;
+
+// Borker messages carried in OP_RETURN outputs. A payload is MAGIC, a type and a nonce, then the
+// txid a comment, rebork or like refers to, as it is displayed, then the content:
+//
+//   bork:    MAGIC 0x03 nonce content
+//   comment: MAGIC 0x04 nonce txid content
+//   rebork:  MAGIC 0x05 nonce txid content
+//   like:    MAGIC 0x08 nonce txid
+//
+// The author is the address of the output spent by the first input. Prefix 12 maps txid to the
+// message record, and prefixes 13 (author ++ time ++ txid), 14 (referenced txid ++ time ++ txid)
+// and 15 (time ++ txid) map to nothing. Times are block times, inverted and big endian so that
+// entries iterate newest first. Likes are left out of prefix 15.
+
+pub const MAGIC: [u8; 2] = [0xd0, 0x6e];
+
+const OP_RETURN: u8 = 0x6a;
+const OP_PUSHDATA1: u8 = 0x4c;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+    Bork = 0x03,
+    Comment = 0x04,
+    Rebork = 0x05,
+    Like = 0x08,
+}
+
+impl Kind {
+    pub fn from_u8(b: u8) -> Option<Self> {
+        match b {
+            0x03 => Some(Kind::Bork),
+            0x04 => Some(Kind::Comment),
+            0x05 => Some(Kind::Rebork),
+            0x08 => Some(Kind::Like),
+            _ => None,
+        }
+    }
+
+    pub fn references(self) -> bool {
+        self != Kind::Bork
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Message {
+    pub kind: Kind,
+    pub nonce: u8,
+    pub reference: Option<[u8; 32]>,
+    pub content: Vec<u8>,
+}
+
+impl Message {
+    // The message in `script`, if it is an OP_RETURN with a single push of a Borker payload.
+    pub fn decode(script: &Script) -> Option<Self> {
+        let script = script.as_bytes();
+        if script.get(0) != Some(&OP_RETURN) {
+            return None;
+        }
+        let payload = match *script.get(1)? {
+            n @ 1..=75 if script.len() == 2 + n as usize => &script[2..],
+            OP_PUSHDATA1 if script.len() == 3 + *script.get(2)? as usize => &script[3..],
+            _ => return None,
+        };
+        Self::from_payload(payload)
+    }
+
+    fn from_payload(payload: &[u8]) -> Option<Self> {
+        if payload.len() < 4 || payload[..2] != MAGIC {
+            return None;
+        }
+        let kind = Kind::from_u8(payload[2])?;
+        let (reference, content) = if kind.references() {
+            let mut txid = [0_u8; 32];
+            txid.clone_from_slice(payload.get(4..36)?);
+            (Some(txid), &payload[36..])
+        } else {
+            (None, &payload[4..])
+        };
+        Some(Message {
+            kind,
+            nonce: payload[3],
+            reference,
+            content: content.to_vec(),
+        })
+    }
+}
+
+// An indexed message.
+#[derive(Clone, Debug, Serialize)]
+pub struct Entry {
+    pub txid: [u8; 32],
+    pub author: [u8; 21],
+    pub height: u32,
+    pub time: u32,
+    pub message: Message,
+}
+
+impl Entry {
+    fn encode(&self) -> Vec<u8> {
+        let mut record = vec![self.message.kind as u8, self.message.nonce];
+        record.extend(self.author.as_ref());
+        record.extend(&self.height.to_ne_bytes());
+        record.extend(&self.time.to_ne_bytes());
+        if let Some(ref reference) = self.message.reference {
+            record.extend(reference);
+        }
+        record.extend(&self.message.content);
+        record
+    }
+
+    fn decode(txid: &[u8], record: &[u8]) -> Result<Self, Error> {
+        if record.len() < 31 {
+            bail!("invalid borker record");
+        }
+        let kind = Kind::from_u8(record[0]).ok_or(format_err!("invalid borker record"))?;
+        let mut entry = Entry {
+            txid: [0_u8; 32],
+            author: [0_u8; 21],
+            height: 0,
+            time: 0,
+            message: Message {
+                kind,
+                nonce: record[1],
+                reference: None,
+                content: Vec::new(),
+            },
+        };
+        entry.txid.clone_from_slice(txid);
+        entry.author.clone_from_slice(&record[2..23]);
+        let mut buf = [0_u8; 4];
+        buf.clone_from_slice(&record[23..27]);
+        entry.height = u32::from_ne_bytes(buf);
+        buf.clone_from_slice(&record[27..31]);
+        entry.time = u32::from_ne_bytes(buf);
+        let mut rest = &record[31..];
+        if kind.references() {
+            if rest.len() < 32 {
+                bail!("invalid borker record");
+            }
+            let mut reference = [0_u8; 32];
+            reference.clone_from_slice(&rest[..32]);
+            entry.message.reference = Some(reference);
+            rest = &rest[32..];
+        }
+        entry.message.content = rest.to_vec();
+        Ok(entry)
+    }
+
+    // The feed keys of the message, under prefixes 13, 14 and 15.
+    fn feed_keys(&self) -> Vec<Vec<u8>> {
+        let time = (std::u32::MAX - self.time).to_be_bytes();
+        let mut keys = Vec::with_capacity(3);
+        let mut key = vec![13_u8];
+        key.extend(self.author.as_ref());
+        key.extend(&time);
+        key.extend(&self.txid);
+        keys.push(key);
+        if let Some(ref reference) = self.message.reference {
+            let mut key = vec![14_u8];
+            key.extend(reference);
+            key.extend(&time);
+            key.extend(&self.txid);
+            keys.push(key);
+        }
+        if self.message.kind != Kind::Like {
+            let mut key = vec![15_u8];
+            key.extend(&time);
+            key.extend(&self.txid);
+            keys.push(key);
+        }
+        keys
+    }
+}
+
+fn record_key(txid: &[u8]) -> Vec<u8> {
+    let mut key = Vec::with_capacity(33);
+    key.push(12_u8);
+    key.extend(txid);
+    key
+}
+
+pub fn add(db: &dyn Store, entry: &Entry) -> Result<(), Error> {
+    db.put(&record_key(&entry.txid), &entry.encode())?;
+    for key in entry.feed_keys() {
+        db.put(&key, &[])?;
+    }
+    Ok(())
+}
+
+// Removes the message of `txid`, if it had one.
+pub fn undo(db: &dyn Store, txid: &[u8; 32]) -> Result<(), Error> {
+    let record_key = record_key(txid);
+    let record = match db.get(&record_key)? {
+        Some(record) => record,
+        None => return Ok(()),
+    };
+    for key in Entry::decode(txid, &record)?.feed_keys() {
+        db.delete(&key)?;
+    }
+    db.delete(&record_key)
+}
+
+pub fn get(db: &dyn ReadStore, txid: &[u8]) -> Result<Option<Entry>, Error> {
+    match db.get(&record_key(txid))? {
+        Some(record) => Ok(Some(Entry::decode(txid, &record)?)),
+        None => Ok(None),
+    }
+}
+
+// Where a feed page starts: messages from blocks before `time`, or, with `after`, from the block
+// at `time` too but following the message `after` in feed order.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cursor {
+    pub time: u32,
+    pub after: Option<[u8; 32]>,
+}
+
+impl Default for Cursor {
+    fn default() -> Self {
+        Cursor {
+            time: std::u32::MAX,
+            after: None,
+        }
+    }
+}
+
+// Up to `limit` messages under `prefix` from `cursor` on, newest first.
+fn feed(
+    db: &dyn ReadStore,
+    prefix: Vec<u8>,
+    cursor: Cursor,
+    limit: usize,
+) -> Result<Vec<Entry>, Error> {
+    let mut start = prefix.clone();
+    match cursor.after {
+        Some(txid) => {
+            start.extend(&(std::u32::MAX - cursor.time).to_be_bytes());
+            start.extend(&txid);
+        }
+        None if cursor.time == 0 => return Ok(Vec::new()),
+        None => start.extend(&(std::u32::MAX - (cursor.time - 1)).to_be_bytes()),
+    }
+    db.iter(&start)
+        .map(|(k, _)| k)
+        .skip_while(|k| k == &start)
+        .take_while(|k| k.starts_with(&prefix))
+        .take(limit)
+        .map(|k| {
+            let txid = &k[k.len() - 32..];
+            get(db, txid)?.ok_or(format_err!("borker record {} missing", hex::encode(txid)))
+        })
+        .collect()
+}
+
+pub fn posts(
+    db: &dyn ReadStore,
+    author: &[u8; 21],
+    cursor: Cursor,
+    limit: usize,
+) -> Result<Vec<Entry>, Error> {
+    let mut prefix = vec![13_u8];
+    prefix.extend(author.as_ref());
+    feed(db, prefix, cursor, limit)
+}
+
+pub fn replies(
+    db: &dyn ReadStore,
+    txid: &[u8],
+    cursor: Cursor,
+    limit: usize,
+) -> Result<Vec<Entry>, Error> {
+    let mut prefix = vec![14_u8];
+    prefix.extend(txid);
+    feed(db, prefix, cursor, limit)
+}
+
+pub fn timeline(db: &dyn ReadStore, cursor: Cursor, limit: usize) -> Result<Vec<Entry>, Error> {
+    feed(db, vec![15_u8], cursor, limit)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn script(payload: &[u8]) -> Script {
+        let mut script = vec![OP_RETURN];
+        if payload.len() > 75 {
+            script.push(OP_PUSHDATA1);
+        }
+        script.push(payload.len() as u8);
+        script.extend(payload);
+        Script::from(script)
+    }
+
+    #[test]
+    fn decodes_payloads() {
+        let mut payload = MAGIC.to_vec();
+        payload.extend(&[0x03, 7]);
+        payload.extend(b"such wow");
+        assert_eq!(
+            Message::decode(&script(&payload)),
+            Some(Message {
+                kind: Kind::Bork,
+                nonce: 7,
+                reference: None,
+                content: b"such wow".to_vec(),
+            })
+        );
+
+        let mut payload = MAGIC.to_vec();
+        payload.extend(&[0x04, 0]);
+        payload.extend(&[9; 32]);
+        payload.extend(&[b'x'; 44]);
+        let comment = Message::decode(&script(&payload)).unwrap();
+        assert_eq!(comment.kind, Kind::Comment);
+        assert_eq!(comment.reference, Some([9; 32]));
+        assert_eq!(comment.content.len(), 44);
+    }
+
+    #[test]
+    fn ignores_other_scripts() {
+        let mut payload = MAGIC.to_vec();
+        payload.extend(&[0x04, 0]);
+        payload.extend(&[9; 31]);
+        // too short for the reference
+        assert_eq!(Message::decode(&script(&payload)), None);
+        assert_eq!(Message::decode(&script(b"\x00\x00\x03\x00hi")), None);
+        assert_eq!(
+            Message::decode(&script(&[MAGIC[0], MAGIC[1], 0x7f, 0])),
+            None
+        );
+        let mut truncated = script(&[MAGIC[0], MAGIC[1], 0x03, 0]).as_bytes().to_vec();
+        truncated.pop();
+        assert_eq!(Message::decode(&Script::from(truncated)), None);
+        assert_eq!(Message::decode(&Script::new()), None);
+    }
+}
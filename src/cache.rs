@@ -0,0 +1,138 @@
+use crate::proxy::RpcCall;
+use crate::store::ReadStore;
+use parking_lot::Mutex;
+use serde_json::Value;
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+fn default_max_bytes() -> usize {
+    64 * 1024 * 1024
+}
+
+#[derive(Clone, Deserialize)]
+pub struct CacheConfig {
+    #[serde(default = "default_max_bytes")]
+    pub max_bytes: usize,
+}
+impl Default for CacheConfig {
+    fn default() -> Self {
+        CacheConfig {
+            max_bytes: default_max_bytes(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub entries: usize,
+    pub bytes: usize,
+}
+
+struct Entries {
+    map: HashMap<String, (Value, usize)>,
+    order: VecDeque<String>,
+    bytes: usize,
+}
+
+pub struct RpcCache {
+    max_bytes: usize,
+    entries: Mutex<Entries>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl RpcCache {
+    pub fn new(conf: CacheConfig) -> Self {
+        RpcCache {
+            max_bytes: conf.max_bytes,
+            entries: Mutex::new(Entries {
+                map: HashMap::new(),
+                order: VecDeque::new(),
+                bytes: 0,
+            }),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    // Only the raw forms are immutable: the verbose forms report confirmations. A transaction is
+    // only cached once it is confirmed in the index, as a mempool transaction can still be dropped.
+    pub fn key(db: &dyn ReadStore, call: &RpcCall) -> Option<String> {
+        let params = call.params.as_array()?;
+        let raw = match params.get(1) {
+            None => call.method == "getrawtransaction",
+            Some(Value::Bool(b)) => !b,
+            Some(Value::Number(n)) => n.as_u64() == Some(0),
+            _ => false,
+        };
+        match call.method.as_str() {
+            "getblock" | "getblockheader" if raw && params.len() <= 2 => {
+                Some(format!("{}:{}", call.method, params.get(0)?.as_str()?))
+            }
+            "getrawtransaction" if raw && params.len() <= 2 => {
+                let txid = params.get(0)?.as_str()?;
+                let mut tx_key = Vec::with_capacity(33);
+                tx_key.push(5_u8);
+                tx_key.extend(hex::decode(txid).ok()?);
+                db.get(&tx_key).ok()??;
+                Some(format!("{}:{}", call.method, txid))
+            }
+            _ => None,
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<Value> {
+        let res = self.entries.lock().map.get(key).map(|(v, _)| v.clone());
+        match res {
+            Some(_) => self.hits.fetch_add(1, Ordering::Relaxed),
+            None => self.misses.fetch_add(1, Ordering::Relaxed),
+        };
+        res
+    }
+
+    pub fn insert(&self, key: String, value: Value) {
+        let size = match value {
+            Value::String(ref s) => s.len(),
+            ref v => v.to_string().len(),
+        } + key.len();
+        if size > self.max_bytes {
+            return;
+        }
+        let mut entries = self.entries.lock();
+        if entries.map.contains_key(&key) {
+            return;
+        }
+        while entries.bytes + size > self.max_bytes {
+            match entries.order.pop_front() {
+                Some(old) => {
+                    if let Some((_, s)) = entries.map.remove(&old) {
+                        entries.bytes -= s;
+                    }
+                }
+                None => break,
+            }
+        }
+        entries.bytes += size;
+        entries.order.push_back(key.clone());
+        entries.map.insert(key, (value, size));
+    }
+
+    pub fn clear(&self) {
+        let mut entries = self.entries.lock();
+        entries.map.clear();
+        entries.order.clear();
+        entries.bytes = 0;
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let entries = self.entries.lock();
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            entries: entries.map.len(),
+            bytes: entries.bytes,
+        }
+    }
+}
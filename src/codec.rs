@@ -0,0 +1,558 @@
+use crate::store::{ReadStore, Store};
+use bitcoin::consensus::encode::{deserialize, serialize};
+use bitcoin::{OutPoint, Script, Transaction, TxIn, TxOut};
+use bitcoin_hashes::{sha256d, Hash};
+use failure::Error;
+
+// How raw transactions (prefix 4) and address slots (prefix 1) are stored. Legacy is the
+// original layout: consensus encoded transactions and 44 byte slots. Compact uses varints,
+// compressed amounts and script templates, like the chainstate of Bitcoin Core. The encoding
+// is chosen when a database is created and recorded under [0, 2].
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoding {
+    Legacy,
+    Compact,
+}
+impl Default for Encoding {
+    fn default() -> Self {
+        Encoding::Compact
+    }
+}
+
+// Block compression of leveldb tables. It can be changed at any time: tables written before the
+// change are still read.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    None,
+    Snappy,
+}
+impl Default for Compression {
+    fn default() -> Self {
+        Compression::Snappy
+    }
+}
+impl From<Compression> for leveldb_sys::Compression {
+    fn from(c: Compression) -> Self {
+        match c {
+            Compression::None => leveldb_sys::Compression::No,
+            Compression::Snappy => leveldb_sys::Compression::Snappy,
+        }
+    }
+}
+
+// script templates, as in Bitcoin Core; 4 and 5 are left for uncompressed keys
+const SCRIPT_P2PKH: u128 = 0;
+const SCRIPT_P2SH: u128 = 1;
+const SPECIAL_SCRIPTS: u128 = 6;
+
+// MSB first base 128, where each continuation byte also adds one, so that every number has
+// exactly one encoding.
+fn write_varint(out: &mut Vec<u8>, mut n: u128) {
+    let mut tmp = [0_u8; 19];
+    let mut len = 0;
+    loop {
+        tmp[len] = (n & 0x7f) as u8 | if len > 0 { 0x80 } else { 0 };
+        if n <= 0x7f {
+            break;
+        }
+        n = (n >> 7) - 1;
+        len += 1;
+    }
+    out.extend(tmp[..=len].iter().rev());
+}
+
+pub fn compress_amount(mut n: u64) -> u128 {
+    if n == 0 {
+        return 0;
+    }
+    let mut e = 0;
+    while n % 10 == 0 && e < 9 {
+        n /= 10;
+        e += 1;
+    }
+    let n = n as u128;
+    if e < 9 {
+        let d = n % 10;
+        1 + ((n / 10) * 9 + d - 1) * 10 + e
+    } else {
+        1 + (n - 1) * 10 + 9
+    }
+}
+
+pub fn decompress_amount(x: u128) -> Result<u64, Error> {
+    if x == 0 {
+        return Ok(0);
+    }
+    let x = x - 1;
+    let mut e = x % 10;
+    let x = x / 10;
+    let mut n = if e < 9 {
+        (x / 9) * 10 + x % 9 + 1
+    } else {
+        x + 1
+    };
+    loop {
+        if n > std::u64::MAX as u128 {
+            bail!("amount out of range");
+        }
+        if e == 0 {
+            return Ok(n as u64);
+        }
+        n *= 10;
+        e -= 1;
+    }
+}
+
+fn write_script(out: &mut Vec<u8>, script: &[u8]) {
+    if script.len() == 25
+        && script.starts_with(&[0x76, 0xa9, 0x14])
+        && script.ends_with(&[0x88, 0xac])
+    {
+        write_varint(out, SCRIPT_P2PKH);
+        out.extend(&script[3..23]);
+    } else if script.len() == 23 && script.starts_with(&[0xa9, 0x14]) && script[22] == 0x87 {
+        write_varint(out, SCRIPT_P2SH);
+        out.extend(&script[2..22]);
+    } else if script.len() == 35
+        && script[0] == 0x21
+        && (script[1] == 2 || script[1] == 3)
+        && script[34] == 0xac
+    {
+        // the template is the first byte of the key
+        out.extend(&script[1..34]);
+    } else {
+        write_varint(out, script.len() as u128 + SPECIAL_SCRIPTS);
+        out.extend(script);
+    }
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8], Error> {
+        let b = self
+            .buf
+            .get(self.pos..self.pos + n)
+            .ok_or(format_err!("unexpected end of input"))?;
+        self.pos += n;
+        Ok(b)
+    }
+
+    fn varint(&mut self) -> Result<u128, Error> {
+        let mut n = 0_u128;
+        loop {
+            if n > std::u128::MAX >> 7 {
+                bail!("varint out of range");
+            }
+            let b = self.bytes(1)?[0];
+            n = n << 7 | (b & 0x7f) as u128;
+            if b & 0x80 == 0 {
+                return Ok(n);
+            }
+            n += 1;
+        }
+    }
+
+    fn u32(&mut self) -> Result<u32, Error> {
+        let n = self.varint()?;
+        if n > std::u32::MAX as u128 {
+            bail!("varint out of range");
+        }
+        Ok(n as u32)
+    }
+
+    fn len(&mut self) -> Result<usize, Error> {
+        let n = self.varint()?;
+        if n > (self.buf.len() - self.pos) as u128 {
+            bail!("unexpected end of input");
+        }
+        Ok(n as usize)
+    }
+
+    fn script(&mut self) -> Result<Script, Error> {
+        let mut script = Vec::new();
+        match self.varint()? {
+            SCRIPT_P2PKH => {
+                script.extend(&[0x76, 0xa9, 0x14]);
+                script.extend(self.bytes(20)?);
+                script.extend(&[0x88, 0xac]);
+            }
+            SCRIPT_P2SH => {
+                script.extend(&[0xa9, 0x14]);
+                script.extend(self.bytes(20)?);
+                script.push(0x87);
+            }
+            t @ 2..=3 => {
+                script.extend(&[0x21, t as u8]);
+                script.extend(self.bytes(32)?);
+                script.push(0xac);
+            }
+            n if n >= SPECIAL_SCRIPTS => {
+                let len = (n - SPECIAL_SCRIPTS) as usize;
+                script.extend(self.bytes(len)?);
+            }
+            n => bail!("unknown script template {}", n),
+        }
+        Ok(Script::from(script))
+    }
+
+    fn end(&self) -> Result<(), Error> {
+        if self.pos != self.buf.len() {
+            bail!("trailing bytes in record");
+        }
+        Ok(())
+    }
+}
+
+// Inputs refer back to an earlier input with the same previous txid rather than repeating it,
+// and sequences are stored inverted so that the usual 0xffffffff takes one byte.
+fn encode_compact(tx: &Transaction) -> Vec<u8> {
+    let mut out = Vec::new();
+    write_varint(&mut out, tx.version as u128);
+    write_varint(&mut out, tx.input.len() as u128);
+    for (i, input) in tx.input.iter().enumerate() {
+        let txid = &input.previous_output.txid;
+        match tx.input[..i]
+            .iter()
+            .position(|prev| &prev.previous_output.txid == txid)
+        {
+            Some(j) => write_varint(&mut out, j as u128 + 1),
+            None => {
+                write_varint(&mut out, 0);
+                out.extend(&txid[..]);
+            }
+        }
+        // the null outpoint of a coinbase becomes 0
+        write_varint(&mut out, input.previous_output.vout.wrapping_add(1) as u128);
+        write_varint(&mut out, input.script_sig.len() as u128);
+        out.extend(input.script_sig.as_bytes());
+        write_varint(&mut out, !input.sequence as u128);
+    }
+    write_varint(&mut out, tx.output.len() as u128);
+    for output in tx.output.iter() {
+        write_varint(&mut out, compress_amount(output.value));
+        write_script(&mut out, output.script_pubkey.as_bytes());
+    }
+    write_varint(&mut out, tx.lock_time as u128);
+    out
+}
+
+fn decode_compact(buf: &[u8]) -> Result<Transaction, Error> {
+    let mut r = Reader { buf, pos: 0 };
+    let version = r.u32()?;
+    let mut input: Vec<TxIn> = Vec::new();
+    for _ in 0..r.len()? {
+        let txid = match r.varint()? {
+            0 => sha256d::Hash::from_slice(r.bytes(32)?)?,
+            j if j <= input.len() as u128 => input[j as usize - 1].previous_output.txid,
+            _ => bail!("invalid txid reference"),
+        };
+        let vout = r.u32()?.wrapping_sub(1);
+        let len = r.len()?;
+        let script_sig = Script::from(r.bytes(len)?.to_vec());
+        let sequence = !r.u32()?;
+        input.push(TxIn {
+            previous_output: OutPoint { txid, vout },
+            script_sig,
+            sequence,
+            witness: Vec::new(),
+        });
+    }
+    let mut output = Vec::new();
+    for _ in 0..r.len()? {
+        let value = decompress_amount(r.varint()?)?;
+        let script_pubkey = r.script()?;
+        output.push(TxOut {
+            value,
+            script_pubkey,
+        });
+    }
+    let lock_time = r.u32()?;
+    r.end()?;
+    Ok(Transaction {
+        version,
+        input,
+        output,
+        lock_time,
+    })
+}
+
+impl Encoding {
+    pub fn load(db: &dyn ReadStore) -> Result<Self, Error> {
+        match db.get(&[0_u8, 2])? {
+            None => Ok(Encoding::Legacy),
+            Some(ref e) if e.as_slice() == [0] => Ok(Encoding::Legacy),
+            Some(ref e) if e.as_slice() == [1] => Ok(Encoding::Compact),
+            Some(e) => bail!("unknown record encoding {}", hex::encode(e)),
+        }
+    }
+
+    // A new database gets the configured encoding. Databases that were indexed before the
+    // encoding was recorded keep the legacy one.
+    pub fn init(db: &dyn Store, configured: Encoding) -> Result<Self, Error> {
+        let stored = db.get(&[0_u8, 2])?;
+        let encoding = if stored.is_some() {
+            Encoding::load(db.as_read())?
+        } else if db.get(&[0_u8])?.is_some() {
+            Encoding::Legacy
+        } else {
+            configured
+        };
+        if stored.is_none() {
+            encoding.store(db)?;
+        }
+        if encoding != configured {
+            warn!(
+                "database uses {:?} records, ignoring configured {:?}",
+                encoding, configured
+            );
+        }
+        Ok(encoding)
+    }
+
+    pub fn store(self, db: &dyn Store) -> Result<(), Error> {
+        let tag = match self {
+            Encoding::Legacy => 0_u8,
+            Encoding::Compact => 1_u8,
+        };
+        db.put(&[0_u8, 2], &[tag])?;
+        Ok(())
+    }
+
+    pub fn encode_tx(self, tx: &Transaction) -> Vec<u8> {
+        match self {
+            Encoding::Legacy => serialize(tx),
+            Encoding::Compact => encode_compact(tx),
+        }
+    }
+
+    pub fn decode_tx(self, stored: &[u8]) -> Result<Transaction, Error> {
+        match self {
+            Encoding::Legacy => Ok(deserialize(stored)?),
+            Encoding::Compact => decode_compact(stored),
+        }
+    }
+
+    // The consensus encoding of a stored transaction, as served by the API.
+    pub fn raw_tx(self, stored: &[u8]) -> Result<Vec<u8>, Error> {
+        match self {
+            Encoding::Legacy => Ok(stored.to_vec()),
+            Encoding::Compact => Ok(serialize(&decode_compact(stored)?)),
+        }
+    }
+
+    pub fn encode_slot(self, txid: &[u8; 32], vout: u32, value: u64) -> Vec<u8> {
+        let mut out = Vec::with_capacity(44);
+        out.extend(txid);
+        match self {
+            Encoding::Legacy => {
+                out.extend(&vout.to_ne_bytes());
+                out.extend(&value.to_ne_bytes());
+            }
+            Encoding::Compact => {
+                write_varint(&mut out, vout as u128);
+                write_varint(&mut out, compress_amount(value));
+            }
+        }
+        out
+    }
+
+    pub fn decode_slot(self, stored: &[u8]) -> Result<([u8; 32], u32, u64), Error> {
+        let mut r = Reader {
+            buf: stored,
+            pos: 0,
+        };
+        let mut txid = [0_u8; 32];
+        txid.clone_from_slice(r.bytes(32)?);
+        let (vout, value) = match self {
+            Encoding::Legacy => {
+                let mut vout = [0_u8; 4];
+                vout.clone_from_slice(r.bytes(4)?);
+                let mut value = [0_u8; 8];
+                value.clone_from_slice(r.bytes(8)?);
+                (u32::from_ne_bytes(vout), u64::from_ne_bytes(value))
+            }
+            Encoding::Compact => (r.u32()?, decompress_amount(r.varint()?)?),
+        };
+        r.end()?;
+        Ok((txid, vout, value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn varint(n: u128) -> Vec<u8> {
+        let mut out = Vec::new();
+        write_varint(&mut out, n);
+        out
+    }
+
+    fn script(bytes: &[u8]) -> Script {
+        Script::from(bytes.to_vec())
+    }
+
+    #[test]
+    fn round_trips_varints() -> Result<(), Error> {
+        assert_eq!(varint(0), vec![0]);
+        assert_eq!(varint(0x7f), vec![0x7f]);
+        assert_eq!(varint(0x80), vec![0x80, 0]);
+        for &n in [
+            0,
+            1,
+            0x7f,
+            0x80,
+            0x407f,
+            0x4080,
+            std::u64::MAX as u128,
+            std::u128::MAX,
+        ]
+        .iter()
+        {
+            let buf = varint(n);
+            let mut r = Reader { buf: &buf, pos: 0 };
+            assert_eq!(r.varint()?, n);
+            r.end()?;
+        }
+        let mut r = Reader {
+            buf: &[0xff; 20],
+            pos: 0,
+        };
+        assert!(r.varint().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn round_trips_amounts() -> Result<(), Error> {
+        assert_eq!(compress_amount(0), 0);
+        for &n in [0, 1, 9, 10, 123_456_789, std::u64::MAX].iter() {
+            assert_eq!(decompress_amount(compress_amount(n))?, n);
+        }
+        for coins in 1..=100 {
+            let n = coins * 100_000_000;
+            assert_eq!(decompress_amount(compress_amount(n))?, n);
+            // whole coins fit in two bytes
+            assert!(varint(compress_amount(n)).len() <= 2);
+        }
+        assert!(decompress_amount(std::u128::MAX).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn round_trips_script_templates() -> Result<(), Error> {
+        let mut p2pkh = vec![0x76, 0xa9, 0x14];
+        p2pkh.extend(&[1; 20]);
+        p2pkh.extend(&[0x88, 0xac]);
+        let mut p2sh = vec![0xa9, 0x14];
+        p2sh.extend(&[2; 20]);
+        p2sh.push(0x87);
+        let mut p2pk = vec![0x21, 0x03];
+        p2pk.extend(&[3; 32]);
+        p2pk.push(0xac);
+        let mut uncompressed = vec![0x41, 0x04];
+        uncompressed.extend(&[4; 64]);
+        uncompressed.push(0xac);
+        let cases: [(&[u8], usize); 6] = [
+            (&p2pkh, 21),
+            (&p2sh, 21),
+            (&p2pk, 33),
+            (&uncompressed, 68),
+            (&[0x6a, 0x02, 0xbe, 0xef], 5),
+            (&[], 1),
+        ];
+        for &(bytes, len) in cases.iter() {
+            let mut out = Vec::new();
+            write_script(&mut out, bytes);
+            assert_eq!(out.len(), len, "{}", hex::encode(bytes));
+            let mut r = Reader { buf: &out, pos: 0 };
+            assert_eq!(r.script()?, script(bytes));
+            r.end()?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn round_trips_transactions() -> Result<(), Error> {
+        let coinbase = Transaction {
+            version: 1,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: OutPoint::null(),
+                script_sig: script(&[0x03, 0x01, 0x02, 0x03]),
+                sequence: 0xffff_ffff,
+                witness: Vec::new(),
+            }],
+            output: vec![TxOut {
+                value: 10_000 * 100_000_000,
+                script_pubkey: script(&[0x51]),
+            }],
+        };
+        let prev = coinbase.txid();
+        let input = |vout, sequence| TxIn {
+            previous_output: OutPoint { txid: prev, vout },
+            script_sig: script(&[0x51]),
+            sequence,
+            witness: Vec::new(),
+        };
+        let spend = Transaction {
+            version: 2,
+            lock_time: 500_000,
+            input: vec![input(0, 0xffff_fffe), input(1, 0), input(7, 0xffff_ffff)],
+            output: vec![
+                TxOut {
+                    value: 0,
+                    script_pubkey: script(&[0x6a]),
+                },
+                TxOut {
+                    value: std::u64::MAX,
+                    script_pubkey: script(&[
+                        0xa9, 0x14, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+                        0x87,
+                    ]),
+                },
+            ],
+        };
+        for tx in [coinbase, spend].iter() {
+            let compact = Encoding::Compact.encode_tx(tx);
+            assert!(compact.len() < serialize(tx).len());
+            assert_eq!(&decode_compact(&compact)?, tx);
+            assert_eq!(Encoding::Compact.raw_tx(&compact)?, serialize(tx));
+            assert_eq!(
+                &Encoding::Legacy.decode_tx(&Encoding::Legacy.encode_tx(tx))?,
+                tx
+            );
+        }
+        let compact = Encoding::Compact.encode_tx(&Transaction {
+            version: 1,
+            lock_time: 0,
+            input: Vec::new(),
+            output: Vec::new(),
+        });
+        let mut trailing = compact.clone();
+        trailing.push(0);
+        assert!(decode_compact(&trailing).is_err());
+        assert!(decode_compact(&compact[..compact.len() - 1]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn round_trips_slots() -> Result<(), Error> {
+        for &encoding in [Encoding::Legacy, Encoding::Compact].iter() {
+            let slot = encoding.encode_slot(&[5; 32], 3, 42 * 100_000_000);
+            assert_eq!(encoding.decode_slot(&slot)?, ([5; 32], 3, 42 * 100_000_000));
+        }
+        assert_eq!(
+            Encoding::Compact
+                .encode_slot(&[5; 32], 3, 100_000_000)
+                .len(),
+            34
+        );
+        Ok(())
+    }
+}
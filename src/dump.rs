@@ -0,0 +1,15 @@
+use crate::store::Store;
+use failure::Error;
+use std::io::Write;
+
+pub fn dump<W: Write>(db: &dyn Store, prefix: Option<u8>, out: &mut W) -> Result<(), Error> {
+    let start = prefix.map(|p| vec![p]).unwrap_or_default();
+    for (key, value) in db.iter(&start) {
+        match prefix {
+            Some(p) if key.get(0) != Some(&p) => break,
+            _ => (),
+        }
+        writeln!(out, "{} {}", hex::encode(&*key), hex::encode(&value))?;
+    }
+    Ok(())
+}
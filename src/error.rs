@@ -0,0 +1,23 @@
+use hyper::StatusCode;
+
+#[derive(Debug, Fail)]
+#[fail(display = "{}", message)]
+pub struct HttpError {
+    pub status: StatusCode,
+    pub message: String,
+    pub retry_after: Option<u64>,
+}
+impl HttpError {
+    pub fn new<S: Into<String>>(status: StatusCode, message: S) -> Self {
+        HttpError {
+            status,
+            message: message.into(),
+            retry_after: None,
+        }
+    }
+
+    pub fn with_retry_after(mut self, secs: u64) -> Self {
+        self.retry_after = Some(secs);
+        self
+    }
+}
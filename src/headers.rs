@@ -0,0 +1,332 @@
+// The indexed header chain: headers by height under prefix 8 and heights by hash under prefix 9,
+// and optional checks of each block's header against it: proof of work, difficulty retargeting,
+// timestamps and the merkle root.
+
+use crate::auxpow::{self, CHAIN_ID};
+use crate::block::Block;
+use crate::network::Network;
+use crate::store::Store;
+use crate::upstream::Pool;
+use bitcoin::consensus::encode::{deserialize, serialize};
+use bitcoin::util::hash::{bitcoin_merkle_root, BitcoinHash};
+use bitcoin::util::uint::Uint256;
+use bitcoin::BlockHeader;
+use failure::Error;
+
+const TARGET_SPACING: i64 = 60;
+const TARGET_TIMESPAN: i64 = 4 * 60 * 60;
+const MAX_FUTURE_TIME: i64 = 2 * 60 * 60;
+const MEDIAN_TIME_SPAN: u32 = 11;
+
+// Indexing halts on this error rather than skip or index the block.
+#[derive(Debug, Fail)]
+#[fail(display = "invalid block {} at {}: {}", hash, height, reason)]
+pub struct InvalidBlock {
+    pub height: u32,
+    pub hash: String,
+    pub reason: String,
+}
+
+fn header_key(height: u32) -> Vec<u8> {
+    let mut key = Vec::with_capacity(5);
+    key.push(8_u8);
+    key.extend(&height.to_ne_bytes());
+    key
+}
+
+fn display_hash(header: &BlockHeader) -> Vec<u8> {
+    let mut hash = header.bitcoin_hash()[..].to_vec();
+    hash.reverse();
+    hash
+}
+
+fn height_key(hash: &[u8]) -> Vec<u8> {
+    let mut key = Vec::with_capacity(33);
+    key.push(9_u8);
+    key.extend(hash);
+    key
+}
+
+pub fn index(db: &dyn Store, height: u32, header: &BlockHeader) -> Result<(), Error> {
+    db.put(&header_key(height), &serialize(header))?;
+    db.put(&height_key(&display_hash(header)), &height.to_ne_bytes())
+}
+
+// Removes the block `hash` at `height`, as displayed, from the header chain.
+pub fn unindex(db: &dyn Store, height: u32, hash: &[u8]) -> Result<(), Error> {
+    db.delete(&header_key(height))?;
+    db.delete(&height_key(hash))
+}
+
+// The header of the indexed block at `height`. Blocks indexed before headers were, or imported
+// from a snapshot, have no stored header, so it is fetched by the indexed hash.
+fn header_at(pool: &Pool, db: &dyn Store, height: u32) -> Result<BlockHeader, Error> {
+    if let Some(raw) = db.get(&header_key(height))? {
+        return Ok(deserialize(&raw)?);
+    }
+    let mut block_key = Vec::with_capacity(5);
+    block_key.push(3_u8);
+    block_key.extend(&height.to_ne_bytes());
+    let hash = db
+        .get(&block_key)?
+        .ok_or(format_err!("missing block_hash"))?;
+    let raw = pool.getblock(&hex::encode(&hash))?;
+    let header: BlockHeader = deserialize(
+        raw.get(..80)
+            .ok_or(format_err!("block {} is too short", hex::encode(&hash)))?,
+    )?;
+    if display_hash(&header) != hash {
+        bail!("node returned another block for {}", hex::encode(&hash));
+    }
+    index(db, height, &header)?;
+    Ok(header)
+}
+
+// The difficulty after a retarget at `height`, given how long the last interval took: Litecoin's
+// four hour retargeting with limits that tighten over the first 10000 blocks, then DigiShield's
+// damped retargeting every block.
+fn retarget(network: Network, height: u32, bits: u32, actual: i64) -> u32 {
+    let digishield = height >= network.digishield_height();
+    let timespan = if digishield {
+        TARGET_SPACING
+    } else {
+        TARGET_TIMESPAN
+    };
+    let (modulated, min, max) = if digishield {
+        (
+            timespan + (actual - timespan) / 8,
+            timespan - timespan / 4,
+            timespan + timespan / 2,
+        )
+    } else if height > 10000 {
+        (actual, timespan / 4, timespan * 4)
+    } else if height > 5000 {
+        (actual, timespan / 8, timespan * 4)
+    } else {
+        (actual, timespan / 16, timespan * 4)
+    };
+    let modulated = modulated.max(min).min(max);
+    let target = auxpow::target(bits).mul_u32(modulated as u32)
+        / Uint256::from_u64(timespan as u64).unwrap();
+    let limit = auxpow::target(network.pow_limit());
+    BlockHeader::compact_target_from_u256(if target > limit { &limit } else { &target })
+}
+
+// The bits dogecoind requires of the block at `height`, after `prev`.
+fn expected_bits(
+    pool: &Pool,
+    db: &dyn Store,
+    network: Network,
+    height: u32,
+    prev: &BlockHeader,
+    time: u32,
+) -> Result<u32, Error> {
+    if !network.retargets() {
+        return Ok(prev.bits);
+    }
+    let limit = network.pow_limit();
+    let digishield = height >= network.digishield_height();
+    let slow = time as i64 > prev.time as i64 + 2 * TARGET_SPACING;
+    let min_difficulty = network.min_difficulty_blocks(height);
+    if digishield && min_difficulty && slow {
+        return Ok(limit);
+    }
+    let interval = if digishield {
+        1
+    } else {
+        (TARGET_TIMESPAN / TARGET_SPACING) as u32
+    };
+    if height % interval != 0 {
+        if !min_difficulty {
+            return Ok(prev.bits);
+        }
+        if slow {
+            return Ok(limit);
+        }
+        // the difficulty of the last block not mined at the minimum
+        let (mut h, mut header) = (height - 1, prev.clone());
+        while h > 1 && h % interval != 0 && header.bits == limit {
+            h -= 1;
+            header = header_at(pool, db, h)?;
+        }
+        return Ok(header.bits);
+    }
+
+    // the first retarget looks back one block less, as the genesis block has no parent
+    let back = if height == interval {
+        interval - 1
+    } else {
+        interval
+    };
+    let first_time = match height - 1 - back {
+        0 => network.genesis_time(),
+        h => header_at(pool, db, h)?.time,
+    };
+    Ok(retarget(
+        network,
+        height,
+        prev.bits,
+        prev.time as i64 - first_time as i64,
+    ))
+}
+
+// The median time of the last 11 blocks up to `prev`. The genesis block is left out.
+fn median_time_past(
+    pool: &Pool,
+    db: &dyn Store,
+    height: u32,
+    prev: &BlockHeader,
+) -> Result<u32, Error> {
+    let mut times = vec![prev.time];
+    for h in height.saturating_sub(MEDIAN_TIME_SPAN).max(1)..height - 1 {
+        times.push(header_at(pool, db, h)?.time);
+    }
+    times.sort();
+    Ok(times[times.len() / 2])
+}
+
+// Blocks from before chain ids, which dogecoind exempts from the chain id check.
+fn legacy(version: u32) -> bool {
+    version == 1 || version == 2
+}
+
+// Checks `block` at `height` against the indexed chain below it, the way dogecoind checks a
+// header and its merkle root. The genesis header is not indexed, so block 1 is only checked to
+// follow it.
+pub fn check(pool: &Pool, db: &dyn Store, height: u32, block: &Block) -> Result<(), Error> {
+    let header = &block.header;
+    let network = block.network;
+    let invalid = |reason: String| -> Error {
+        InvalidBlock {
+            height,
+            hash: hex::encode(display_hash(header)),
+            reason,
+        }
+        .into()
+    };
+
+    if height == 1 {
+        let mut prev = header.prev_blockhash[..].to_vec();
+        prev.reverse();
+        if prev != hex::decode(network.genesis_hash())? {
+            return Err(invalid("does not follow the genesis block".to_owned()));
+        }
+    } else {
+        let prev = header_at(pool, db, height - 1)?;
+        if header.prev_blockhash != prev.bitcoin_hash() {
+            return Err(invalid(format!(
+                "does not follow the indexed block {}",
+                hex::encode(display_hash(&prev))
+            )));
+        }
+        let bits = expected_bits(pool, db, network, height, &prev, header.time)?;
+        if header.bits != bits {
+            return Err(invalid(format!(
+                "bits {:08x}, expected {:08x}",
+                header.bits, bits
+            )));
+        }
+        let median = median_time_past(pool, db, height, &prev)?;
+        if header.time <= median {
+            return Err(invalid(format!(
+                "time {} is not after the median time past {}",
+                header.time, median
+            )));
+        }
+    }
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)?
+        .as_secs() as i64;
+    if header.time as i64 > now + MAX_FUTURE_TIME {
+        return Err(invalid(format!(
+            "time {} is more than two hours in the future",
+            header.time
+        )));
+    }
+
+    if network.strict_chain_id()
+        && !legacy(header.version)
+        && auxpow::chain_id(header.version) != CHAIN_ID
+    {
+        return Err(invalid(format!(
+            "chain id {:x}, expected {:x}",
+            auxpow::chain_id(header.version),
+            CHAIN_ID
+        )));
+    }
+    match block.auxpow {
+        Some(ref auxpow) => auxpow
+            .check(header, network)
+            .map_err(|e| invalid(e.to_string()))?,
+        None => {
+            if !auxpow::meets_target(&auxpow::pow_hash(header), header.bits) {
+                return Err(invalid("proof of work does not meet the target".to_owned()));
+            }
+        }
+    }
+
+    let txids = block
+        .clone()
+        .map(|tx| tx.map(|tx| tx.txid()))
+        .collect::<Result<Vec<_>, Error>>()?;
+    if txids.is_empty() || bitcoin_merkle_root(txids) != header.merkle_root {
+        return Err(invalid(
+            "merkle root does not match the transactions".to_owned(),
+        ));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NETWORK: Network = Network::Mainnet;
+    const BITS: u32 = 0x1b04_04cb;
+
+    fn scaled(bits: u32, num: u32, den: u64) -> u32 {
+        let target = auxpow::target(bits).mul_u32(num) / Uint256::from_u64(den).unwrap();
+        BlockHeader::compact_target_from_u256(&target)
+    }
+
+    #[test]
+    fn keeps_difficulty_on_schedule() {
+        let digishield = NETWORK.digishield_height();
+        assert_eq!(retarget(NETWORK, 240 * 50, BITS, TARGET_TIMESPAN), BITS);
+        assert_eq!(retarget(NETWORK, digishield, BITS, TARGET_SPACING), BITS);
+    }
+
+    #[test]
+    fn digishield_damps_and_limits_adjustments() {
+        let height = NETWORK.digishield_height() + 1;
+        // 80 seconds late is damped to an eighth: a modulated timespan of 70 seconds for 60
+        assert_eq!(retarget(NETWORK, height, BITS, 140), scaled(BITS, 70, 60));
+        assert_eq!(
+            retarget(NETWORK, height, BITS, 10_000),
+            scaled(BITS, 90, 60)
+        );
+        assert_eq!(
+            retarget(NETWORK, height, BITS, -10_000),
+            scaled(BITS, 45, 60)
+        );
+    }
+
+    #[test]
+    fn early_limits_tighten() {
+        let slow = TARGET_TIMESPAN * 100;
+        assert_eq!(retarget(NETWORK, 240 * 50, BITS, 0), scaled(BITS, 1, 4));
+        assert_eq!(retarget(NETWORK, 240 * 25, BITS, 0), scaled(BITS, 1, 8));
+        assert_eq!(retarget(NETWORK, 240 * 10, BITS, 0), scaled(BITS, 1, 16));
+        assert_eq!(retarget(NETWORK, 240 * 10, BITS, slow), scaled(BITS, 4, 1));
+    }
+
+    #[test]
+    fn never_exceeds_pow_limit() {
+        let limit = NETWORK.pow_limit();
+        assert_eq!(
+            retarget(NETWORK, 240 * 50, limit, TARGET_TIMESPAN * 4),
+            limit
+        );
+    }
+}
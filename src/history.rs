@@ -0,0 +1,159 @@
+use crate::store::{ReadStore, Store};
+use failure::Error;
+
+// Prefix 6 maps address ++ height ++ txid to the amount the transaction paid to the address, and
+// prefix 7 maps height ++ address ++ txid to nothing so that a block's entries can be found again
+// by `undo`. Heights are big endian so that entries iterate in height order.
+//
+// Databases indexed before the history was recorded only have it from the height stored under
+// [0, 3], and queries reaching below it fail rather than return part of the history.
+
+const START_KEY: [u8; 2] = [0, 3];
+
+// Records where the history starts, the next height to index, the first time a database is
+// opened.
+pub fn init(db: &dyn Store) -> Result<(), Error> {
+    if db.get(&START_KEY)?.is_some() {
+        return Ok(());
+    }
+    let start = db.get(&[0_u8])?.unwrap_or(1_u32.to_ne_bytes().to_vec());
+    db.put(&START_KEY, &start)
+}
+
+// A database that was never opened writable by this version, such as an earlier one served
+// with `api-only`, has no start recorded and no history below its tip.
+pub fn start(db: &dyn ReadStore) -> Result<u32, Error> {
+    let b = match db.get(&START_KEY)? {
+        Some(b) => b,
+        None => match db.get(&[0_u8])? {
+            Some(b) => b,
+            None => return Ok(1),
+        },
+    };
+    let mut buf = [0_u8; 4];
+    if b.len() != 4 {
+        bail!("invalid size for u32");
+    }
+    buf.clone_from_slice(&b);
+    Ok(u32::from_ne_bytes(buf))
+}
+
+pub fn set_start(db: &dyn Store, height: u32) -> Result<(), Error> {
+    db.put(&START_KEY, &height.max(1).to_ne_bytes())
+}
+
+// Moves the start of the history down to `height`, once every block from it on is reindexed.
+pub fn extend(db: &dyn Store, height: u32) -> Result<(), Error> {
+    if height.max(1) < start(db.as_read())? {
+        set_start(db, height)?;
+    }
+    Ok(())
+}
+
+fn check_start(db: &dyn ReadStore, height: u32) -> Result<(), Error> {
+    let start = start(db)?;
+    if height.max(1) < start {
+        bail!(
+            "address history is only indexed from height {}, reindex to extend it",
+            start
+        );
+    }
+    Ok(())
+}
+
+fn history_key(address: &[u8], height: u32, txid: &[u8; 32]) -> Vec<u8> {
+    let mut key = Vec::with_capacity(58);
+    key.push(6_u8);
+    key.extend(address);
+    key.extend(&height.to_be_bytes());
+    key.extend(txid);
+    key
+}
+
+// `received` is what one output of the transaction paid to the address, or 0 for an input.
+pub fn add(
+    db: &dyn Store,
+    address: &[u8; 21],
+    height: u32,
+    txid: &[u8; 32],
+    received: u64,
+) -> Result<(), Error> {
+    let key = history_key(address, height, txid);
+    let received = received + amount(&db.get(&key)?.unwrap_or_default());
+    db.put(&key, &received.to_ne_bytes())?;
+    let mut undo_key = Vec::with_capacity(58);
+    undo_key.push(7_u8);
+    undo_key.extend(&height.to_be_bytes());
+    undo_key.extend(address.as_ref());
+    undo_key.extend(txid);
+    db.put(&undo_key, &[])?;
+    Ok(())
+}
+
+pub fn undo(db: &dyn Store, height: u32) -> Result<(), Error> {
+    let mut prefix = Vec::with_capacity(5);
+    prefix.push(7_u8);
+    prefix.extend(&height.to_be_bytes());
+    let keys: Vec<Vec<u8>> = db
+        .iter(&prefix)
+        .map(|(k, _)| k)
+        .take_while(|k| k.starts_with(&prefix))
+        .collect();
+    for undo_key in keys {
+        let mut txid = [0_u8; 32];
+        txid.clone_from_slice(&undo_key[26..58]);
+        db.delete(&history_key(&undo_key[5..26], height, &txid))?;
+        db.delete(&undo_key)?;
+    }
+    Ok(())
+}
+
+// The amount stored in a prefix 6 entry.
+pub fn amount(value: &[u8]) -> u64 {
+    let mut buf = [0_u8; 8];
+    if value.len() == 8 {
+        buf.clone_from_slice(value);
+    }
+    u64::from_ne_bytes(buf)
+}
+
+pub fn txids(
+    db: &dyn ReadStore,
+    address: &[u8; 21],
+    start: u32,
+    end: u32,
+) -> Result<Vec<(u32, [u8; 32])>, Error> {
+    check_start(db, start)?;
+    let mut prefix = Vec::with_capacity(22);
+    prefix.push(6_u8);
+    prefix.extend(address.as_ref());
+    let mut res = Vec::new();
+    for (key, _) in db.iter(&history_key(address, start, &[0_u8; 32])) {
+        if !key.starts_with(&prefix) {
+            break;
+        }
+        let mut height = [0_u8; 4];
+        height.clone_from_slice(&key[22..26]);
+        let height = u32::from_be_bytes(height);
+        if height > end {
+            break;
+        }
+        let mut txid = [0_u8; 32];
+        txid.clone_from_slice(&key[26..58]);
+        res.push((height, txid));
+    }
+    Ok(res)
+}
+
+// The total ever paid to `address`.
+pub fn received(db: &dyn ReadStore, address: &[u8; 21]) -> Result<u64, Error> {
+    check_start(db, 0)?;
+    let mut prefix = Vec::with_capacity(22);
+    prefix.push(6_u8);
+    prefix.extend(address.as_ref());
+    Ok(db
+        .iter(&prefix)
+        .take_while(|(k, _)| k.starts_with(&prefix))
+        .map(|(_, v)| amount(&v))
+        .sum())
+}
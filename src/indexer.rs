@@ -0,0 +1,109 @@
+use crate::cache::RpcCache;
+use crate::network::Network;
+use crate::query::{Query, Tip};
+use crate::shutdown::Shutdown;
+use crate::store::Store;
+use crate::upstream::Pool;
+use crate::utxo::StorageMode;
+use crossbeam_channel::{Receiver, Sender};
+use failure::Error;
+use parking_lot::Mutex;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use std::thread::JoinHandle;
+
+// Each subscriber gets the new tip after every indexed block, including blocks indexed after a
+// reorg. Subscribers that have gone away are dropped at the next block.
+#[derive(Default)]
+pub struct Subscribers {
+    senders: Mutex<Vec<Sender<Tip>>>,
+}
+
+impl Subscribers {
+    pub fn subscribe(&self) -> Receiver<Tip> {
+        let (send, recv) = crossbeam_channel::unbounded();
+        self.senders.lock().push(send);
+        recv
+    }
+
+    pub fn notify(&self, tip: &Tip) {
+        self.senders.lock().retain(|s| s.send(tip.clone()).is_ok());
+    }
+}
+
+// How blocks are indexed: the storage mode, and whether each header is validated against the
+// indexed chain before its block is.
+#[derive(Clone, Copy, Default)]
+pub struct SyncConfig {
+    pub mode: StorageMode,
+    pub validate_headers: bool,
+}
+
+// Indexes the chain of the pool's active node into a store, on a fetch thread and a processing
+// thread, until `stop` is called or `shutdown` is requested.
+pub struct Indexer {
+    db: Arc<dyn Store>,
+    network: Network,
+    subscribers: Arc<Subscribers>,
+    shutdown: Shutdown,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl Indexer {
+    pub fn start(
+        db: Arc<dyn Store>,
+        pool: Arc<Pool>,
+        cache: Arc<RpcCache>,
+        conf: SyncConfig,
+        shutdown: Shutdown,
+    ) -> Self {
+        let network = pool.network();
+        let subscribers = Arc::new(Subscribers::default());
+        let (send, recv) = crossbeam_channel::bounded(50);
+        let reset = Arc::new(AtomicBool::new(false));
+        let (d, p, r, s) = (db.clone(), pool.clone(), reset.clone(), shutdown.clone());
+        let fetch = std::thread::spawn(move || crate::sync::fetch(&p, &*d, &send, &r, &s));
+        let (d, subs, s) = (db.clone(), subscribers.clone(), shutdown.clone());
+        let process = std::thread::spawn(move || {
+            crate::sync::process(&pool, &*d, &cache, recv, &reset, conf, &subs, &s)
+        });
+        Indexer {
+            db,
+            network,
+            subscribers,
+            shutdown,
+            threads: vec![process, fetch],
+        }
+    }
+
+    pub fn tip(&self) -> Result<Option<Tip>, Error> {
+        self.query().tip()
+    }
+
+    pub fn subscribe(&self) -> Receiver<Tip> {
+        self.subscribers.subscribe()
+    }
+
+    pub fn query(&self) -> Query {
+        Query::new(&*self.db, self.network)
+    }
+
+    // False once shutdown has been requested, including when indexing halted on an invalid block.
+    pub fn is_running(&self) -> bool {
+        !self.shutdown.requested()
+    }
+
+    pub fn stop(self) {
+        self.shutdown.request();
+        self.join();
+    }
+
+    // Waits for the threads to finish after shutdown has been requested.
+    pub fn join(mut self) {
+        for t in self.threads.drain(..) {
+            if t.join().is_err() {
+                error!("indexer thread panicked");
+            }
+        }
+    }
+}
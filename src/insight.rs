@@ -0,0 +1,132 @@
+use crate::api;
+use crate::network::Network;
+use crate::proxy::{RpcCall, RpcResponse};
+use crate::store::ReadStore;
+use bitcoin::consensus::Decodable;
+use failure::Error;
+use serde_json::{json, Value};
+
+pub const RPC_INVALID_ADDRESS_OR_KEY: i64 = -5;
+
+struct AddressQuery {
+    addresses: Vec<String>,
+    start: u32,
+    end: u32,
+}
+
+// Accepts both `"address"` and `{"addresses": [...], "start": h, "end": h}`, like the
+// addressindex patches do.
+fn parse_query(params: &Value) -> Result<AddressQuery, Error> {
+    let arg = match params {
+        Value::Array(a) => a.get(0),
+        v => Some(v),
+    };
+    match arg {
+        Some(Value::String(a)) => Ok(AddressQuery {
+            addresses: vec![a.clone()],
+            start: 0,
+            end: std::u32::MAX,
+        }),
+        Some(Value::Object(o)) => Ok(AddressQuery {
+            addresses: o
+                .get("addresses")
+                .and_then(|a| a.as_array())
+                .ok_or(format_err!("addresses is expected to be an array"))?
+                .iter()
+                .map(|a| {
+                    a.as_str()
+                        .map(|a| a.to_owned())
+                        .ok_or(format_err!("invalid address"))
+                })
+                .collect::<Result<_, _>>()?,
+            start: o.get("start").and_then(|s| s.as_u64()).unwrap_or(0) as u32,
+            end: o
+                .get("end")
+                .and_then(|s| s.as_u64())
+                .unwrap_or(std::u32::MAX as u64) as u32,
+        }),
+        _ => bail!("addresses is expected to be an array"),
+    }
+}
+
+fn getaddressutxos(db: &dyn ReadStore, network: Network, params: &Value) -> Result<Value, Error> {
+    let query = parse_query(params)?;
+    let mut res = Vec::new();
+    for address in query.addresses {
+        for utxo in api::get_utxos(
+            db,
+            network,
+            &address,
+            std::u64::MAX,
+            Some(std::usize::MAX),
+            true,
+        )? {
+            let tx: bitcoin::Transaction =
+                Decodable::consensus_decode(&mut std::io::Cursor::new(utxo.raw.as_slice()))?;
+            let script = &tx
+                .output
+                .get(utxo.vout as usize)
+                .ok_or(format_err!("vout out of range"))?
+                .script_pubkey;
+            res.push(json!({
+                "address": address,
+                "txid": hex::encode(utxo.txid),
+                "outputIndex": utxo.vout,
+                "script": hex::encode(script.as_bytes()),
+                "satoshis": utxo.value,
+            }));
+        }
+    }
+    Ok(Value::Array(res))
+}
+
+fn getaddressbalance(db: &dyn ReadStore, network: Network, params: &Value) -> Result<Value, Error> {
+    let (mut balance, mut received) = (0, 0);
+    for address in parse_query(params)?.addresses {
+        balance += api::get_balance(db, network, &address)?.total();
+        received += crate::history::received(db, &api::parse_address(network, &address)?)?;
+    }
+    Ok(json!({ "balance": balance, "received": received }))
+}
+
+fn getaddresstxids(db: &dyn ReadStore, network: Network, params: &Value) -> Result<Value, Error> {
+    let query = parse_query(params)?;
+    let mut txids = Vec::new();
+    for address in query.addresses {
+        txids.extend(crate::history::txids(
+            db,
+            &api::parse_address(network, &address)?,
+            query.start,
+            query.end,
+        )?);
+    }
+    txids.sort();
+    txids.dedup();
+    Ok(Value::Array(
+        txids
+            .into_iter()
+            .map(|(_, txid)| Value::String(hex::encode(txid)))
+            .collect(),
+    ))
+}
+
+pub fn handle(db: &dyn ReadStore, network: Network, call: &RpcCall) -> Option<RpcResponse> {
+    let res = match call.method.as_str() {
+        "getaddressutxos" => getaddressutxos(db, network, &call.params),
+        "getaddressbalance" => getaddressbalance(db, network, &call.params),
+        "getaddresstxids" => getaddresstxids(db, network, &call.params),
+        _ => return None,
+    };
+    Some(match res {
+        Ok(result) => RpcResponse {
+            result,
+            error: None,
+            id: call.id.clone(),
+        },
+        Err(e) => RpcResponse::error(
+            call.id.clone(),
+            RPC_INVALID_ADDRESS_OR_KEY,
+            format!("{}", e),
+        ),
+    })
+}
@@ -0,0 +1,82 @@
+// Indexes the Dogecoin utxo set by address. `Indexer` runs the indexer and `Query` reads the
+// index; they and the types re-exported with them are stable. The hidden modules and functions
+// serve the superdoge binary and may change.
+
+#[macro_use]
+extern crate failure;
+#[macro_use]
+extern crate lazy_static;
+#[macro_use]
+extern crate log;
+#[macro_use]
+extern crate prometheus;
+#[macro_use]
+extern crate serde;
+
+macro_rules! ldb_try {
+    ($x:expr) => {
+        $x.map_err(|e| format_err!("{:?}: {}", e, e))?
+    };
+}
+
+pub mod api;
+#[doc(hidden)]
+pub mod auth;
+pub mod auxpow;
+#[doc(hidden)]
+pub mod bench;
+mod block;
+pub mod borker;
+pub mod cache;
+pub mod codec;
+#[doc(hidden)]
+pub mod dump;
+#[doc(hidden)]
+pub mod error;
+pub mod headers;
+#[doc(hidden)]
+pub mod history;
+pub mod indexer;
+#[doc(hidden)]
+pub mod insight;
+mod key;
+#[doc(hidden)]
+pub mod limit;
+#[doc(hidden)]
+pub mod logger;
+#[doc(hidden)]
+pub mod metrics;
+pub mod network;
+#[doc(hidden)]
+pub mod proxy;
+pub mod query;
+#[doc(hidden)]
+pub mod server;
+pub mod shutdown;
+#[doc(hidden)]
+pub mod snapshot;
+pub mod store;
+mod sync;
+#[cfg(test)]
+mod testing;
+#[doc(hidden)]
+pub mod tls;
+pub mod upstream;
+mod utxo;
+mod verify;
+
+pub use crate::block::Block;
+pub use crate::indexer::{Indexer, SyncConfig};
+pub use crate::query::{Query, Tip, Utxo};
+pub use crate::utxo::{StorageMode, UTXO};
+
+#[doc(hidden)]
+pub use crate::sync::reindex;
+#[doc(hidden)]
+pub use crate::verify::{verify_chain, verify_index};
+
+use std::collections::HashMap;
+
+pub const CONFIRMATIONS: usize = 10;
+
+pub(crate) type Rewind = Vec<HashMap<utxo::UTXOID, (Option<utxo::UTXOData>, Option<Vec<u8>>)>>;
@@ -0,0 +1,188 @@
+use crate::error::HttpError;
+use failure::Error;
+use hyper::StatusCode;
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Instant;
+
+const MAX_IDLE_BUCKETS: usize = 10000;
+
+#[derive(Clone, Deserialize)]
+pub struct Budget {
+    pub rate: f64,
+    pub burst: f64,
+    #[serde(default)]
+    pub concurrency: Option<usize>,
+}
+
+#[derive(Clone, Default, Deserialize)]
+pub struct ScopeConfig {
+    #[serde(default)]
+    pub per_ip: Option<Budget>,
+    #[serde(default)]
+    pub per_key: Option<Budget>,
+}
+
+#[derive(Clone, Default, Deserialize)]
+pub struct LimitConfig {
+    #[serde(default)]
+    pub index: ScopeConfig,
+    #[serde(default)]
+    pub proxy: ScopeConfig,
+}
+
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+    in_flight: usize,
+}
+
+pub struct Limiter {
+    budget: Budget,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+impl Limiter {
+    pub fn new(budget: Budget) -> Result<Self, Error> {
+        if budget.rate.is_nan() || budget.rate <= 0.0 {
+            bail!("limit rate must be positive, got {}", budget.rate);
+        }
+        // a bucket that cannot hold a whole token never admits a request
+        if budget.burst.is_nan() || budget.burst < 1.0 {
+            bail!("limit burst must be at least 1, got {}", budget.burst);
+        }
+        Ok(Limiter {
+            budget,
+            buckets: Mutex::new(HashMap::new()),
+        })
+    }
+
+    // Refills the bucket of `key` and fails if it cannot take another request, without taking it.
+    fn check(
+        &self,
+        buckets: &mut HashMap<String, Bucket>,
+        key: &str,
+        now: Instant,
+    ) -> Result<(), Error> {
+        if buckets.len() > MAX_IDLE_BUCKETS {
+            let budget = &self.budget;
+            buckets.retain(|_, b| {
+                b.in_flight > 0
+                    || b.tokens + now.duration_since(b.last).as_secs_f64() * budget.rate
+                        < budget.burst
+            });
+        }
+        let bucket = buckets.entry(key.to_owned()).or_insert_with(|| Bucket {
+            tokens: self.budget.burst,
+            last: now,
+            in_flight: 0,
+        });
+        bucket.tokens = (bucket.tokens
+            + now.duration_since(bucket.last).as_secs_f64() * self.budget.rate)
+            .min(self.budget.burst);
+        bucket.last = now;
+        if bucket.tokens < 1.0 {
+            let wait = ((1.0 - bucket.tokens) / self.budget.rate).ceil() as u64;
+            return Err(
+                HttpError::new(StatusCode::TOO_MANY_REQUESTS, "rate limit exceeded")
+                    .with_retry_after(wait)
+                    .into(),
+            );
+        }
+        if let Some(concurrency) = self.budget.concurrency {
+            if bucket.in_flight >= concurrency {
+                return Err(HttpError::new(
+                    StatusCode::TOO_MANY_REQUESTS,
+                    "too many concurrent requests",
+                )
+                .with_retry_after(1)
+                .into());
+            }
+        }
+        Ok(())
+    }
+
+    // Takes a request from the bucket of `key`, which `check` has passed under the same lock.
+    fn take(self: &Arc<Self>, buckets: &mut HashMap<String, Bucket>, key: String) -> Permit {
+        if let Some(bucket) = buckets.get_mut(&key) {
+            bucket.tokens -= 1.0;
+            bucket.in_flight += 1;
+        }
+        Permit {
+            limiter: self.clone(),
+            key,
+        }
+    }
+
+    pub fn acquire(self: &Arc<Self>, key: String) -> Result<Permit, Error> {
+        let mut buckets = self.buckets.lock();
+        self.check(&mut buckets, &key, Instant::now())?;
+        Ok(self.take(&mut buckets, key))
+    }
+}
+
+pub struct Permit {
+    limiter: Arc<Limiter>,
+    key: String,
+}
+impl Drop for Permit {
+    fn drop(&mut self) {
+        if let Some(bucket) = self.limiter.buckets.lock().get_mut(&self.key) {
+            bucket.in_flight -= 1;
+        }
+    }
+}
+
+pub struct Scope {
+    per_ip: Option<Arc<Limiter>>,
+    per_key: Option<Arc<Limiter>>,
+}
+impl Scope {
+    fn new(conf: ScopeConfig) -> Result<Self, Error> {
+        Ok(Scope {
+            per_ip: match conf.per_ip {
+                Some(budget) => Some(Arc::new(Limiter::new(budget)?)),
+                None => None,
+            },
+            per_key: match conf.per_key {
+                Some(budget) => Some(Arc::new(Limiter::new(budget)?)),
+                None => None,
+            },
+        })
+    }
+
+    // Both limits are checked before either is debited, so a request rejected by one does not
+    // use up the other.
+    pub fn acquire(&self, ip: std::net::IpAddr, key: Option<&str>) -> Result<Vec<Permit>, Error> {
+        let mut limiters = Vec::with_capacity(2);
+        if let Some(ref limiter) = self.per_ip {
+            limiters.push((limiter, ip.to_string()));
+        }
+        if let (Some(ref limiter), Some(key)) = (&self.per_key, key) {
+            limiters.push((limiter, key.to_owned()));
+        }
+        let mut locked: Vec<_> = limiters.iter().map(|&(l, _)| l.buckets.lock()).collect();
+        let now = Instant::now();
+        for ((limiter, key), buckets) in limiters.iter().zip(locked.iter_mut()) {
+            limiter.check(buckets, key, now)?;
+        }
+        Ok(limiters
+            .into_iter()
+            .zip(locked.iter_mut())
+            .map(|((limiter, key), buckets)| limiter.take(buckets, key))
+            .collect())
+    }
+}
+
+pub struct Limits {
+    pub index: Scope,
+    pub proxy: Scope,
+}
+impl Limits {
+    pub fn new(conf: LimitConfig) -> Result<Self, Error> {
+        Ok(Limits {
+            index: Scope::new(conf.index)?,
+            proxy: Scope::new(conf.proxy)?,
+        })
+    }
+}
@@ -0,0 +1,156 @@
+use failure::Error;
+use log::{LevelFilter, Log, Metadata, Record};
+use serde_json::{Map, Value};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::io::Write;
+use std::time::SystemTime;
+
+#[derive(Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+impl Default for LogFormat {
+    fn default() -> Self {
+        LogFormat::Text
+    }
+}
+
+fn default_level() -> LevelFilter {
+    LevelFilter::Info
+}
+
+#[derive(Clone, Deserialize)]
+pub struct LogConfig {
+    #[serde(default = "default_level")]
+    pub level: LevelFilter,
+    #[serde(default)]
+    pub format: LogFormat,
+    #[serde(default)]
+    pub modules: HashMap<String, LevelFilter>,
+}
+impl Default for LogConfig {
+    fn default() -> Self {
+        LogConfig {
+            level: default_level(),
+            format: LogFormat::default(),
+            modules: HashMap::new(),
+        }
+    }
+}
+
+thread_local! {
+    static CONTEXT: RefCell<Vec<(&'static str, String)>> = RefCell::new(Vec::new());
+}
+
+// Fields attached to every record logged on this thread until the guard is dropped.
+pub struct Context(usize);
+impl Drop for Context {
+    fn drop(&mut self) {
+        CONTEXT.with(|c| c.borrow_mut().truncate(self.0));
+    }
+}
+
+pub fn context(fields: &[(&'static str, &dyn Display)]) -> Context {
+    CONTEXT.with(|c| {
+        let mut c = c.borrow_mut();
+        let len = c.len();
+        c.extend(fields.iter().map(|(k, v)| (*k, v.to_string())));
+        Context(len)
+    })
+}
+
+struct Logger {
+    level: LevelFilter,
+    // longest module path first, so that the most specific filter wins
+    modules: Vec<(String, LevelFilter)>,
+    format: LogFormat,
+}
+
+impl Logger {
+    fn level_for(&self, target: &str) -> LevelFilter {
+        self.modules
+            .iter()
+            .find(|(m, _)| {
+                target == m
+                    || (target.starts_with(m.as_str()) && target[m.len()..].starts_with("::"))
+            })
+            .map(|(_, l)| *l)
+            .unwrap_or(self.level)
+    }
+
+    fn format(&self, record: &Record) -> String {
+        let ts = humantime::format_rfc3339_millis(SystemTime::now());
+        CONTEXT.with(|c| match self.format {
+            LogFormat::Text => {
+                let mut line = format!(
+                    "{} {:<5} {} {}",
+                    ts,
+                    record.level(),
+                    record.target(),
+                    record.args()
+                );
+                for (k, v) in c.borrow().iter() {
+                    line.push_str(&format!(" {}={}", k, v));
+                }
+                line
+            }
+            LogFormat::Json => {
+                let mut obj = Map::new();
+                obj.insert("ts".to_owned(), Value::String(ts.to_string()));
+                obj.insert(
+                    "level".to_owned(),
+                    Value::String(record.level().to_string()),
+                );
+                obj.insert(
+                    "target".to_owned(),
+                    Value::String(record.target().to_owned()),
+                );
+                obj.insert("msg".to_owned(), Value::String(record.args().to_string()));
+                for (k, v) in c.borrow().iter() {
+                    obj.insert((*k).to_owned(), Value::String(v.clone()));
+                }
+                Value::Object(obj).to_string()
+            }
+        })
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level_for(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            let line = self.format(record);
+            let _ = writeln!(std::io::stderr().lock(), "{}", line);
+        }
+    }
+
+    fn flush(&self) {
+        let _ = std::io::stderr().flush();
+    }
+}
+
+pub fn init(conf: &LogConfig) -> Result<(), Error> {
+    let mut modules: Vec<_> = conf.modules.iter().map(|(m, l)| (m.clone(), *l)).collect();
+    modules.sort_by_key(|(m, _)| std::cmp::Reverse(m.len()));
+    let max = modules
+        .iter()
+        .map(|(_, l)| *l)
+        .chain(std::iter::once(conf.level))
+        .max()
+        .unwrap_or(conf.level);
+    log::set_boxed_logger(Box::new(Logger {
+        level: conf.level,
+        modules,
+        format: conf.format,
+    }))
+    .map_err(|e| format_err!("{}", e))?;
+    log::set_max_level(max);
+    Ok(())
+}
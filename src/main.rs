@@ -1,452 +1,298 @@
-#![feature(duration_float)]
-
 #[macro_use]
 extern crate failure;
 #[macro_use]
+extern crate log;
+#[macro_use]
 extern crate serde;
 
-macro_rules! ldb_try {
-    ($x:expr) => {
-        $x.map_err(|e| format_err!("{:?}: {}", e, e))?
-    };
-}
-
-mod api;
-mod block;
-mod key;
-mod utxo;
-
-use crate::block::Block;
-use crate::key::Bytes;
 use failure::Error;
-use futures::future::*;
+use futures::future::Either;
 use hyper::rt::Future;
-use hyper::rt::Stream;
-use hyper::service::service_fn;
-use hyper::{Body, Request, Response, Server};
-use leveldb::database::Database;
-use leveldb::kv::KV;
-use leveldb::options::*;
-use std::collections::HashMap;
-use std::str::FromStr;
+use hyper::server::conn::AddrStream;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Server};
+use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::sync::Arc;
-use throttled_bitcoin_rpc::BitcoinRpcClient;
+use structopt::StructOpt;
+use superdoge::store::Store;
+use superdoge::{
+    auth, bench, cache, codec, dump, history, limit, logger, network, proxy, server, shutdown,
+    snapshot, store, tls, upstream, Indexer, StorageMode, SyncConfig,
+};
 
-pub const P2PKH: u8 = 30;
-pub const P2SH: u8 = 22;
-pub const CONFIRMATIONS: usize = 10;
+#[derive(StructOpt)]
+#[structopt(name = "superdoge")]
+struct Opt {
+    #[structopt(long = "config", default_value = "config.yaml", parse(from_os_str))]
+    config: PathBuf,
+    /// Directory holding utxos.db
+    #[structopt(long = "data-dir", default_value = ".", parse(from_os_str))]
+    data_dir: PathBuf,
+    #[structopt(long = "bind", default_value = "0.0.0.0:11021")]
+    bind: SocketAddr,
+    #[structopt(subcommand)]
+    cmd: Option<Command>,
+}
 
-pub type Rewind = Vec<HashMap<utxo::UTXOID, (Option<utxo::UTXOData>, Option<Vec<u8>>)>>;
+#[derive(StructOpt)]
+enum Command {
+    /// Index the chain and serve the API and proxy (default)
+    #[structopt(name = "serve")]
+    Serve,
+    /// Index the chain without serving anything
+    #[structopt(name = "sync-only")]
+    SyncOnly,
+    /// Serve the API and proxy from an existing database without indexing
+    #[structopt(name = "api-only")]
+    ApiOnly,
+    /// Undo indexed blocks down to the given height, so that indexing resumes from it
+    #[structopt(name = "reindex")]
+    Reindex {
+        #[structopt(long = "from")]
+        from: u32,
+    },
+    /// Check the index for internal consistency and the indexed chain against the node
+    #[structopt(name = "verify")]
+    Verify {
+        /// Rebuild inconsistent address slots and unspent counters
+        #[structopt(long = "repair")]
+        repair: bool,
+    },
+    /// Write the utxo set and address index at the current tip to a snapshot file
+    #[structopt(name = "export")]
+    Export {
+        #[structopt(long = "out", parse(from_os_str))]
+        out: PathBuf,
+    },
+    /// Load a snapshot into an empty database, so that indexing resumes after its height
+    #[structopt(name = "import")]
+    Import {
+        #[structopt(long = "snapshot", parse(from_os_str))]
+        snapshot: PathBuf,
+    },
+    /// Compare the size and get_utxos latency of copies of the database in each record encoding
+    /// and block compression
+    #[structopt(name = "bench")]
+    Bench {
+        /// Directory for the copies, which must not exist and is removed afterwards
+        #[structopt(long = "dir", parse(from_os_str))]
+        dir: PathBuf,
+        #[structopt(long = "samples", default_value = "1000")]
+        samples: usize,
+    },
+    /// Print the database as hex encoded key value pairs
+    #[structopt(name = "dump")]
+    Dump {
+        #[structopt(long = "prefix")]
+        prefix: Option<u8>,
+    },
+}
 
-#[derive(Deserialize)]
-struct Config {
-    node_uri: String,
-    node_user: Option<String>,
-    node_password: Option<String>,
+fn default_health_check_interval() -> u64 {
+    10
 }
 
-#[derive(Deserialize)]
-struct RpcMethod {
-    method: String,
+fn default_drain_timeout() -> u64 {
+    30
 }
 
 #[derive(Deserialize)]
-#[serde(untagged)]
-enum RpcQuery {
-    Single(RpcMethod),
-    Multi(Vec<RpcMethod>),
+struct Config {
+    #[serde(default)]
+    network: network::Network,
+    #[serde(default)]
+    mode: StorageMode,
+    #[serde(default)]
+    validate_headers: bool,
+    #[serde(default)]
+    encoding: codec::Encoding,
+    #[serde(default)]
+    compression: codec::Compression,
+    #[serde(default)]
+    node_uri: Option<String>,
+    #[serde(default)]
+    node_user: Option<String>,
+    #[serde(default)]
+    node_password: Option<String>,
+    #[serde(default)]
+    nodes: Vec<upstream::NodeConfig>,
+    #[serde(default = "default_health_check_interval")]
+    health_check_interval: u64,
+    #[serde(default)]
+    rpc_policy: proxy::RpcPolicy,
+    #[serde(default)]
+    auth: auth::AuthConfig,
+    #[serde(default)]
+    limits: limit::LimitConfig,
+    #[serde(default)]
+    rpc_cache: cache::CacheConfig,
+    #[serde(default)]
+    tls: Option<tls::TlsConfig>,
+    #[serde(default)]
+    log: logger::LogConfig,
+    #[serde(default = "default_drain_timeout")]
+    drain_timeout: u64,
 }
 
 fn main() -> Result<(), Error> {
-    let conf: Config = serde_yaml::from_reader(std::fs::File::open("config.yaml")?)?;
-    let client_arc = BitcoinRpcClient::new(
-        conf.node_uri.clone(),
-        conf.node_user.clone(),
-        conf.node_password.clone(),
-        0,
-        0,
-        0,
-    );
-    let path = std::path::Path::new("utxos.db");
-    let mut options = Options::new();
-    options.create_if_missing = true;
-    options.max_open_files = Some(500);
-    let db_arc: Arc<Database<Bytes>> = Arc::new(ldb_try!(Database::open(path, options)));
-    let (send, recv) = crossbeam_channel::bounded(50);
-    let db = db_arc.clone();
-    let client = client_arc.clone();
-    let b = std::thread::spawn(move || {
-        let mut idx = match db.get(ReadOptions::new(), Bytes::from(&[0_u8])) {
-            Ok(Some(b)) => {
-                let mut buf = [0_u8; 4];
-                if b.len() == 4 {
-                    buf.clone_from_slice(&b);
-                } else {
-                    panic!("invalid size for u32");
-                }
-                u32::from_ne_bytes(buf)
-            }
-            Ok(None) => 1,
-            Err(e) => {
-                panic!("{}", e);
-            }
-        };
-        'main: loop {
-            let count = match client.getblockcount() {
-                Ok(a) => a,
-                Err(e) => {
-                    eprintln!("{}: {}", line!(), e);
-                    continue 'main;
-                }
-            };
-            use throttled_bitcoin_rpc::BatchRequest;
-            let mut batcher = client.batcher::<String>();
-            let idxs = idx..std::cmp::min(idx + 20, count + 1);
-            let time = std::time::Instant::now();
-            for i in idxs.clone() {
-                match batcher.getblockhash(i) {
-                    Ok(_) => (),
-                    Err(e) => {
-                        eprintln!("{}: {}", line!(), e);
-                        continue 'main;
-                    }
-                }
-            }
-            let hashes = match batcher.send() {
-                Ok(a) => a,
-                Err(e) => {
-                    eprintln!("{}: {}", line!(), e);
-                    continue 'main;
-                }
-            };
-            for hash in hashes.iter() {
-                match batcher.getblock(hash, false) {
-                    Ok(_) => (),
-                    Err(e) => {
-                        eprintln!("{}: {}", line!(), e);
-                        continue 'main;
-                    }
-                }
-            }
-            let blocks = match batcher.send() {
-                Ok(a) => a,
-                Err(e) => {
-                    eprintln!("{}: {}", line!(), e);
-                    continue 'main;
-                }
-            };
-            if idx <= count {
-                println!("fetched in {:?}", time.elapsed());
-            }
-            for ((i, hash), block) in idxs
-                .into_iter()
-                .zip(hashes.into_iter())
-                .into_iter()
-                .zip(blocks.into_iter())
-            {
-                let hash = match hex::decode(hash) {
-                    Ok(a) => a,
-                    Err(e) => {
-                        eprintln!("{}: {}", line!(), e);
-                        continue 'main;
-                    }
-                };
-                let block = match hex::decode(block) {
-                    Ok(a) => a,
-                    Err(e) => {
-                        eprintln!("{}: {}", line!(), e);
-                        continue 'main;
-                    }
-                };
-                match send.send((i, hash, block)) {
-                    Ok(_) => (),
-                    Err(e) => {
-                        eprintln!("{}: {}", line!(), e);
-                        continue 'main;
-                    }
-                };
-                idx = i + 1;
-            }
-            if idx > count {
-                std::thread::sleep(std::time::Duration::from_secs(1));
-            }
+    let opt = Opt::from_args();
+    let conf: Config = serde_yaml::from_reader(std::fs::File::open(&opt.config)?)?;
+    logger::init(&conf.log)?;
+    let shutdown = shutdown::Shutdown::install()?;
+    let cmd = opt.cmd.unwrap_or(Command::Serve);
+    let writable = match cmd {
+        Command::Serve | Command::SyncOnly | Command::Import { .. } => true,
+        _ => false,
+    };
+    let db_arc: Arc<dyn Store> = Arc::new(store::LevelDb::open(
+        &opt.data_dir.join("utxos.db"),
+        writable,
+        conf.compression,
+    )?);
+    if let Command::Dump { prefix } = cmd {
+        return dump::dump(&db_arc, prefix, &mut std::io::stdout().lock());
+    }
+    conf.network.check_db(&db_arc, writable)?;
+    if writable {
+        codec::Encoding::init(&db_arc, conf.encoding)?;
+        history::init(&*db_arc)?;
+    }
+    if let Command::Serve | Command::SyncOnly | Command::Reindex { .. } = cmd {
+        snapshot::check_complete(&*db_arc)?;
+    }
+    if let Command::Bench { ref dir, samples } = cmd {
+        return bench::run(&db_arc, conf.network, dir, samples);
+    }
+    if let Command::Export { ref out } = cmd {
+        let f = std::fs::File::create(out).map_err(|e| format_err!("{}: {}", out.display(), e))?;
+        snapshot::export(&db_arc, std::io::BufWriter::new(f))?;
+        return Ok(());
+    }
+
+    let mut nodes = conf.nodes.clone();
+    if let Some(ref uri) = conf.node_uri {
+        nodes.insert(
+            0,
+            upstream::NodeConfig {
+                uri: uri.clone(),
+                user: conf.node_user.clone(),
+                password: conf.node_password.clone(),
+            },
+        );
+    }
+    let pool_arc = Arc::new(upstream::Pool::new(conf.network, nodes)?);
+    pool_arc.check();
+    match cmd {
+        Command::Reindex { from } => {
+            superdoge::reindex(&pool_arc, &db_arc, from, conf.mode, &shutdown)?;
+            return Ok(());
         }
-    });
-    let db = db_arc.clone();
-    let client = client_arc.clone();
-    let t = std::thread::spawn(move || {
-        let mut time = std::time::Instant::now();
-        let mut tpb = std::time::Duration::from_secs(0);
-        let mut periods = 0;
-        loop {
-            let mut rewind: Rewind = std::fs::File::open("rewind.cbor")
-                .map_err(Error::from)
-                .and_then(|f| serde_cbor::from_reader(f).map_err(Error::from))
-                .unwrap_or_else(|_| {
-                    std::iter::repeat_with(|| HashMap::new())
-                        .take(CONFIRMATIONS)
-                        .collect()
-                });
-            match try_process_block(&client, &recv, &db, &mut rewind) {
-                Ok(Some(i)) => {
-                    println!("scanned {}", i);
-                    if i % 100 == 0 {
-                        let inst_tpb = time.elapsed() / 100;
-                        println!("{:.2} blocks/second", 1.0 / inst_tpb.as_secs_f64());
-                        tpb = ((tpb * periods) + inst_tpb) / (periods + 1);
-                        periods += 1;
-                        time = std::time::Instant::now();
-                    }
-                    if i % 500 == 0 {
-                        match client.getblockcount().ok() {
-                            Some(count) if i < count as u32 => {
-                                println!("average {} blocks/second", 1.0 / tpb.as_secs_f64());
-                                let remaining = tpb * (count - i);
-                                println!("{} remaining", humantime::format_duration(remaining));
-                            }
-                            _ => (),
-                        }
-                    }
-                }
-                Ok(None) => {
-                    match std::fs::File::create("rewind.cbor")
-                        .map_err(Error::from)
-                        .and_then(|mut f| {
-                            serde_cbor::to_writer(&mut f, &rewind).map_err(Error::from)
-                        }) {
-                        Ok(_) => (),
-                        Err(e) => eprintln!("ERROR SAVING REWIND: {}", e),
-                    }
-                }
-                Err(e) => eprintln!("ERROR: {}{}", e, e.backtrace()),
-            };
+        Command::Import { ref snapshot } => {
+            snapshot::import(&pool_arc, &db_arc, || {
+                Ok(std::io::BufReader::new(
+                    std::fs::File::open(snapshot)
+                        .map_err(|e| format_err!("{}: {}", snapshot.display(), e))?,
+                ))
+            })?;
+            return Ok(());
         }
-    });
-
-    let addr_http = ([0, 0, 0, 0], 11021).into();
-    // let addr_https = ([0, 0, 0, 0], 11022).into();
-
-    let db = db_arc.clone();
-    let rpc_arc = Arc::new(hyper::Uri::from_str(&conf.node_uri)?);
-    let make_service = move || {
-        let (uname, pwd) = (conf.node_user.clone(), conf.node_password.clone());
-        let client = hyper::Client::new();
-        let rpc = (&*rpc_arc).clone();
-        let db = db.clone();
-        service_fn(move |req: Request<Body>| {
-            match req.uri().path_and_query() {
-                Some(p_and_q) if p_and_q.path() == "/" => {
-                    let client = client.clone();
-                    let mut r = Request::builder();
-                    r.uri(rpc.clone());
-                    r.method(req.method());
-                    r.headers_mut().map(|h| *h = req.headers().clone());
-                    match uname {
-                        Some(ref u) => {
-                            r.header(
-                                "Authorization",
-                                format!(
-                                    "Basic {}",
-                                    base64::encode(&format!(
-                                        "{}:{}",
-                                        u,
-                                        pwd.as_ref().unwrap_or(&"".to_owned())
-                                    ))
-                                ),
-                            );
-                        }
-                        _ => (),
-                    }
-                    let bstream = req.into_body();
-                    let body = bstream.concat2().map_err(Error::from);
-                    let m_b = body.and_then(|b| {
-                        serde_json::from_slice(&b)
-                            .map(|m: RpcQuery| (m, b))
-                            .map_err(Error::from)
-                    });
-                    let b = m_b.and_then(|(m, b)| match m {
-                        RpcQuery::Single(ref m) if m.method == "stop" => {
-                            bail!("unauthorized method")
-                        }
-                        RpcQuery::Multi(ref m)
-                            if m.iter().filter(|m| m.method == "stop").count() > 0 =>
-                        {
-                            bail!("unauthorized method")
-                        }
-                        _ => Ok(b),
-                    });
-                    let req = b.and_then(move |b| r.body(Body::from(b)).map_err(Error::from));
-                    Either::B(Either::A(
-                        req.and_then(move |r| client.request(r).map_err(Error::from)),
-                    ))
-                }
-                None => {
-                    let client = client.clone();
-                    let mut r = Request::builder();
-                    r.uri(rpc.clone());
-                    r.method(req.method());
-                    r.headers_mut().map(|h| *h = req.headers().clone());
-                    match uname {
-                        Some(ref u) => {
-                            r.header(
-                                "Authorization",
-                                base64::encode(&format!(
-                                    "{}:{}",
-                                    u,
-                                    pwd.as_ref().unwrap_or(&"".to_owned())
-                                )),
-                            );
-                        }
-                        _ => (),
-                    }
-                    let bstream = req.into_body();
-                    let body = bstream.concat2().map_err(Error::from);
-                    let m_b = body.and_then(|b| {
-                        serde_json::from_slice(&b)
-                            .map(|m: RpcQuery| (m, b))
-                            .map_err(Error::from)
-                    });
-                    let b = m_b.and_then(|(m, b)| match m {
-                        RpcQuery::Single(ref m) if m.method == "stop" => {
-                            bail!("unauthorized method")
-                        }
-                        RpcQuery::Multi(ref m)
-                            if m.iter().filter(|m| m.method == "stop").count() > 0 =>
-                        {
-                            bail!("unauthorized method")
-                        }
-                        _ => Ok(b),
-                    });
-                    let req = b.and_then(move |b| r.body(Body::from(b)).map_err(Error::from));
-                    Either::B(Either::B(
-                        req.and_then(move |r| client.request(r).map_err(Error::from)),
-                    ))
-                    // TODO: Don't duplicate
-                }
-                Some(path_and_query) => Either::A(match req.headers().get("Content-Type") {
-                    Some(a) if a.as_bytes().starts_with(b"application/json") => result(
-                        api::handle_request(&db, path_and_query)
-                            .and_then(|res| res.to_json())
-                            .map(|res| Response::new(Body::from(res))),
-                    ),
-                    Some(a) if a.as_bytes().starts_with(b"application/cbor") => result(
-                        api::handle_request(&db, path_and_query)
-                            .and_then(|res| serde_cbor::to_vec(&res).map_err(Error::from))
-                            .map(|res| Response::new(Body::from(res))),
-                    ),
-                    Some(a) if a.as_bytes().starts_with(b"application/x-yaml") => result(
-                        api::handle_request(&db, path_and_query)
-                            .and_then(|res| serde_yaml::to_string(&res).map_err(Error::from))
-                            .map(|res| Response::new(Body::from(res))),
-                    ),
-                    Some(a) if a.as_bytes().starts_with(b"application/octet-stream") => result(
-                        api::handle_request(&db, path_and_query)
-                            .map(|res| res.to_bytes())
-                            .map(|res| Response::new(Body::from(res))),
-                    ),
-                    _ => err(format_err!("invalid content type")),
-                }),
+        Command::Verify { repair } => {
+            let index_problems = superdoge::verify_index(&db_arc, repair)?;
+            let chain_problems = superdoge::verify_chain(&pool_arc, &db_arc)?;
+            if chain_problems > 0 || (index_problems > 0 && !repair) {
+                bail!("{} problems found", index_problems + chain_problems);
             }
-            .or_else(|e| {
-                eprintln!("{}\n{}", e, e.backtrace());
-                result(Response::builder().status(500).body(Body::from(format!(
-                    "{}{}",
-                    e,
-                    e.backtrace()
-                ))))
-            })
-        })
-    };
-
-    let server_http = Server::bind(&addr_http).serve(make_service);
-    // let server_https = Server::bind(&addr_https).serve(make_service);
-
-    hyper::rt::run(server_http.map_err(|e| {
-        eprintln!("server error: {}", e);
-    }));
-
-    t.join().unwrap();
-    b.join().unwrap();
-
-    Ok(())
-}
+            return Ok(());
+        }
+        _ => (),
+    }
+    let h = pool_arc.clone().spawn_health_check(
+        std::time::Duration::from_secs(conf.health_check_interval),
+        shutdown.clone(),
+    );
+    let rpc_cache = Arc::new(cache::RpcCache::new(conf.rpc_cache.clone()));
 
-fn try_process_block(
-    client: &BitcoinRpcClient,
-    recv: &crossbeam_channel::Receiver<(u32, Vec<u8>, Vec<u8>)>,
-    db: &Database<Bytes>,
-    rewind: &mut Rewind,
-) -> Result<Option<u32>, Error> {
-    let (idx, bhash, block_raw) = match recv.try_recv() {
-        Ok(a) => a,
-        Err(crossbeam_channel::TryRecvError::Empty) => return Ok(None),
-        Err(e) => return Err(Error::from(e)),
+    let indexer = match cmd {
+        Command::Serve | Command::SyncOnly => Some(Indexer::start(
+            db_arc.clone(),
+            pool_arc.clone(),
+            rpc_cache.clone(),
+            SyncConfig {
+                mode: conf.mode,
+                validate_headers: conf.validate_headers,
+            },
+            shutdown.clone(),
+        )),
+        _ => None,
     };
-    let mut bkey = Vec::with_capacity(9);
-    bkey.push(3_u8);
-    bkey.extend(&idx.to_ne_bytes());
-    ldb_try!(db.put(WriteOptions::new(), Bytes::from(&bkey), &bhash));
-    let block = Block::from_slice(&block_raw)?;
-    handle_rewind(
-        client,
-        db,
-        &block.header.prev_blockhash[..],
-        idx - 1,
-        rewind,
-    )?;
-    block.exec(db, idx, rewind)?;
-    ldb_try!(db.put(
-        WriteOptions::new(),
-        Bytes::from(&[0_u8]),
-        &(idx + 1).to_ne_bytes()
-    ));
 
-    Ok(Some(idx))
-}
+    if let Command::Serve | Command::ApiOnly = cmd {
+        let ctx = Arc::new(server::Context {
+            db: db_arc.clone(),
+            network: conf.network,
+            proxy: proxy::Proxy::new(
+                pool_arc.clone(),
+                Arc::new(conf.rpc_policy),
+                rpc_cache.clone(),
+                db_arc.clone(),
+            ),
+            auth: auth::Auth::new(conf.auth),
+            limits: limit::Limits::new(conf.limits)?,
+        });
+        let server_https = match conf.tls {
+            Some(tls_conf) => {
+                let acceptor = tls::Acceptor::new(tls_conf)?;
+                acceptor.clone().spawn_reload();
+                Some(tls::serve(acceptor, ctx.clone(), &shutdown)?)
+            }
+            None => None,
+        };
+        let make_service = make_service_fn(move |conn: &AddrStream| {
+            let ctx = ctx.clone();
+            let peer = server::Peer {
+                addr: conn.remote_addr(),
+                certificate: None,
+            };
+            service_fn(move |req: Request<Body>| server::handle(&ctx, peer.clone(), req))
+        });
 
-fn handle_rewind(
-    client: &BitcoinRpcClient,
-    db: &Database<Bytes>,
-    hash: &[u8],
-    idx: u32,
-    rewind: &mut Rewind,
-) -> Result<(), Error> {
-    if idx <= 1 {
-        return Ok(());
+        let server_http = Server::bind(&opt.bind)
+            .serve(make_service)
+            .with_graceful_shutdown(shutdown.wait())
+            .map_err(|e| {
+                error!("server error: {}", e);
+            });
+        let server_https = match server_https {
+            Some(server_https) => Either::A(server_https),
+            None => Either::B(futures::future::ok(())),
+        };
+        let drain_timeout = std::time::Duration::from_secs(conf.drain_timeout);
+        let deadline = shutdown.deadline(drain_timeout).map(|_| {
+            warn!("requests still in flight after drain timeout, closing them");
+        });
+
+        // the runtime is shut down explicitly rather than run to idle, so that connections
+        // left open past the deadline do not keep the process alive
+        let mut rt = tokio::runtime::Runtime::new()?;
+        let _ = rt.block_on(
+            server_http
+                .join(server_https)
+                .map(|_| ())
+                .select(deadline)
+                .map(|_| ())
+                .map_err(|_| ()),
+        );
+        let _ = rt.shutdown_now().wait();
+        info!("server stopped");
+        // the server also stops on its own when it fails, and the indexer must then stop with it
+        shutdown.request();
     }
 
-    let mut cow = std::borrow::Cow::Borrowed(hash);
-    let hash = cow.to_mut();
-    hash.reverse();
-    let mut block_key = Vec::with_capacity(5);
-    block_key.push(3_u8);
-    block_key.extend(&idx.to_ne_bytes());
-    let old_hash = ldb_try!(db.get(ReadOptions::new(), Bytes::from(&block_key)))
-        .ok_or(format_err!("missing block_hash"))?;
-    if old_hash.as_slice() == AsRef::<[u8]>::as_ref(hash) {
-        return Ok(());
+    if let Some(indexer) = indexer {
+        indexer.join();
     }
-    println!("reverting {}", hex::encode(old_hash.as_slice()));
-    let block_raw = match client.getblock(&hex::encode(old_hash), false)? {
-        throttled_bitcoin_rpc::reply::getblock::False(a) => hex::decode(a)?,
-        _ => bail!("unexpected response"),
-    };
-    let block = Block::from_slice(&block_raw)?;
-    block.undo(client, db, idx, rewind)?;
-    let block_raw = match client.getblock(&hex::encode(&hash), false)? {
-        throttled_bitcoin_rpc::reply::getblock::False(a) => hex::decode(a)?,
-        _ => bail!("unexpected response"),
-    };
-    let block = Block::from_slice(&block_raw)?;
-    handle_rewind(
-        client,
-        db,
-        &block.header.prev_blockhash[..],
-        idx - 1,
-        rewind,
-    )?;
-    block.exec(db, idx, rewind)?;
-    ldb_try!(db.put(WriteOptions::new(), Bytes::from(&block_key), hash));
+    h.join().unwrap();
 
     Ok(())
 }
@@ -0,0 +1,78 @@
+use failure::Error;
+use prometheus::{
+    Encoder, Histogram, HistogramVec, IntCounter, IntCounterVec, IntGauge, TextEncoder,
+};
+
+lazy_static! {
+    pub static ref INDEXED_HEIGHT: IntGauge = register_int_gauge!(
+        "superdoge_indexed_height",
+        "Height of the last indexed block"
+    )
+    .unwrap();
+    pub static ref NODE_HEIGHT: IntGauge = register_int_gauge!(
+        "superdoge_node_height",
+        "Height of the active upstream node"
+    )
+    .unwrap();
+    pub static ref LAG: IntGauge = register_int_gauge!(
+        "superdoge_lag_blocks",
+        "Blocks between the active upstream node and the index"
+    )
+    .unwrap();
+    pub static ref BLOCKS_PROCESSED: IntCounter = register_int_counter!(
+        "superdoge_blocks_processed_total",
+        "Blocks indexed since startup"
+    )
+    .unwrap();
+    pub static ref TXS_PROCESSED: IntCounter = register_int_counter!(
+        "superdoge_transactions_processed_total",
+        "Transactions indexed since startup"
+    )
+    .unwrap();
+    pub static ref FETCH_DURATION: Histogram = register_histogram!(
+        "superdoge_fetch_duration_seconds",
+        "Time to fetch a batch of blocks from the upstream node"
+    )
+    .unwrap();
+    pub static ref EXEC_DURATION: Histogram = register_histogram!(
+        "superdoge_exec_duration_seconds",
+        "Time to apply a block to the index"
+    )
+    .unwrap();
+    pub static ref REORG_DEPTH: Histogram = register_histogram!(
+        "superdoge_reorg_depth_blocks",
+        "Blocks reverted per reorg",
+        vec![1.0, 2.0, 3.0, 5.0, 8.0, crate::CONFIRMATIONS as f64]
+    )
+    .unwrap();
+    pub static ref REWIND_ENTRIES: IntGauge = register_int_gauge!(
+        "superdoge_rewind_entries",
+        "Spent outputs held for undoing recent blocks"
+    )
+    .unwrap();
+    pub static ref API_REQUESTS: IntCounterVec = register_int_counter_vec!(
+        "superdoge_api_requests_total",
+        "API and proxy requests",
+        &["endpoint", "encoding", "status"]
+    )
+    .unwrap();
+    pub static ref API_DURATION: HistogramVec = register_histogram_vec!(
+        "superdoge_api_request_duration_seconds",
+        "Time to answer API and proxy requests",
+        &["endpoint", "encoding"]
+    )
+    .unwrap();
+    pub static ref UPSTREAM_ERRORS: IntCounterVec = register_int_counter_vec!(
+        "superdoge_upstream_errors_total",
+        "Failed requests to upstream nodes",
+        &["node"]
+    )
+    .unwrap();
+}
+
+pub fn encode() -> Result<Vec<u8>, Error> {
+    LAG.set(NODE_HEIGHT.get() - INDEXED_HEIGHT.get());
+    let mut buf = Vec::new();
+    TextEncoder::new().encode(&prometheus::gather(), &mut buf)?;
+    Ok(buf)
+}
@@ -0,0 +1,124 @@
+use crate::store::Store;
+use failure::Error;
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Network {
+    Mainnet,
+    Testnet,
+    Regtest,
+}
+impl Default for Network {
+    fn default() -> Self {
+        Network::Mainnet
+    }
+}
+
+impl Network {
+    pub fn p2pkh(self) -> u8 {
+        match self {
+            Network::Mainnet => 30,
+            Network::Testnet => 113,
+            Network::Regtest => 111,
+        }
+    }
+
+    pub fn p2sh(self) -> u8 {
+        match self {
+            Network::Mainnet => 22,
+            Network::Testnet => 196,
+            Network::Regtest => 196,
+        }
+    }
+
+    pub fn auxpow_height(self) -> u32 {
+        match self {
+            Network::Mainnet => 371337,
+            Network::Testnet => 158100,
+            Network::Regtest => 20,
+        }
+    }
+
+    // Whether blocks must carry our chain id, and auxpow parents must not.
+    pub fn strict_chain_id(self) -> bool {
+        match self {
+            Network::Mainnet => true,
+            Network::Testnet => false,
+            Network::Regtest => true,
+        }
+    }
+
+    pub fn digishield_height(self) -> u32 {
+        match self {
+            Network::Mainnet => 145000,
+            Network::Testnet => 145000,
+            Network::Regtest => 10,
+        }
+    }
+
+    // The easiest target a block may have, in compact form.
+    pub fn pow_limit(self) -> u32 {
+        match self {
+            Network::Regtest => 0x207f_ffff,
+            _ => 0x1e0f_ffff,
+        }
+    }
+
+    // Regtest blocks keep the genesis difficulty.
+    pub fn retargets(self) -> bool {
+        self != Network::Regtest
+    }
+
+    // Whether testnet's minimum difficulty blocks are allowed after `height`. They were allowed
+    // between retargets until DigiShield, and after any slow block from 157500 on.
+    pub fn min_difficulty_blocks(self, height: u32) -> bool {
+        self == Network::Testnet && (height < self.digishield_height() || height > 157_500)
+    }
+
+    pub fn genesis_time(self) -> u32 {
+        match self {
+            Network::Mainnet => 1386325540,
+            Network::Testnet => 1391503289,
+            Network::Regtest => 1296688602,
+        }
+    }
+
+    pub fn coinbase_maturity(self, height: u32) -> u32 {
+        match self {
+            Network::Regtest => 60,
+            _ if height < self.digishield_height() => 30,
+            _ => 240,
+        }
+    }
+
+    pub fn genesis_hash(self) -> &'static str {
+        match self {
+            Network::Mainnet => "1a91e3dace36e2be3bf030a65679fe821aa1d6ef92e7c9902eb318182c355691",
+            Network::Testnet => "bb0a78264637406b6360aad926284d544d7049f45189db5664f3c4d07350559e",
+            Network::Regtest => "3d2160a3b5dc4a9d62e7e66a295f70313ac808440ef7400d6c0772171ce973a5",
+        }
+    }
+
+    // The genesis hash is stored under [0, 1] the first time a database is opened for writing.
+    // Databases from before it was recorded were all built on mainnet.
+    pub fn check_db(self, db: &dyn Store, writable: bool) -> Result<(), Error> {
+        let genesis = hex::decode(self.genesis_hash())?;
+        match db.get(&[0_u8, 1])? {
+            Some(ref g) if g == &genesis => Ok(()),
+            Some(g) => bail!(
+                "database was built for a different network (genesis {})",
+                hex::encode(g)
+            ),
+            None => {
+                let indexed = db.get(&[0_u8])?.is_some();
+                if indexed && self != Network::Mainnet {
+                    bail!("database was built for mainnet");
+                }
+                if writable {
+                    db.put(&[0_u8, 1], &genesis)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
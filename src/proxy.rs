@@ -0,0 +1,367 @@
+use crate::auth::API_KEY_HEADER;
+use crate::cache::RpcCache;
+use crate::insight;
+use crate::network::Network;
+use crate::store::{ReadStore, Store};
+use crate::upstream::{Node, Pool};
+use failure::Error;
+use futures::future::{self, Future};
+use futures::Stream;
+use hyper::client::HttpConnector;
+use hyper::header::{HeaderMap, AUTHORIZATION, CONTENT_LENGTH, CONTENT_TYPE, HOST};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+pub const RPC_PARSE_ERROR: i64 = -32700;
+pub const RPC_METHOD_NOT_ALLOWED: i64 = -32601;
+
+pub type ProxyFuture = Box<dyn Future<Item = Response<Body>, Error = Error> + Send>;
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct RpcCall {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub jsonrpc: Option<String>,
+    pub method: String,
+    #[serde(default)]
+    pub params: Value,
+    #[serde(default)]
+    pub id: Value,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct RpcResponse {
+    pub result: Value,
+    pub error: Option<RpcError>,
+    pub id: Value,
+}
+impl RpcResponse {
+    pub fn error(id: Value, code: i64, message: String) -> Self {
+        RpcResponse {
+            result: Value::Null,
+            error: Some(RpcError { code, message }),
+            id,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum RpcQuery {
+    Single(RpcCall),
+    Multi(Vec<RpcCall>),
+}
+
+fn default_deny() -> HashSet<String> {
+    std::iter::once("stop".to_owned()).collect()
+}
+
+#[derive(Clone, Deserialize)]
+pub struct MethodPolicy {
+    #[serde(default)]
+    pub allow: Option<HashSet<String>>,
+    #[serde(default = "default_deny")]
+    pub deny: HashSet<String>,
+}
+impl Default for MethodPolicy {
+    fn default() -> Self {
+        MethodPolicy {
+            allow: None,
+            deny: default_deny(),
+        }
+    }
+}
+impl MethodPolicy {
+    pub fn permits(&self, method: &str) -> bool {
+        !self.deny.contains(method)
+            && self
+                .allow
+                .as_ref()
+                .map(|allow| allow.contains(method))
+                .unwrap_or(true)
+    }
+}
+
+// A key listed under `keys` uses its own policy instead of the default one.
+#[derive(Clone, Default, Deserialize)]
+pub struct RpcPolicy {
+    #[serde(flatten)]
+    pub default: MethodPolicy,
+    #[serde(default)]
+    pub keys: HashMap<String, MethodPolicy>,
+}
+impl RpcPolicy {
+    pub fn permits(&self, key: Option<&str>, method: &str) -> bool {
+        key.and_then(|k| self.keys.get(k))
+            .unwrap_or(&self.default)
+            .permits(method)
+    }
+
+    fn check(&self, key: Option<&str>, call: RpcCall) -> Result<RpcCall, RpcResponse> {
+        if self.permits(key, &call.method) {
+            Ok(call)
+        } else {
+            Err(RpcResponse::error(
+                call.id,
+                RPC_METHOD_NOT_ALLOWED,
+                format!("method not allowed: {}", call.method),
+            ))
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Upstream {
+    client: hyper::Client<HttpConnector>,
+    pool: Arc<Pool>,
+    method: Method,
+    headers: HeaderMap,
+}
+impl Upstream {
+    fn send_to(
+        &self,
+        node: &Node,
+        body: Vec<u8>,
+    ) -> impl Future<Item = Response<Body>, Error = Error> {
+        let mut r = Request::builder();
+        r.uri(node.uri.clone());
+        r.method(self.method.clone());
+        r.headers_mut().map(|h| {
+            *h = self.headers.clone();
+            if let Some(ref auth) = node.auth {
+                h.insert(AUTHORIZATION, auth.clone());
+            }
+        });
+        let client = self.client.clone();
+        future::result(r.body(Body::from(body)).map_err(Error::from))
+            .and_then(move |r| client.request(r).map_err(Error::from))
+    }
+
+    // Fails over to the next elected node once if the active node cannot be reached.
+    fn send(&self, body: Vec<u8>) -> impl Future<Item = Response<Body>, Error = Error> {
+        let node = self.pool.active();
+        let upstream = self.clone();
+        self.send_to(&node, body.clone()).or_else(move |e| {
+            warn!("upstream node {} failed: {}", node.id, e);
+            upstream.pool.mark_failed(&node);
+            let next = upstream.pool.active();
+            if next.id == node.id {
+                future::Either::A(future::err(e))
+            } else {
+                future::Either::B(upstream.send_to(&next, body))
+            }
+        })
+    }
+}
+
+enum Dispatch {
+    Local(RpcResponse),
+    Forward(RpcCall, Option<String>),
+}
+
+fn dispatch(
+    policy: &RpcPolicy,
+    cache: &RpcCache,
+    db: &dyn ReadStore,
+    network: Network,
+    key: Option<&str>,
+    call: RpcCall,
+) -> Dispatch {
+    let call = match policy.check(key, call) {
+        Ok(a) => a,
+        Err(res) => return Dispatch::Local(res),
+    };
+    if let Some(res) = insight::handle(db, network, &call) {
+        return Dispatch::Local(res);
+    }
+    match RpcCache::key(db, &call) {
+        Some(cache_key) => match cache.get(&cache_key) {
+            Some(result) => Dispatch::Local(RpcResponse {
+                result,
+                error: None,
+                id: call.id,
+            }),
+            None => Dispatch::Forward(call, Some(cache_key)),
+        },
+        None => Dispatch::Forward(call, None),
+    }
+}
+
+pub struct Proxy {
+    client: hyper::Client<HttpConnector>,
+    pool: Arc<Pool>,
+    policy: Arc<RpcPolicy>,
+    cache: Arc<RpcCache>,
+    db: Arc<dyn Store>,
+}
+
+impl Proxy {
+    pub fn new(
+        pool: Arc<Pool>,
+        policy: Arc<RpcPolicy>,
+        cache: Arc<RpcCache>,
+        db: Arc<dyn Store>,
+    ) -> Self {
+        Proxy {
+            client: hyper::Client::new(),
+            pool,
+            policy,
+            cache,
+            db,
+        }
+    }
+
+    pub fn cache(&self) -> &RpcCache {
+        &self.cache
+    }
+
+    pub fn handle(&self, key: Option<&str>, req: Request<Body>) -> ProxyFuture {
+        let mut headers = req.headers().clone();
+        headers.remove(AUTHORIZATION);
+        headers.remove(API_KEY_HEADER);
+        headers.remove(CONTENT_LENGTH);
+        headers.remove(HOST);
+        let upstream = Upstream {
+            client: self.client.clone(),
+            pool: self.pool.clone(),
+            method: req.method().clone(),
+            headers,
+        };
+        let key = key.map(|k| k.to_owned());
+        let policy = self.policy.clone();
+        let cache = self.cache.clone();
+        let db = self.db.clone();
+        let network = self.pool.network();
+        Box::new(req.into_body().concat2().map_err(Error::from).and_then(
+            move |body| -> ProxyFuture {
+                let key = key.as_ref().map(|k| k.as_str());
+                // calls answered from the index all read the same snapshot
+                let snapshot = db.snapshot();
+                match serde_json::from_slice(&body) {
+                    Ok(RpcQuery::Single(call)) => {
+                        match dispatch(&policy, &cache, &*snapshot, network, key, call) {
+                            Dispatch::Local(res) => {
+                                Box::new(future::result(reply(status_of(&res), &res)))
+                            }
+                            Dispatch::Forward(_, None) => Box::new(upstream.send(body.to_vec())),
+                            Dispatch::Forward(_, Some(cache_key)) => {
+                                Box::new(upstream.send(body.to_vec()).and_then(move |res| {
+                                    let (parts, body) = res.into_parts();
+                                    body.concat2().map_err(Error::from).map(move |b| {
+                                        if let Ok(RpcResponse {
+                                            result,
+                                            error: None,
+                                            ..
+                                        }) = serde_json::from_slice(&b)
+                                        {
+                                            cache.insert(cache_key, result);
+                                        }
+                                        Response::from_parts(parts, Body::from(b))
+                                    })
+                                }))
+                            }
+                        }
+                    }
+                    Ok(RpcQuery::Multi(calls)) => {
+                        let calls: Vec<_> = calls
+                            .into_iter()
+                            .map(|c| dispatch(&policy, &cache, &*snapshot, network, key, c))
+                            .collect();
+                        forward_batch(upstream, cache, body.to_vec(), calls)
+                    }
+                    Err(e) => Box::new(future::result(reply(
+                        StatusCode::OK,
+                        &RpcResponse::error(Value::Null, RPC_PARSE_ERROR, format!("{}", e)),
+                    ))),
+                }
+            },
+        ))
+    }
+}
+
+fn forward_batch(
+    upstream: Upstream,
+    cache: Arc<RpcCache>,
+    body: Vec<u8>,
+    calls: Vec<Dispatch>,
+) -> ProxyFuture {
+    let passthrough = calls.iter().all(|c| match c {
+        Dispatch::Forward(_, None) => true,
+        _ => false,
+    });
+    if passthrough {
+        return Box::new(upstream.send(body));
+    }
+    let forwarded: Vec<&RpcCall> = calls
+        .iter()
+        .filter_map(|c| match c {
+            Dispatch::Forward(call, _) => Some(call),
+            Dispatch::Local(_) => None,
+        })
+        .collect();
+    if forwarded.is_empty() {
+        let res: Vec<RpcResponse> = calls
+            .into_iter()
+            .filter_map(|c| match c {
+                Dispatch::Local(res) => Some(res),
+                Dispatch::Forward(..) => None,
+            })
+            .collect();
+        return Box::new(future::result(reply(StatusCode::OK, &res)));
+    }
+    let body = match serde_json::to_vec(&forwarded) {
+        Ok(a) => a,
+        Err(e) => return Box::new(future::err(Error::from(e))),
+    };
+    Box::new(
+        upstream
+            .send(body)
+            .and_then(|res| res.into_body().concat2().map_err(Error::from))
+            .and_then(move |b| {
+                let mut upstream_res = serde_json::from_slice::<Vec<RpcResponse>>(&b)?.into_iter();
+                let res = calls
+                    .into_iter()
+                    .map(|c| match c {
+                        Dispatch::Forward(_, cache_key) => {
+                            let res = upstream_res
+                                .next()
+                                .ok_or(format_err!("upstream batch response too short"))?;
+                            match (cache_key, &res.error) {
+                                (Some(cache_key), None) => {
+                                    cache.insert(cache_key, res.result.clone())
+                                }
+                                _ => (),
+                            }
+                            Ok(res)
+                        }
+                        Dispatch::Local(res) => Ok(res),
+                    })
+                    .collect::<Result<Vec<_>, Error>>()?;
+                reply(StatusCode::OK, &res)
+            }),
+    )
+}
+
+fn status_of(res: &RpcResponse) -> StatusCode {
+    match res.error {
+        Some(RpcError {
+            code: RPC_METHOD_NOT_ALLOWED,
+            ..
+        }) => StatusCode::FORBIDDEN,
+        _ => StatusCode::OK,
+    }
+}
+
+fn reply<T: serde::Serialize>(status: StatusCode, res: &T) -> Result<Response<Body>, Error> {
+    Ok(Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(serde_json::to_vec(res)?))?)
+}
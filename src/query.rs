@@ -0,0 +1,126 @@
+use crate::api::{self, Balance};
+use crate::codec::Encoding;
+use crate::history;
+use crate::network::Network;
+use crate::store::{ReadStore, Store};
+use bitcoin::consensus::encode::deserialize;
+use bitcoin::Transaction;
+use bitcoin_hashes::{sha256d, Hash};
+use failure::Error;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Tip {
+    pub height: u32,
+    pub hash: sha256d::Hash,
+}
+
+#[derive(Clone, Debug)]
+pub struct Utxo {
+    pub txid: sha256d::Hash,
+    pub vout: u32,
+    pub value: u64,
+    pub tx: Transaction,
+    // a coinbase output that cannot be spent in the next block
+    pub immature: bool,
+}
+
+// The index keeps hashes as dogecoind shows them, the reverse of their internal byte order.
+pub(crate) fn from_display(buf: &[u8]) -> Result<sha256d::Hash, Error> {
+    let mut buf = buf.to_vec();
+    buf.reverse();
+    Ok(sha256d::Hash::from_slice(&buf)?)
+}
+
+fn to_display(hash: &sha256d::Hash) -> [u8; 32] {
+    let mut buf = [0_u8; 32];
+    buf.clone_from_slice(&hash[..]);
+    buf.reverse();
+    buf
+}
+
+// Reads a single snapshot of the index, so that every result reflects the same tip however
+// long the query is kept.
+pub struct Query<'a> {
+    db: Box<dyn ReadStore + 'a>,
+    network: Network,
+}
+
+impl<'a> Query<'a> {
+    pub fn new(db: &'a dyn Store, network: Network) -> Self {
+        Query {
+            db: db.snapshot(),
+            network,
+        }
+    }
+
+    pub fn tip(&self) -> Result<Option<Tip>, Error> {
+        match api::tip(&*self.db)? {
+            Some((height, hash)) => Ok(Some(Tip {
+                height,
+                hash: from_display(&hash)?,
+            })),
+            None => Ok(None),
+        }
+    }
+
+    pub fn balance(&self, address: &str) -> Result<Balance, Error> {
+        api::get_balance(&*self.db, self.network, address)
+    }
+
+    // Utxos of `address` in index order, until they add up to `amount` and there are more than
+    // `min_count` of them (20 by default). Immature coinbase outputs are skipped unless
+    // `include_immature` is set.
+    pub fn utxos(
+        &self,
+        address: &str,
+        amount: u64,
+        min_count: Option<usize>,
+        include_immature: bool,
+    ) -> Result<Vec<Utxo>, Error> {
+        api::get_utxos(
+            &*self.db,
+            self.network,
+            address,
+            amount,
+            min_count,
+            include_immature,
+        )?
+        .into_iter()
+        .map(|u| {
+            Ok(Utxo {
+                txid: from_display(&u.txid)?,
+                vout: u.vout,
+                value: u.value,
+                tx: deserialize(&u.raw)?,
+                immature: u.immature,
+            })
+        })
+        .collect()
+    }
+
+    // Transactions that paid to or spent from `address` between heights `start` and `end`
+    // inclusive, by height.
+    pub fn txids(
+        &self,
+        address: &str,
+        start: u32,
+        end: u32,
+    ) -> Result<Vec<(u32, sha256d::Hash)>, Error> {
+        let address = api::parse_address(self.network, address)?;
+        history::txids(&*self.db, &address, start, end)?
+            .into_iter()
+            .map(|(height, txid)| Ok((height, from_display(&txid)?)))
+            .collect()
+    }
+
+    // A transaction with unspent outputs, or any indexed transaction in archive mode.
+    pub fn transaction(&self, txid: &sha256d::Hash) -> Result<Option<Transaction>, Error> {
+        let mut tx_key = Vec::with_capacity(33);
+        tx_key.push(4_u8);
+        tx_key.extend(&to_display(txid));
+        match self.db.get(&tx_key)? {
+            Some(stored) => Ok(Some(Encoding::load(&*self.db)?.decode_tx(&stored)?)),
+            None => Ok(None),
+        }
+    }
+}
@@ -0,0 +1,194 @@
+use crate::api;
+use crate::auth::{Auth, Identity, Permission};
+use crate::error::HttpError;
+use crate::limit::Limits;
+use crate::logger;
+use crate::metrics;
+use crate::network::Network;
+use crate::proxy::Proxy;
+use crate::store::{ReadStore, Store};
+use failure::Error;
+use futures::future::{err, result, Future};
+use hyper::header::{HeaderMap, HeaderValue, CONTENT_TYPE, RETRY_AFTER, WWW_AUTHENTICATE};
+use hyper::{Body, Request, Response, StatusCode};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Instant;
+
+pub type ResponseFuture = Box<dyn Future<Item = Response<Body>, Error = Error> + Send>;
+
+#[derive(Clone)]
+pub struct Peer {
+    pub addr: SocketAddr,
+    pub certificate: Option<String>,
+}
+
+pub struct Context {
+    pub db: Arc<dyn Store>,
+    pub network: Network,
+    pub proxy: Proxy,
+    pub auth: Auth,
+    pub limits: Limits,
+}
+
+fn endpoint(path: &str) -> &'static str {
+    match path {
+        "/" => "/",
+        "/balance" => "/balance",
+        "/utxos" => "/utxos",
+        "/headers" => "/headers",
+        "/borker/posts" => "/borker/posts",
+        "/borker/replies" => "/borker/replies",
+        "/borker/timeline" => "/borker/timeline",
+        p if p.starts_with("/block/") => "/block",
+        "/stats/cache" => "/stats/cache",
+        "/metrics" => "/metrics",
+        _ => "other",
+    }
+}
+
+fn encoding(headers: &HeaderMap) -> &'static str {
+    match headers.get(CONTENT_TYPE).map(|a| a.as_bytes()) {
+        Some(a) if a.starts_with(b"application/json") => "json",
+        Some(a) if a.starts_with(b"application/cbor") => "cbor",
+        Some(a) if a.starts_with(b"application/x-yaml") => "yaml",
+        Some(a) if a.starts_with(b"application/octet-stream") => "octet-stream",
+        _ => "other",
+    }
+}
+
+pub fn handle(
+    ctx: &Context,
+    peer: Peer,
+    req: Request<Body>,
+) -> impl Future<Item = Response<Body>, Error = http::Error> {
+    let start = Instant::now();
+    let labels = (endpoint(req.uri().path()), encoding(req.headers()));
+    let method = req.method().clone();
+    let path = req.uri().path().to_owned();
+    let addr = peer.addr;
+    let ident = ctx
+        .auth
+        .authenticate(req.headers(), peer.certificate.as_ref().map(|c| c.as_str()));
+    let client = match ident {
+        Ok(ref ident) => ident.id.clone(),
+        Err(_) => "-".to_owned(),
+    };
+    let res: ResponseFuture = match ident {
+        Ok(ident) => route(ctx, peer, ident, req),
+        Err(e) => Box::new(err(e)),
+    };
+    res.or_else(|e| {
+        let mut res = Response::builder();
+        match e.downcast_ref::<HttpError>() {
+            Some(e) => {
+                debug!("{}: {}", e.status, e);
+                res.status(e.status);
+                if e.status == StatusCode::UNAUTHORIZED {
+                    res.header(WWW_AUTHENTICATE, "Basic realm=\"superdoge\"");
+                }
+                if let Some(retry_after) = e.retry_after {
+                    res.header(RETRY_AFTER, retry_after.to_string());
+                }
+                res.body(Body::from(e.message.clone()))
+            }
+            None => {
+                error!("{}\n{}", e, e.backtrace());
+                res.status(500)
+                    .body(Body::from(format!("{}{}", e, e.backtrace())))
+            }
+        }
+    })
+    .map(move |res| {
+        let latency = start.elapsed();
+        metrics::API_DURATION
+            .with_label_values(&[labels.0, labels.1])
+            .observe(latency.as_secs_f64());
+        metrics::API_REQUESTS
+            .with_label_values(&[labels.0, labels.1, res.status().as_str()])
+            .inc();
+        let _ctx = logger::context(&[
+            ("peer", &addr),
+            ("client", &client),
+            ("method", &method),
+            ("path", &path),
+            ("status", &res.status().as_u16()),
+            ("latency_ms", &latency.as_millis()),
+        ]);
+        info!("{} {} {}", method, path, res.status());
+        res
+    })
+}
+
+fn route(ctx: &Context, peer: Peer, ident: Arc<Identity>, req: Request<Body>) -> ResponseFuture {
+    let key = if ident.authenticated {
+        Some(ident.id.as_str())
+    } else {
+        None
+    };
+    match req.uri().path_and_query() {
+        Some(path_and_query) if path_and_query.path() == "/stats/cache" => Box::new(result(
+            ident
+                .require(Permission::Admin)
+                .and_then(|_| Ok(serde_json::to_vec(&ctx.proxy.cache().stats())?))
+                .map(|res| Response::new(Body::from(res))),
+        )),
+        Some(path_and_query) if path_and_query.path() == "/metrics" => Box::new(result(
+            ident
+                .require(Permission::Metrics)
+                .and_then(|_| metrics::encode())
+                .map(|res| Response::new(Body::from(res))),
+        )),
+        Some(path_and_query) if path_and_query.path() != "/" => Box::new(result(
+            ident
+                .require(Permission::Index)
+                .and_then(|_| ctx.limits.index.acquire(peer.addr.ip(), key))
+                .and_then(|_permits| {
+                    // one snapshot per request, so that it sees a single indexed height
+                    let snapshot = ctx.db.snapshot();
+                    index(&*snapshot, ctx.network, req.headers(), path_and_query)
+                }),
+        )),
+        _ => match ident
+            .require(Permission::Proxy)
+            .and_then(|_| ctx.limits.proxy.acquire(peer.addr.ip(), key))
+        {
+            Ok(permits) => Box::new(ctx.proxy.handle(Some(&ident.id), req).then(move |res| {
+                drop(permits);
+                res
+            })),
+            Err(e) => Box::new(err(e)),
+        },
+    }
+}
+
+fn index(
+    db: &dyn ReadStore,
+    network: Network,
+    headers: &HeaderMap,
+    path_and_query: &http::uri::PathAndQuery,
+) -> Result<Response<Body>, Error> {
+    let body = match headers.get(CONTENT_TYPE) {
+        Some(a) if a.as_bytes().starts_with(b"application/json") => {
+            Body::from(api::handle_request(db, network, path_and_query)?.to_json()?)
+        }
+        Some(a) if a.as_bytes().starts_with(b"application/cbor") => Body::from(serde_cbor::to_vec(
+            &api::handle_request(db, network, path_and_query)?,
+        )?),
+        Some(a) if a.as_bytes().starts_with(b"application/x-yaml") => Body::from(
+            serde_yaml::to_string(&api::handle_request(db, network, path_and_query)?)?,
+        ),
+        Some(a) if a.as_bytes().starts_with(b"application/octet-stream") => {
+            Body::from(api::handle_request(db, network, path_and_query)?.to_bytes())
+        }
+        _ => bail!("invalid content type"),
+    };
+    let mut res = Response::new(body);
+    if let Some((height, hash)) = api::tip(db)? {
+        res.headers_mut()
+            .insert("x-tip-height", HeaderValue::from(height));
+        res.headers_mut()
+            .insert("x-tip-hash", HeaderValue::from_str(&hex::encode(hash))?);
+    }
+    Ok(res)
+}
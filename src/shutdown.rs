@@ -0,0 +1,57 @@
+use failure::Error;
+use futures::{Future, Stream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::timer::{Delay, Interval};
+
+// Set by SIGINT or SIGTERM. Threads check it between units of work, so a block is never
+// interrupted half way through `exec`. A default one is only set by `request`.
+#[derive(Clone, Default)]
+pub struct Shutdown {
+    flag: Arc<AtomicBool>,
+}
+
+impl Shutdown {
+    pub fn install() -> Result<Self, Error> {
+        let shutdown = Shutdown::default();
+        signal_hook::flag::register(signal_hook::SIGINT, shutdown.flag.clone())?;
+        signal_hook::flag::register(signal_hook::SIGTERM, shutdown.flag.clone())?;
+        Ok(shutdown)
+    }
+
+    pub fn request(&self) {
+        self.flag.store(true, Ordering::SeqCst);
+    }
+
+    pub fn requested(&self) -> bool {
+        self.flag.load(Ordering::SeqCst)
+    }
+
+    // Sleeps for up to `dur`, waking early if shutdown is requested.
+    pub fn sleep(&self, dur: Duration) {
+        let until = Instant::now() + dur;
+        while !self.requested() {
+            let now = Instant::now();
+            if now >= until {
+                break;
+            }
+            std::thread::sleep(std::cmp::min(until - now, Duration::from_millis(100)));
+        }
+    }
+
+    pub fn wait(&self) -> impl Future<Item = (), Error = ()> + Send {
+        let flag = self.flag.clone();
+        Interval::new_interval(Duration::from_millis(100))
+            .take_while(move |_| Ok(!flag.load(Ordering::SeqCst)))
+            .for_each(|_| Ok(()))
+            .map_err(|e| error!("shutdown timer: {}", e))
+    }
+
+    // Resolves `timeout` after shutdown is requested, to bound how long requests may drain.
+    pub fn deadline(&self, timeout: Duration) -> impl Future<Item = (), Error = ()> + Send {
+        self.wait().and_then(move |_| {
+            Delay::new(Instant::now() + timeout).map_err(|e| error!("shutdown timer: {}", e))
+        })
+    }
+}
@@ -0,0 +1,590 @@
+use crate::block;
+use crate::borker::{self, Entry, Kind, Message};
+use crate::codec::Encoding;
+use crate::headers;
+use crate::history;
+use crate::network::Network;
+use crate::store::{BatchStore, ReadStore, Store};
+use crate::upstream::Pool;
+use crate::utxo::UTXO;
+use bitcoin_hashes::{sha256, Hash, HashEngine};
+use failure::Error;
+use std::io::{Read, Write};
+
+// A snapshot is a header followed by tagged records and a sha256 of everything before it.
+// Integers are big endian, so snapshots can be moved between machines, unlike the database.
+//
+//   header: MAGIC, version u8, genesis [32], height u32, block hash [32], history start u32
+//   TAG_BLOCK: height u32, hash [32]
+//   TAG_TX: txid [32], unspent u32, len u32, raw [len]
+//   TAG_UTXO: address [21], txid [32], vout u32, value u64
+//   TAG_HEADER: height u32, header [80]
+//   TAG_TXIDS: height u32, len u32, txids [len]
+//   TAG_COINBASE: txid [32], height u32
+//   TAG_HISTORY: address [21], height u32, txid [32], received u64
+//   TAG_BORKER: txid [32], author [21], height u32, time u32, type u8, nonce u8,
+//               reference [32] for types that have one, len u32, content [len]
+//   TAG_END: sha256 [32]
+//
+// Undo records are not carried over, so blocks up to the snapshot height cannot be reverted.
+const MAGIC: &[u8; 6] = b"SDSNAP";
+const VERSION: u8 = 2;
+const TAG_END: u8 = 0;
+const TAG_BLOCK: u8 = 1;
+const TAG_TX: u8 = 2;
+const TAG_UTXO: u8 = 3;
+const TAG_HEADER: u8 = 4;
+const TAG_TXIDS: u8 = 5;
+const TAG_COINBASE: u8 = 6;
+const TAG_HISTORY: u8 = 7;
+const TAG_BORKER: u8 = 8;
+
+// Set while an import is written, in chunks of IMPORT_CHUNK records. The next height [0] is
+// only written with the last chunk, which also clears the marker.
+const IMPORT_KEY: [u8; 2] = [0, 4];
+const IMPORT_CHUNK: usize = 100_000;
+
+// The largest variable length record, the size limit of a block, so that a corrupt length is
+// caught before it is allocated.
+const MAX_RECORD_SIZE: u32 = 1_000_000;
+
+struct HashWriter<W: Write> {
+    inner: W,
+    engine: sha256::HashEngine,
+}
+impl<W: Write> Write for HashWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.engine.input(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+struct HashReader<R: Read> {
+    inner: R,
+    engine: sha256::HashEngine,
+}
+impl<R: Read> Read for HashReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.engine.input(&buf[..n]);
+        Ok(n)
+    }
+}
+
+fn read_array<R: Read>(r: &mut R, buf: &mut [u8]) -> Result<(), Error> {
+    r.read_exact(buf)
+        .map_err(|e| format_err!("truncated snapshot: {}", e))
+}
+
+fn read_u8<R: Read>(r: &mut R) -> Result<u8, Error> {
+    let mut buf = [0_u8; 1];
+    read_array(r, &mut buf)?;
+    Ok(buf[0])
+}
+
+fn read_u32<R: Read>(r: &mut R) -> Result<u32, Error> {
+    let mut buf = [0_u8; 4];
+    read_array(r, &mut buf)?;
+    Ok(u32::from_be_bytes(buf))
+}
+
+fn read_u64<R: Read>(r: &mut R) -> Result<u64, Error> {
+    let mut buf = [0_u8; 8];
+    read_array(r, &mut buf)?;
+    Ok(u64::from_be_bytes(buf))
+}
+
+fn read_len<R: Read>(r: &mut R) -> Result<usize, Error> {
+    let len = read_u32(r)?;
+    if len > MAX_RECORD_SIZE {
+        bail!("invalid record size {}", len);
+    }
+    Ok(len as usize)
+}
+
+fn ne_u32(b: &[u8]) -> Result<u32, Error> {
+    if b.len() != 4 {
+        bail!("invalid size for u32");
+    }
+    let mut buf = [0_u8; 4];
+    buf.clone_from_slice(b);
+    Ok(u32::from_ne_bytes(buf))
+}
+
+// Writes the index at the current tip: the utxo set, the address index, block hashes, headers
+// and txids, coinbase heights, address history and Borker messages. Returns the height of the
+// snapshot. Records are written in the legacy encoding whatever the database uses.
+pub fn export<W: Write>(db: &dyn Store, out: W) -> Result<u32, Error> {
+    let encoding = Encoding::load(db.as_read())?;
+    let next = match db.get(&[0_u8])? {
+        Some(b) => ne_u32(&b)?,
+        None => bail!("database is empty"),
+    };
+    let height = next - 1;
+    let genesis = db
+        .get(&[0_u8, 1])?
+        .ok_or(format_err!("missing genesis hash"))?;
+    let mut block_key = Vec::with_capacity(5);
+    block_key.push(3_u8);
+    block_key.extend(&height.to_ne_bytes());
+    let tip = db
+        .get(&block_key)?
+        .ok_or(format_err!("missing block_hash"))?;
+
+    let mut w = HashWriter {
+        inner: out,
+        engine: sha256::Hash::engine(),
+    };
+    w.write_all(MAGIC)?;
+    w.write_all(&[VERSION])?;
+    w.write_all(&genesis)?;
+    w.write_all(&height.to_be_bytes())?;
+    w.write_all(&tip)?;
+    w.write_all(&history::start(db.as_read())?.to_be_bytes())?;
+
+    for (key, value) in db.iter(&[3_u8]) {
+        if key.get(0) != Some(&3) {
+            break;
+        }
+        w.write_all(&[TAG_BLOCK])?;
+        w.write_all(&ne_u32(&key[1..])?.to_be_bytes())?;
+        w.write_all(&value)?;
+    }
+    let mut txs = 0;
+    for (key, value) in db.iter(&[5_u8]) {
+        if key.get(0) != Some(&5) {
+            break;
+        }
+        let unspent = ne_u32(&value)?;
+        if unspent == 0 {
+            continue;
+        }
+        let mut tx_key = key.to_vec();
+        tx_key[0] = 4;
+        let raw = db.get(&tx_key)?.ok_or(format_err!(
+            "raw transaction {} missing",
+            hex::encode(&key[1..])
+        ))?;
+        let raw = encoding.raw_tx(&raw)?;
+        w.write_all(&[TAG_TX])?;
+        w.write_all(&key[1..33])?;
+        w.write_all(&unspent.to_be_bytes())?;
+        w.write_all(&(raw.len() as u32).to_be_bytes())?;
+        w.write_all(&raw)?;
+        txs += 1;
+    }
+    let mut utxos = 0;
+    for (key, value) in db.iter(&[1_u8]) {
+        if key.get(0) != Some(&1) {
+            break;
+        }
+        if key.len() != 26 {
+            continue;
+        }
+        let (txid, vout, value) = encoding
+            .decode_slot(&value)
+            .map_err(|e| format_err!("invalid address slot {}: {}", hex::encode(&*key), e))?;
+        w.write_all(&[TAG_UTXO])?;
+        w.write_all(&key[1..22])?;
+        w.write_all(&txid)?;
+        w.write_all(&vout.to_be_bytes())?;
+        w.write_all(&value.to_be_bytes())?;
+        utxos += 1;
+    }
+    for (key, value) in db.iter(&[8_u8]) {
+        if key.get(0) != Some(&8) {
+            break;
+        }
+        w.write_all(&[TAG_HEADER])?;
+        w.write_all(&ne_u32(&key[1..])?.to_be_bytes())?;
+        w.write_all(&value)?;
+    }
+    for (key, value) in db.iter(&[10_u8]) {
+        if key.get(0) != Some(&10) {
+            break;
+        }
+        w.write_all(&[TAG_TXIDS])?;
+        w.write_all(&ne_u32(&key[1..])?.to_be_bytes())?;
+        w.write_all(&(value.len() as u32).to_be_bytes())?;
+        w.write_all(&value)?;
+    }
+    for (key, value) in db.iter(&[11_u8]) {
+        if key.get(0) != Some(&11) {
+            break;
+        }
+        w.write_all(&[TAG_COINBASE])?;
+        w.write_all(&key[1..])?;
+        w.write_all(&ne_u32(&value)?.to_be_bytes())?;
+    }
+    for (key, value) in db.iter(&[6_u8]) {
+        if key.get(0) != Some(&6) {
+            break;
+        }
+        // address [21], height [4] big endian, txid [32]
+        w.write_all(&[TAG_HISTORY])?;
+        w.write_all(&key[1..])?;
+        w.write_all(&history::amount(&value).to_be_bytes())?;
+    }
+    for (key, _) in db.iter(&[12_u8]) {
+        if key.get(0) != Some(&12) {
+            break;
+        }
+        let entry = borker::get(db.as_read(), &key[1..])?.ok_or(format_err!(
+            "borker record {} missing",
+            hex::encode(&key[1..])
+        ))?;
+        w.write_all(&[TAG_BORKER])?;
+        w.write_all(&entry.txid)?;
+        w.write_all(&entry.author)?;
+        w.write_all(&entry.height.to_be_bytes())?;
+        w.write_all(&entry.time.to_be_bytes())?;
+        w.write_all(&[entry.message.kind as u8, entry.message.nonce])?;
+        if let Some(ref reference) = entry.message.reference {
+            w.write_all(reference)?;
+        }
+        w.write_all(&(entry.message.content.len() as u32).to_be_bytes())?;
+        w.write_all(&entry.message.content)?;
+    }
+    w.write_all(&[TAG_END])?;
+    let checksum = sha256::Hash::from_engine(w.engine);
+    w.inner.write_all(&checksum[..])?;
+    w.inner.flush()?;
+    info!(
+        "exported {} utxos and {} transactions at height {}",
+        utxos, txs, height
+    );
+    Ok(height)
+}
+
+struct Header {
+    height: u32,
+    tip: [u8; 32],
+    history_start: u32,
+}
+
+fn read_header<R: Read>(r: &mut R, network: Network) -> Result<Header, Error> {
+    let mut magic = [0_u8; 6];
+    read_array(r, &mut magic)?;
+    if magic != *MAGIC {
+        bail!("not a snapshot");
+    }
+    let version = read_u8(r)?;
+    if version != VERSION {
+        bail!(
+            "unsupported snapshot version {}, export it again with this version",
+            version
+        );
+    }
+    let mut genesis = [0_u8; 32];
+    read_array(r, &mut genesis)?;
+    if hex::encode(genesis) != network.genesis_hash() {
+        bail!("snapshot is not for {:?}", network);
+    }
+    let height = read_u32(r)?;
+    let mut tip = [0_u8; 32];
+    read_array(r, &mut tip)?;
+    let history_start = read_u32(r)?;
+    Ok(Header {
+        height,
+        tip,
+        history_start,
+    })
+}
+
+// Reads through the snapshot to its checksum, without writing anything.
+fn check<R: Read>(input: R, network: Network) -> Result<Header, Error> {
+    let mut r = HashReader {
+        inner: input,
+        engine: sha256::Hash::engine(),
+    };
+    let header = read_header(&mut r, network)?;
+    let mut buf = Vec::new();
+    loop {
+        match read_u8(&mut r)? {
+            TAG_END => break,
+            TAG_BLOCK => buf.resize(36, 0),
+            TAG_TX => {
+                buf.resize(36, 0);
+                read_array(&mut r, &mut buf)?;
+                let len = read_len(&mut r)?;
+                buf.resize(len, 0);
+            }
+            TAG_UTXO => buf.resize(65, 0),
+            TAG_HEADER => buf.resize(84, 0),
+            TAG_TXIDS => {
+                buf.resize(4, 0);
+                read_array(&mut r, &mut buf)?;
+                let len = read_len(&mut r)?;
+                buf.resize(len, 0);
+            }
+            TAG_COINBASE => buf.resize(36, 0),
+            TAG_HISTORY => buf.resize(65, 0),
+            TAG_BORKER => {
+                buf.resize(63, 0);
+                read_array(&mut r, &mut buf)?;
+                let kind = Kind::from_u8(buf[61]).ok_or(format_err!("invalid borker record"))?;
+                if kind.references() {
+                    buf.resize(32, 0);
+                    read_array(&mut r, &mut buf)?;
+                }
+                let len = read_len(&mut r)?;
+                buf.resize(len, 0);
+            }
+            tag => bail!("invalid record type {}", tag),
+        }
+        read_array(&mut r, &mut buf)?;
+    }
+    let computed = sha256::Hash::from_engine(r.engine);
+    let mut checksum = [0_u8; 32];
+    read_array(&mut r.inner, &mut checksum)?;
+    if computed[..] != checksum[..] {
+        bail!("snapshot checksum mismatch");
+    }
+    Ok(header)
+}
+
+// Fails if an import into the database was interrupted, leaving it partly written.
+pub fn check_complete(db: &dyn ReadStore) -> Result<(), Error> {
+    if db.get(&IMPORT_KEY)?.is_some() {
+        bail!("an import into this database was interrupted, delete it and import again");
+    }
+    Ok(())
+}
+
+// Loads a snapshot into an empty database, after checking its checksum and that the node
+// agrees with its block hash, so that indexing resumes from the block after it.
+pub fn import<R: Read, F: Fn() -> Result<R, Error>>(
+    pool: &Pool,
+    db: &dyn Store,
+    open: F,
+) -> Result<u32, Error> {
+    check_complete(db.as_read())?;
+    if db.get(&[0_u8])?.is_some() {
+        bail!("database is not empty");
+    }
+    let network = pool.network();
+    let encoding = Encoding::load(db.as_read())?;
+    let header = check(open()?, network)?;
+    let node_hash = pool.active().client.getblockhash(header.height)?;
+    if node_hash != hex::encode(header.tip) {
+        bail!(
+            "node has {} at {}, snapshot has {}",
+            node_hash,
+            header.height,
+            hex::encode(header.tip)
+        );
+    }
+
+    db.put(&IMPORT_KEY, &[])?;
+    let base = db;
+    let mut batch = BatchStore::new(base);
+    let mut r = open()?;
+    read_header(&mut r, network)?;
+    let mut utxos = 0;
+    let mut records = 0;
+    loop {
+        records += 1;
+        if records % IMPORT_CHUNK == 0 {
+            std::mem::replace(&mut batch, BatchStore::new(base)).commit()?;
+        }
+        let db: &dyn Store = &batch;
+        match read_u8(&mut r)? {
+            TAG_END => break,
+            TAG_BLOCK => {
+                let height = read_u32(&mut r)?;
+                let mut hash = [0_u8; 32];
+                read_array(&mut r, &mut hash)?;
+                let mut block_key = Vec::with_capacity(5);
+                block_key.push(3_u8);
+                block_key.extend(&height.to_ne_bytes());
+                db.put(&block_key, &hash)?;
+                let mut height_key = vec![9_u8];
+                height_key.extend(&hash);
+                db.put(&height_key, &height.to_ne_bytes())?;
+            }
+            TAG_TX => {
+                let mut tx_key = vec![5_u8; 33];
+                read_array(&mut r, &mut tx_key[1..])?;
+                let unspent = read_u32(&mut r)?;
+                let mut raw = vec![0_u8; read_len(&mut r)?];
+                read_array(&mut r, &mut raw)?;
+                db.put(&tx_key, &unspent.to_ne_bytes())?;
+                let tx: bitcoin::Transaction = bitcoin::consensus::encode::deserialize(&raw)?;
+                tx_key[0] = 4;
+                db.put(&tx_key, &encoding.encode_tx(&tx))?;
+            }
+            TAG_UTXO => {
+                let mut address = [0_u8; 21];
+                read_array(&mut r, &mut address)?;
+                let mut txid = [0_u8; 32];
+                read_array(&mut r, &mut txid)?;
+                let vout = read_u32(&mut r)?;
+                let value = read_u64(&mut r)?;
+                UTXO::new(address, &txid, vout, value).add(db, encoding, None)?;
+                utxos += 1;
+            }
+            TAG_HEADER => {
+                let height = read_u32(&mut r)?;
+                let mut header = [0_u8; 80];
+                read_array(&mut r, &mut header)?;
+                headers::index(
+                    db,
+                    height,
+                    &bitcoin::consensus::encode::deserialize(&header)?,
+                )?;
+            }
+            TAG_TXIDS => {
+                let height = read_u32(&mut r)?;
+                let mut txids = vec![0_u8; read_len(&mut r)?];
+                read_array(&mut r, &mut txids)?;
+                db.put(&block::txids_key(height), &txids)?;
+            }
+            TAG_COINBASE => {
+                let mut txid = [0_u8; 32];
+                read_array(&mut r, &mut txid)?;
+                let height = read_u32(&mut r)?;
+                db.put(&block::coinbase_key(&txid), &height.to_ne_bytes())?;
+            }
+            TAG_HISTORY => {
+                let mut address = [0_u8; 21];
+                read_array(&mut r, &mut address)?;
+                let height = read_u32(&mut r)?;
+                let mut txid = [0_u8; 32];
+                read_array(&mut r, &mut txid)?;
+                let received = read_u64(&mut r)?;
+                history::add(db, &address, height, &txid, received)?;
+            }
+            TAG_BORKER => {
+                let mut txid = [0_u8; 32];
+                read_array(&mut r, &mut txid)?;
+                let mut author = [0_u8; 21];
+                read_array(&mut r, &mut author)?;
+                let height = read_u32(&mut r)?;
+                let time = read_u32(&mut r)?;
+                let kind =
+                    Kind::from_u8(read_u8(&mut r)?).ok_or(format_err!("invalid borker record"))?;
+                let nonce = read_u8(&mut r)?;
+                let reference = if kind.references() {
+                    let mut reference = [0_u8; 32];
+                    read_array(&mut r, &mut reference)?;
+                    Some(reference)
+                } else {
+                    None
+                };
+                let mut content = vec![0_u8; read_len(&mut r)?];
+                read_array(&mut r, &mut content)?;
+                borker::add(
+                    db,
+                    &Entry {
+                        txid,
+                        author,
+                        height,
+                        time,
+                        message: Message {
+                            kind,
+                            nonce,
+                            reference,
+                            content,
+                        },
+                    },
+                )?;
+            }
+            tag => bail!("invalid record type {}", tag),
+        }
+    }
+    let db: &dyn Store = &batch;
+    db.put(&[0_u8, 1], &hex::decode(network.genesis_hash())?)?;
+    history::set_start(db, header.history_start)?;
+    db.put(&[0_u8], &(header.height + 1).to_ne_bytes())?;
+    db.delete(&IMPORT_KEY)?;
+    batch.commit()?;
+    info!("imported {} utxos at height {}", utxos, header.height);
+    Ok(header.height)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MemStore;
+    use crate::testing::*;
+    use crate::utxo::StorageMode;
+
+    const NETWORK: Network = Network::Regtest;
+
+    fn entries(db: &dyn Store, prefixes: &[u8]) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let mut entries = Vec::new();
+        for &prefix in prefixes {
+            entries.extend(
+                db.iter(&[prefix])
+                    .take_while(|(k, _)| k.get(0) == Some(&prefix)),
+            );
+        }
+        entries
+    }
+
+    #[test]
+    fn round_trips_the_index() -> Result<(), Error> {
+        let mut chain = Chain::new(NETWORK);
+        chain.mine_empty(1, 3);
+        let mut bork = spend(&[outpoint(chain.block(1).coinbase(), 0)], &[(2, REWARD)]);
+        let mut payload = borker::MAGIC.to_vec();
+        payload.extend(&[Kind::Bork as u8, 0]);
+        payload.extend(b"such wow");
+        bork.output.push(op_return(&payload));
+        chain.mine(3, vec![bork]);
+        let node = MockNode::start(chain.clone())?;
+        let index = TestIndex::start(&node, NETWORK, StorageMode::Archive)?;
+        index.sync_to(&chain)?;
+
+        let mut snapshot = Vec::new();
+        assert_eq!(export(&*index.db, &mut snapshot)?, chain.height());
+        let db = MemStore::new();
+        NETWORK.check_db(&db, true)?;
+        Encoding::init(&db, Encoding::default())?;
+        let pool = Pool::new(NETWORK, vec![node.config()])?;
+        pool.check();
+        assert_eq!(import(&pool, &db, || Ok(&snapshot[..]))?, chain.height());
+
+        assert_eq!(db.get(&[0_u8])?, index.db.get(&[0_u8])?);
+        // everything but the meta keys, the transactions with no unspent outputs and the undo
+        // records
+        let prefixes = [1, 2, 3, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15];
+        assert_eq!(entries(&db, &prefixes), entries(&*index.db, &prefixes));
+        Ok(())
+    }
+
+    #[test]
+    fn refuses_interrupted_imports() -> Result<(), Error> {
+        let db = MemStore::new();
+        check_complete(&db)?;
+        db.put(&IMPORT_KEY, &[])?;
+        assert!(check_complete(&db).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_corrupt_lengths() {
+        let db = MemStore::new();
+        let mut snapshot = Vec::new();
+        db.put(&[0_u8], &2_u32.to_ne_bytes()).unwrap();
+        db.put(&[0_u8, 1], &hex::decode(NETWORK.genesis_hash()).unwrap())
+            .unwrap();
+        let mut block_key = vec![3_u8];
+        block_key.extend(&1_u32.to_ne_bytes());
+        db.put(&block_key, &[7; 32]).unwrap();
+        export(&db, &mut snapshot).unwrap();
+        // a transaction record claiming 4 GiB, in place of the end of the records
+        let end = snapshot.len() - 33;
+        snapshot.truncate(end);
+        snapshot.push(TAG_TX);
+        snapshot.extend(&[0; 36]);
+        snapshot.extend(&std::u32::MAX.to_be_bytes());
+        let err = check(&snapshot[..], NETWORK).err().unwrap();
+        assert!(err.to_string().contains("invalid record size"), "{}", err);
+    }
+}
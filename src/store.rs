@@ -0,0 +1,540 @@
+use crate::codec::Compression;
+use crate::key::Bytes;
+use failure::Error;
+use leveldb::batch::{Batch as _, Writebatch};
+use leveldb::compaction::Compaction;
+use leveldb::database::Database;
+use leveldb::iterator::{Iterable, LevelDBIterator};
+use leveldb::kv::KV;
+use leveldb::options::*;
+use leveldb::snapshots::{Snapshot, Snapshots};
+use parking_lot::RwLock;
+use std::collections::BTreeMap;
+use std::ops::Bound;
+use std::path::Path;
+use std::sync::Arc;
+
+pub type Entries<'a> = Box<dyn Iterator<Item = (Vec<u8>, Vec<u8>)> + 'a>;
+
+// Reads from a store, or from a snapshot of one.
+pub trait ReadStore {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Error>;
+
+    // Entries in key order, starting at the first key not below `from`. Callers stop at the end
+    // of the prefix they are reading.
+    fn iter<'a>(&'a self, from: &[u8]) -> Entries<'a>;
+}
+
+pub trait Store: ReadStore + Send + Sync {
+    fn put(&self, key: &[u8], value: &[u8]) -> Result<(), Error>;
+
+    fn delete(&self, key: &[u8]) -> Result<(), Error>;
+
+    // Applies all of the batch or none of it.
+    fn write(&self, batch: Batch) -> Result<(), Error>;
+
+    // A view of the store as it is now, unaffected by later writes.
+    fn snapshot<'a>(&'a self) -> Box<dyn ReadStore + 'a>;
+
+    fn as_read(&self) -> &dyn ReadStore;
+}
+
+#[derive(Default)]
+pub struct Batch {
+    ops: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+}
+
+impl Batch {
+    pub fn new() -> Self {
+        Batch::default()
+    }
+
+    pub fn put(&mut self, key: &[u8], value: &[u8]) {
+        self.ops.push((key.to_vec(), Some(value.to_vec())));
+    }
+
+    pub fn delete(&mut self, key: &[u8]) {
+        self.ops.push((key.to_vec(), None));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+}
+
+pub struct LevelDb {
+    db: Database<Bytes<'static>>,
+}
+
+impl LevelDb {
+    pub fn open(
+        path: &Path,
+        create_if_missing: bool,
+        compression: Compression,
+    ) -> Result<Self, Error> {
+        let mut options = Options::new();
+        options.create_if_missing = create_if_missing;
+        options.max_open_files = Some(500);
+        options.compression = compression.into();
+        Ok(LevelDb {
+            db: ldb_try!(Database::open(path, options)),
+        })
+    }
+
+    // Rewrites every table, so that the size on disk reflects the current contents.
+    pub fn compact(&self) {
+        // every key starts with a prefix below 0xff
+        self.db
+            .compact(&Bytes::from(vec![]), &Bytes::from(vec![0xff_u8]));
+    }
+}
+
+fn leveldb_get(
+    db: &Database<Bytes<'static>>,
+    options: ReadOptions<Bytes<'static>>,
+    key: &[u8],
+) -> Result<Option<Vec<u8>>, Error> {
+    Ok(ldb_try!(db.get(options, Bytes::from(key.to_vec()))))
+}
+
+fn leveldb_iter<'a>(
+    db: &'a Database<Bytes<'static>>,
+    options: ReadOptions<'a, Bytes<'static>>,
+    from: &[u8],
+) -> Entries<'a> {
+    let iter = db.iter(options);
+    iter.seek(&Bytes::from(from.to_vec()));
+    Box::new(iter.map(|(k, v)| (k.to_vec(), v)))
+}
+
+impl ReadStore for LevelDb {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        leveldb_get(&self.db, ReadOptions::new(), key)
+    }
+
+    fn iter<'a>(&'a self, from: &[u8]) -> Entries<'a> {
+        leveldb_iter(&self.db, ReadOptions::new(), from)
+    }
+}
+
+impl Store for LevelDb {
+    fn put(&self, key: &[u8], value: &[u8]) -> Result<(), Error> {
+        ldb_try!(self
+            .db
+            .put(WriteOptions::new(), Bytes::from(key.to_vec()), value));
+        Ok(())
+    }
+
+    fn delete(&self, key: &[u8]) -> Result<(), Error> {
+        ldb_try!(self
+            .db
+            .delete(WriteOptions::new(), Bytes::from(key.to_vec())));
+        Ok(())
+    }
+
+    fn write(&self, batch: Batch) -> Result<(), Error> {
+        let mut wb = Writebatch::new();
+        for (key, value) in batch.ops {
+            match value {
+                Some(value) => wb.put(Bytes::from(key), &value),
+                None => wb.delete(Bytes::from(key)),
+            }
+        }
+        ldb_try!(self.db.write(WriteOptions::new(), &wb));
+        Ok(())
+    }
+
+    fn snapshot<'a>(&'a self) -> Box<dyn ReadStore + 'a> {
+        Box::new(LevelDbSnapshot {
+            db: &self.db,
+            snapshot: self.db.snapshot(),
+        })
+    }
+
+    fn as_read(&self) -> &dyn ReadStore {
+        self
+    }
+}
+
+struct LevelDbSnapshot<'a> {
+    db: &'a Database<Bytes<'static>>,
+    snapshot: Snapshot<'a, Bytes<'static>>,
+}
+
+impl<'a> LevelDbSnapshot<'a> {
+    fn options(&self) -> ReadOptions<Bytes<'static>> {
+        let mut options = ReadOptions::new();
+        options.snapshot = Some(&self.snapshot);
+        options
+    }
+}
+
+impl<'a> ReadStore for LevelDbSnapshot<'a> {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        leveldb_get(self.db, self.options(), key)
+    }
+
+    fn iter<'b>(&'b self, from: &[u8]) -> Entries<'b> {
+        leveldb_iter(self.db, self.options(), from)
+    }
+}
+
+type Map = BTreeMap<Vec<u8>, Vec<u8>>;
+
+// Keeps everything in memory, for tests. Snapshots share the map until it is next written.
+#[derive(Default)]
+pub struct MemStore {
+    map: RwLock<Arc<Map>>,
+}
+
+impl MemStore {
+    pub fn new() -> Self {
+        MemStore::default()
+    }
+}
+
+// Holds its own reference to the map, so no lock is held while iterating and the store can be
+// written to meanwhile.
+struct MemEntries {
+    map: Arc<Map>,
+    next: Bound<Vec<u8>>,
+}
+
+impl Iterator for MemEntries {
+    type Item = (Vec<u8>, Vec<u8>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (k, v) = self
+            .map
+            .range((self.next.clone(), Bound::Unbounded))
+            .next()
+            .map(|(k, v)| (k.clone(), v.clone()))?;
+        self.next = Bound::Excluded(k.clone());
+        Some((k, v))
+    }
+}
+
+impl ReadStore for Arc<Map> {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        Ok(BTreeMap::get(self, key).cloned())
+    }
+
+    fn iter<'a>(&'a self, from: &[u8]) -> Entries<'a> {
+        Box::new(MemEntries {
+            map: self.clone(),
+            next: Bound::Included(from.to_vec()),
+        })
+    }
+}
+
+impl ReadStore for MemStore {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        Ok(BTreeMap::get(&**self.map.read(), key).cloned())
+    }
+
+    fn iter<'a>(&'a self, from: &[u8]) -> Entries<'a> {
+        let map = self.map.read().clone();
+        Box::new(MemEntries {
+            map,
+            next: Bound::Included(from.to_vec()),
+        })
+    }
+}
+
+impl Store for MemStore {
+    fn put(&self, key: &[u8], value: &[u8]) -> Result<(), Error> {
+        Arc::make_mut(&mut *self.map.write()).insert(key.to_vec(), value.to_vec());
+        Ok(())
+    }
+
+    fn delete(&self, key: &[u8]) -> Result<(), Error> {
+        Arc::make_mut(&mut *self.map.write()).remove(key);
+        Ok(())
+    }
+
+    fn write(&self, batch: Batch) -> Result<(), Error> {
+        let mut guard = self.map.write();
+        let map = Arc::make_mut(&mut *guard);
+        for (key, value) in batch.ops {
+            match value {
+                Some(value) => map.insert(key, value),
+                None => map.remove(&key),
+            };
+        }
+        Ok(())
+    }
+
+    fn snapshot<'a>(&'a self) -> Box<dyn ReadStore + 'a> {
+        Box::new(self.map.read().clone())
+    }
+
+    fn as_read(&self) -> &dyn ReadStore {
+        self
+    }
+}
+
+type Pending = BTreeMap<Vec<u8>, Option<Vec<u8>>>;
+
+// Holds writes in memory until `commit` applies them to the underlying store in one batch.
+// Reads see the pending writes, so a block can be executed against it as against the store.
+pub struct BatchStore<'a> {
+    base: &'a dyn Store,
+    pending: RwLock<Pending>,
+}
+
+impl<'a> BatchStore<'a> {
+    pub fn new(base: &'a dyn Store) -> Self {
+        BatchStore {
+            base,
+            pending: RwLock::new(Pending::new()),
+        }
+    }
+
+    pub fn commit(self) -> Result<(), Error> {
+        let mut batch = Batch::new();
+        for (key, value) in self.pending.into_inner() {
+            match value {
+                Some(value) => batch.put(&key, &value),
+                None => batch.delete(&key),
+            }
+        }
+        if batch.is_empty() {
+            return Ok(());
+        }
+        self.base.write(batch)
+    }
+}
+
+fn layered_get(
+    base: &dyn ReadStore,
+    pending: &Pending,
+    key: &[u8],
+) -> Result<Option<Vec<u8>>, Error> {
+    match pending.get(key) {
+        Some(value) => Ok(value.clone()),
+        None => base.get(key),
+    }
+}
+
+fn layered_iter<'a>(base: &'a dyn ReadStore, pending: &Pending, from: &[u8]) -> Entries<'a> {
+    let pending: Vec<_> = pending
+        .range::<[u8], _>((Bound::Included(from), Bound::Unbounded))
+        .map(|(k, v)| (k.clone(), v.clone()))
+        .collect();
+    Box::new(LayeredEntries {
+        base: base.iter(from).peekable(),
+        pending: pending.into_iter().peekable(),
+    })
+}
+
+// Merges pending writes into the entries of the underlying store, skipping deleted keys.
+struct LayeredEntries<'a> {
+    base: std::iter::Peekable<Entries<'a>>,
+    pending: std::iter::Peekable<std::vec::IntoIter<(Vec<u8>, Option<Vec<u8>>)>>,
+}
+
+impl<'a> Iterator for LayeredEntries<'a> {
+    type Item = (Vec<u8>, Vec<u8>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let from_base = match (self.base.peek(), self.pending.peek()) {
+                (Some(_), None) => true,
+                (Some((a, _)), Some((b, _))) => a < b,
+                (None, _) => false,
+            };
+            if from_base {
+                return self.base.next();
+            }
+            let (key, value) = self.pending.next()?;
+            if self.base.peek().map(|(k, _)| k == &key).unwrap_or(false) {
+                self.base.next();
+            }
+            if let Some(value) = value {
+                return Some((key, value));
+            }
+        }
+    }
+}
+
+impl<'a> ReadStore for BatchStore<'a> {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        layered_get(self.base.as_read(), &self.pending.read(), key)
+    }
+
+    fn iter<'b>(&'b self, from: &[u8]) -> Entries<'b> {
+        layered_iter(self.base.as_read(), &self.pending.read(), from)
+    }
+}
+
+impl<'a> Store for BatchStore<'a> {
+    fn put(&self, key: &[u8], value: &[u8]) -> Result<(), Error> {
+        self.pending
+            .write()
+            .insert(key.to_vec(), Some(value.to_vec()));
+        Ok(())
+    }
+
+    fn delete(&self, key: &[u8]) -> Result<(), Error> {
+        self.pending.write().insert(key.to_vec(), None);
+        Ok(())
+    }
+
+    fn write(&self, batch: Batch) -> Result<(), Error> {
+        self.pending.write().extend(batch.ops);
+        Ok(())
+    }
+
+    fn snapshot<'b>(&'b self) -> Box<dyn ReadStore + 'b> {
+        Box::new(BatchSnapshot {
+            base: self.base.snapshot(),
+            pending: self.pending.read().clone(),
+        })
+    }
+
+    fn as_read(&self) -> &dyn ReadStore {
+        self
+    }
+}
+
+struct BatchSnapshot<'a> {
+    base: Box<dyn ReadStore + 'a>,
+    pending: Pending,
+}
+
+impl<'a> ReadStore for BatchSnapshot<'a> {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        layered_get(&*self.base, &self.pending, key)
+    }
+
+    fn iter<'b>(&'b self, from: &[u8]) -> Entries<'b> {
+        layered_iter(&*self.base, &self.pending, from)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entries(db: &dyn ReadStore, prefix: u8) -> Vec<(Vec<u8>, Vec<u8>)> {
+        db.iter(&[prefix])
+            .take_while(|(k, _)| k.get(0) == Some(&prefix))
+            .collect()
+    }
+
+    fn base() -> Result<MemStore, Error> {
+        let db = MemStore::new();
+        db.put(&[1, 1], b"a")?;
+        db.put(&[1, 2], b"b")?;
+        db.put(&[1, 3], b"c")?;
+        db.put(&[2, 0], b"d")?;
+        Ok(db)
+    }
+
+    #[test]
+    fn batch_reads_its_own_writes() -> Result<(), Error> {
+        let db = base()?;
+        let batch = BatchStore::new(&db);
+        batch.put(&[1, 2], b"x")?;
+        batch.put(&[1, 4], b"y")?;
+        batch.delete(&[1, 1])?;
+        batch.delete(&[1, 5])?;
+        assert_eq!(batch.get(&[1, 1])?, None);
+        assert_eq!(batch.get(&[1, 2])?, Some(b"x".to_vec()));
+        assert_eq!(batch.get(&[1, 3])?, Some(b"c".to_vec()));
+        assert_eq!(
+            entries(&batch, 1),
+            vec![
+                (vec![1, 2], b"x".to_vec()),
+                (vec![1, 3], b"c".to_vec()),
+                (vec![1, 4], b"y".to_vec()),
+            ]
+        );
+        // nothing reaches the store before the commit
+        assert_eq!(db.get(&[1, 2])?, Some(b"b".to_vec()));
+        assert_eq!(db.get(&[1, 4])?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn batch_deletes_then_puts() -> Result<(), Error> {
+        let db = base()?;
+        let batch = BatchStore::new(&db);
+        batch.delete(&[1, 3])?;
+        assert_eq!(entries(&batch, 1).len(), 2);
+        batch.put(&[1, 3], b"z")?;
+        let mut ops = Batch::new();
+        ops.delete(&[1, 1]);
+        ops.delete(&[1, 2]);
+        batch.write(ops)?;
+        assert_eq!(entries(&batch, 1), vec![(vec![1, 3], b"z".to_vec())]);
+        batch.delete(&[1, 3])?;
+        assert_eq!(entries(&batch, 1), vec![]);
+        assert_eq!(entries(&batch, 2), vec![(vec![2, 0], b"d".to_vec())]);
+        Ok(())
+    }
+
+    #[test]
+    fn batch_keeps_prefix_boundaries() -> Result<(), Error> {
+        let db = base()?;
+        let batch = BatchStore::new(&db);
+        // pending keys on both sides of prefix 1, and one sorting before its entries
+        batch.put(&[0, 9], b"p")?;
+        batch.put(&[1], b"q")?;
+        batch.put(&[2], b"r")?;
+        batch.delete(&[2, 0])?;
+        assert_eq!(
+            entries(&batch, 1),
+            vec![
+                (vec![1], b"q".to_vec()),
+                (vec![1, 1], b"a".to_vec()),
+                (vec![1, 2], b"b".to_vec()),
+                (vec![1, 3], b"c".to_vec()),
+            ]
+        );
+        assert_eq!(entries(&batch, 2), vec![(vec![2], b"r".to_vec())]);
+        assert_eq!(
+            batch.iter(&[1, 2]).next(),
+            Some((vec![1, 2], b"b".to_vec()))
+        );
+        assert_eq!(batch.iter(&[3]).next(), None);
+        Ok(())
+    }
+
+    #[test]
+    fn batch_commits_at_once() -> Result<(), Error> {
+        let db = base()?;
+        let batch = BatchStore::new(&db);
+        batch.put(&[1, 1], b"x")?;
+        batch.delete(&[1, 2])?;
+        let snapshot = batch.snapshot();
+        batch.put(&[1, 3], b"y")?;
+        assert_eq!(snapshot.get(&[1, 3])?, Some(b"c".to_vec()));
+        assert_eq!(entries(&*snapshot, 1).len(), 2);
+        drop(snapshot);
+        batch.commit()?;
+        assert_eq!(
+            entries(&db, 1),
+            vec![(vec![1, 1], b"x".to_vec()), (vec![1, 3], b"y".to_vec())]
+        );
+
+        // a dropped batch leaves the store as it was
+        let batch = BatchStore::new(&db);
+        batch.delete(&[1, 1])?;
+        drop(batch);
+        assert_eq!(db.get(&[1, 1])?, Some(b"x".to_vec()));
+        Ok(())
+    }
+
+    #[test]
+    fn snapshots_ignore_later_writes() -> Result<(), Error> {
+        let db = base()?;
+        let snapshot = db.snapshot();
+        db.put(&[1, 1], b"x")?;
+        db.delete(&[2, 0])?;
+        assert_eq!(snapshot.get(&[1, 1])?, Some(b"a".to_vec()));
+        assert_eq!(entries(&*snapshot, 2).len(), 1);
+        assert_eq!(entries(&db, 2).len(), 0);
+        Ok(())
+    }
+}
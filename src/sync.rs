@@ -0,0 +1,997 @@
+use crate::api;
+use crate::block::Block;
+use crate::cache::RpcCache;
+use crate::headers::{self, InvalidBlock};
+use crate::history;
+use crate::indexer::{Subscribers, SyncConfig};
+use crate::logger;
+use crate::metrics;
+use crate::query::{self, Tip};
+use crate::shutdown::Shutdown;
+use crate::store::{BatchStore, ReadStore, Store};
+use crate::upstream::Pool;
+use crate::utxo::{StorageMode, UTXOID};
+use crate::Rewind;
+use failure::Error;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+pub type BlockSender = crossbeam_channel::Sender<(u32, Vec<u8>, Vec<u8>)>;
+pub type BlockReceiver = crossbeam_channel::Receiver<(u32, Vec<u8>, Vec<u8>)>;
+
+// Fetches blocks from the next height to index on. `reset` is set by the processing thread when
+// a block failed, which drops the blocks queued after it, so that fetching resumes from the next
+// height again.
+pub fn fetch(
+    pool: &Pool,
+    db: &dyn Store,
+    send: &BlockSender,
+    reset: &AtomicBool,
+    shutdown: &Shutdown,
+) {
+    let next = || api::next_height(db.as_read()).unwrap_or_else(|e| panic!("{}", e));
+    let mut idx = next();
+    metrics::INDEXED_HEIGHT.set(idx as i64 - 1);
+    let mut last: Option<(usize, String)> = None;
+    'main: loop {
+        if shutdown.requested() {
+            info!("fetcher stopped at {}", idx);
+            return;
+        }
+        if reset.swap(false, Ordering::SeqCst) {
+            idx = next();
+            last = None;
+            info!("fetching again from {}", idx);
+        }
+        let node = pool.active();
+        let _ctx = logger::context(&[("node", &node.id)]);
+        let client = &node.client;
+        let count = match client.getblockcount() {
+            Ok(a) => a,
+            Err(e) => {
+                warn!("getblockcount: {}", e);
+                pool.mark_failed(&node);
+                shutdown.sleep(std::time::Duration::from_secs(1));
+                continue 'main;
+            }
+        };
+        // blocks already queued came from another node: if this one disagrees on the
+        // last of them, the processing thread will find the fork and rewind to it.
+        match last {
+            Some((id, ref hash)) if id != node.id && idx > 1 => {
+                match client.getblockhash(idx - 1) {
+                    Ok(ref h) if h == hash => (),
+                    Ok(h) => warn!(
+                        "upstream node {} has {} at {}, node {} had {}",
+                        node.id,
+                        h,
+                        idx - 1,
+                        id,
+                        hash
+                    ),
+                    Err(e) => {
+                        warn!("getblockhash: {}", e);
+                        pool.mark_failed(&node);
+                        continue 'main;
+                    }
+                }
+            }
+            _ => (),
+        }
+        use throttled_bitcoin_rpc::BatchRequest;
+        let mut batcher = client.batcher::<String>();
+        let idxs = idx..std::cmp::min(idx + 20, count + 1);
+        let time = std::time::Instant::now();
+        for i in idxs.clone() {
+            match batcher.getblockhash(i) {
+                Ok(_) => (),
+                Err(e) => {
+                    warn!("getblockhash: {}", e);
+                    continue 'main;
+                }
+            }
+        }
+        let hashes = match batcher.send() {
+            Ok(a) => a,
+            Err(e) => {
+                warn!("getblockhash batch: {}", e);
+                pool.mark_failed(&node);
+                continue 'main;
+            }
+        };
+        for hash in hashes.iter() {
+            match batcher.getblock(hash, false) {
+                Ok(_) => (),
+                Err(e) => {
+                    warn!("getblock: {}", e);
+                    continue 'main;
+                }
+            }
+        }
+        let blocks = match batcher.send() {
+            Ok(a) => a,
+            Err(e) => {
+                warn!("getblock batch: {}", e);
+                pool.mark_failed(&node);
+                continue 'main;
+            }
+        };
+        if idx <= count {
+            debug!("fetched {:?} in {:?}", idxs, time.elapsed());
+            metrics::FETCH_DURATION.observe(time.elapsed().as_secs_f64());
+        }
+        for ((i, hash), block) in idxs
+            .into_iter()
+            .zip(hashes.into_iter())
+            .into_iter()
+            .zip(blocks.into_iter())
+        {
+            last = Some((node.id, hash.clone()));
+            let hash = match hex::decode(hash) {
+                Ok(a) => a,
+                Err(e) => {
+                    warn!("invalid block hash at {}: {}", i, e);
+                    continue 'main;
+                }
+            };
+            let block = match hex::decode(block) {
+                Ok(a) => a,
+                Err(e) => {
+                    warn!("invalid block at {}: {}", i, e);
+                    continue 'main;
+                }
+            };
+            match send.send((i, hash, block)) {
+                Ok(_) => (),
+                Err(e) => {
+                    error!("queueing block {}: {}", i, e);
+                    continue 'main;
+                }
+            };
+            idx = i + 1;
+        }
+        if idx > count {
+            shutdown.sleep(std::time::Duration::from_secs(1));
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn process(
+    pool: &Pool,
+    db: &dyn Store,
+    cache: &RpcCache,
+    recv: BlockReceiver,
+    reset: &AtomicBool,
+    conf: SyncConfig,
+    subscribers: &Subscribers,
+    shutdown: &Shutdown,
+) {
+    let mut time = std::time::Instant::now();
+    let mut tpb = std::time::Duration::from_secs(0);
+    let mut periods = 0;
+    let mut rewind = load_rewind(db.as_read()).unwrap_or_else(|e| {
+        error!(
+            "failed to load rewind, recent blocks cannot be undone: {}",
+            e
+        );
+        empty_rewind()
+    });
+    loop {
+        if shutdown.requested() {
+            info!("processor stopped");
+            return;
+        }
+        match try_process_block(pool, &recv, db, cache, &mut rewind, conf) {
+            Ok(Some(tip)) => {
+                subscribers.notify(&tip);
+                let i = tip.height;
+                debug!("scanned {}", i);
+                if i % 100 == 0 {
+                    let inst_tpb = time.elapsed() / 100;
+                    info!(
+                        "scanned {}, {:.2} blocks/second",
+                        i,
+                        1.0 / inst_tpb.as_secs_f64()
+                    );
+                    tpb = ((tpb * periods) + inst_tpb) / (periods + 1);
+                    periods += 1;
+                    time = std::time::Instant::now();
+                }
+                if i % 500 == 0 {
+                    match pool.active().client.getblockcount().ok() {
+                        Some(count) if i < count as u32 => {
+                            let remaining = tpb * (count - i);
+                            info!(
+                                "average {:.2} blocks/second, {} remaining",
+                                1.0 / tpb.as_secs_f64(),
+                                humantime::format_duration(remaining)
+                            );
+                        }
+                        _ => (),
+                    }
+                }
+            }
+            Ok(None) => (),
+            Err(e) => {
+                reset.store(true, Ordering::SeqCst);
+                // the batch of the failed block was dropped, and with it the undo records that
+                // reverting and executing it changed in memory
+                match load_rewind(db.as_read()) {
+                    Ok(r) => rewind = r,
+                    Err(e) => error!("failed to reload rewind: {}", e),
+                }
+                match e.downcast_ref::<InvalidBlock>() {
+                    Some(invalid) => {
+                        error!("{}, halting", invalid);
+                        shutdown.request();
+                    }
+                    None => error!("{}\n{}", e, e.backtrace()),
+                }
+            }
+        };
+    }
+}
+
+// The undo record of each of the last CONFIRMATIONS blocks is stored under prefix 16 ++ slot, as
+// the height it belongs to then the cbor encoded record, in the batch that indexes the block. A
+// slot holding another height is stale, left by a reindex, and is ignored.
+fn rewind_key(height: u32) -> [u8; 2] {
+    [16, (height as usize % crate::CONFIRMATIONS) as u8]
+}
+
+// Blocks deeper than the rewind window, or from before a snapshot was imported, have no undo
+// record, and undoing them would lose the outputs they spent.
+fn check_rewind(db: &dyn Store, height: u32) -> Result<(), Error> {
+    match db.get(&rewind_key(height))? {
+        Some(ref record) if record.len() >= 4 && record[..4] == height.to_ne_bytes() => Ok(()),
+        _ => bail!(
+            "block {} has no undo record and cannot be reverted, reindex or import a newer snapshot",
+            height
+        ),
+    }
+}
+
+fn empty_rewind() -> Rewind {
+    std::iter::repeat_with(HashMap::new)
+        .take(crate::CONFIRMATIONS)
+        .collect()
+}
+
+fn save_rewind(db: &dyn Store, rewind: &Rewind, height: u32) -> Result<(), Error> {
+    let mut record = height.to_ne_bytes().to_vec();
+    serde_cbor::to_writer(&mut record, &rewind[height as usize % crate::CONFIRMATIONS])?;
+    db.put(&rewind_key(height), &record)
+}
+
+fn load_rewind(db: &dyn ReadStore) -> Result<Rewind, Error> {
+    let next = match db.get(&[0_u8])? {
+        Some(b) => {
+            let mut buf = [0_u8; 4];
+            if b.len() != 4 {
+                bail!("invalid size for u32");
+            }
+            buf.clone_from_slice(&b);
+            u32::from_ne_bytes(buf)
+        }
+        None => 1,
+    };
+    let mut rewind = empty_rewind();
+    for height in next.saturating_sub(crate::CONFIRMATIONS as u32)..next {
+        match db.get(&rewind_key(height))? {
+            Some(ref record) if record.len() >= 4 && record[..4] == height.to_ne_bytes() => {
+                rewind[height as usize % crate::CONFIRMATIONS] =
+                    serde_cbor::from_slice(&record[4..])?;
+            }
+            _ => (),
+        }
+    }
+    Ok(rewind)
+}
+
+fn try_process_block(
+    pool: &Pool,
+    recv: &BlockReceiver,
+    db: &dyn Store,
+    cache: &RpcCache,
+    rewind: &mut Rewind,
+    conf: SyncConfig,
+) -> Result<Option<Tip>, Error> {
+    let (idx, bhash, block_raw) = match recv.recv_timeout(std::time::Duration::from_millis(100)) {
+        Ok(a) => a,
+        Err(crossbeam_channel::RecvTimeoutError::Timeout) => return Ok(None),
+        Err(e) => return Err(Error::from(e)),
+    };
+    let _ctx = logger::context(&[("height", &idx), ("hash", &hex::encode(&bhash))]);
+    let next = api::next_height(db.as_read())?;
+    if idx != next {
+        // queued after a block that failed, before the fetcher was reset
+        debug!("skipping block, expected {}", next);
+        return Ok(None);
+    }
+    // the block, and any blocks it reverts, are committed at once so that readers never see
+    // a partly indexed block
+    let batch = BatchStore::new(db);
+    let db: &dyn Store = &batch;
+    let mut bkey = Vec::with_capacity(9);
+    bkey.push(3_u8);
+    bkey.extend(&idx.to_ne_bytes());
+    db.put(&bkey, &bhash)?;
+    let block = Block::from_slice(&block_raw, pool.network(), idx)?;
+    if !conf.validate_headers {
+        block.check_auxpow()?;
+    }
+    let depth = handle_rewind(
+        pool,
+        db,
+        cache,
+        &block.header.prev_blockhash[..],
+        idx - 1,
+        rewind,
+        conf,
+    )?;
+    if depth > 0 {
+        metrics::REORG_DEPTH.observe(depth as f64);
+    }
+    if conf.validate_headers {
+        headers::check(pool, db, idx, &block)?;
+    }
+    headers::index(db, idx, &block.header)?;
+    let tx_count = block.tx_count;
+    let timer = metrics::EXEC_DURATION.start_timer();
+    block.exec(db, idx, rewind, conf.mode)?;
+    save_rewind(db, rewind, idx)?;
+    timer.observe_duration();
+    db.put(&[0_u8], &(idx + 1).to_ne_bytes())?;
+    batch.commit()?;
+    metrics::INDEXED_HEIGHT.set(idx as i64);
+    metrics::BLOCKS_PROCESSED.inc();
+    metrics::TXS_PROCESSED.inc_by(tx_count as i64);
+    metrics::REWIND_ENTRIES.set(rewind.iter().map(|r| r.len() as i64).sum());
+
+    Ok(Some(Tip {
+        height: idx,
+        hash: query::from_display(&bhash)?,
+    }))
+}
+
+fn handle_rewind(
+    pool: &Pool,
+    db: &dyn Store,
+    cache: &RpcCache,
+    hash: &[u8],
+    idx: u32,
+    rewind: &mut Rewind,
+    conf: SyncConfig,
+) -> Result<u32, Error> {
+    if idx <= 1 {
+        return Ok(0);
+    }
+
+    let mut cow = std::borrow::Cow::Borrowed(hash);
+    let hash = cow.to_mut();
+    hash.reverse();
+    let mut block_key = Vec::with_capacity(5);
+    block_key.push(3_u8);
+    block_key.extend(&idx.to_ne_bytes());
+    let old_hash = db
+        .get(&block_key)?
+        .ok_or(format_err!("missing block_hash"))?;
+    if old_hash.as_slice() == AsRef::<[u8]>::as_ref(hash) {
+        return Ok(0);
+    }
+    let _ctx = logger::context(&[("height", &idx), ("hash", &hex::encode(&old_hash))]);
+    warn!("reverting block");
+    cache.clear();
+    check_rewind(db, idx)?;
+    let block_raw = pool.getblock(&hex::encode(old_hash))?;
+    let block = Block::from_slice(&block_raw, pool.network(), idx)?;
+    block.undo(&pool.active().client, db, idx, rewind, conf.mode)?;
+    headers::unindex(db, idx, &old_hash)?;
+    let block_raw = pool.getblock(&hex::encode(&hash))?;
+    let block = Block::from_slice(&block_raw, pool.network(), idx)?;
+    if !conf.validate_headers {
+        block.check_auxpow()?;
+    }
+    let depth = handle_rewind(
+        pool,
+        db,
+        cache,
+        &block.header.prev_blockhash[..],
+        idx - 1,
+        rewind,
+        conf,
+    )?;
+    if conf.validate_headers {
+        headers::check(pool, db, idx, &block)?;
+    }
+    headers::index(db, idx, &block.header)?;
+    block.exec(db, idx, rewind, conf.mode)?;
+    save_rewind(db, rewind, idx)?;
+    db.put(&block_key, hash)?;
+
+    Ok(depth + 1)
+}
+
+// Undoes every indexed block from the tip down to `from`, so that indexing resumes at `from`.
+// Blocks deeper than the rewind window have no undo record, so the outputs they spent are
+// restored from the raw transactions under prefix 4.
+pub fn reindex(
+    pool: &Pool,
+    db: &dyn Store,
+    from: u32,
+    mode: StorageMode,
+    shutdown: &Shutdown,
+) -> Result<(), Error> {
+    if from < 1 {
+        bail!("cannot reindex from below height 1");
+    }
+    let next = match db.get(&[0_u8])? {
+        Some(b) => {
+            let mut buf = [0_u8; 4];
+            if b.len() != 4 {
+                bail!("invalid size for u32");
+            }
+            buf.clone_from_slice(&b);
+            u32::from_ne_bytes(buf)
+        }
+        None => 1,
+    };
+    for idx in (from..next).rev() {
+        if shutdown.requested() {
+            warn!("reindex interrupted, indexing resumes from {}", idx + 1);
+            return Ok(());
+        }
+        let mut block_key = Vec::with_capacity(5);
+        block_key.push(3_u8);
+        block_key.extend(&idx.to_ne_bytes());
+        let hash = db
+            .get(&block_key)?
+            .ok_or(format_err!("missing block_hash"))?;
+        let block_raw = pool.getblock(&hex::encode(&hash))?;
+        let mut rewind = empty_rewind();
+        for tx in Block::from_slice(&block_raw, pool.network(), idx)? {
+            for i in tx?.input {
+                if i.previous_output.is_null() {
+                    continue;
+                }
+                let id = UTXOID::from(&i);
+                let mut tx_key = Vec::with_capacity(33);
+                tx_key.push(4_u8);
+                tx_key.extend(&id.txid);
+                let raw = db.get(&tx_key)?;
+                rewind[idx as usize % crate::CONFIRMATIONS].insert(id, (None, raw));
+            }
+        }
+        Block::from_slice(&block_raw, pool.network(), idx)?.undo(
+            &pool.active().client,
+            db,
+            idx,
+            &mut rewind,
+            mode,
+        )?;
+        db.delete(&block_key)?;
+        headers::unindex(db, idx, &hash)?;
+        db.put(&[0_u8], &idx.to_ne_bytes())?;
+        info!("reverted {}", idx);
+    }
+    history::extend(db, from)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::api;
+    use crate::borker::{self, Kind};
+    use crate::network::Network;
+    use crate::shutdown::Shutdown;
+    use crate::testing::*;
+    use crate::upstream::Pool;
+    use crate::utxo::StorageMode;
+    use bitcoin::consensus::encode::serialize;
+    use bitcoin::{BlockHeader, Transaction};
+    use bitcoin_hashes::{sha256d, Hash};
+    use failure::Error;
+    use serde_json::{json, Value};
+
+    const NETWORK: Network = Network::Regtest;
+    const MODES: [StorageMode; 2] = [StorageMode::Archive, StorageMode::Utxo];
+
+    #[test]
+    fn indexes_transfers_across_auxpow_activation() -> Result<(), Error> {
+        let mut chain = Chain::new(NETWORK);
+        chain.mine_empty(1, NETWORK.auxpow_height() + 4);
+        let tx = spend(
+            &[outpoint(chain.block(1).coinbase(), 0)],
+            &[(2, 3000 * COIN), (1, 6999 * COIN)],
+        );
+        chain.mine(1, vec![tx.clone()]);
+        let node = MockNode::start(chain.clone())?;
+        let index = TestIndex::start(&node, NETWORK, StorageMode::Archive)?;
+        index.sync_to(&chain)?;
+
+        let blocks = chain.height() as u64;
+        assert_eq!(index.balance(2)?, 3000 * COIN);
+        assert_eq!(index.balance(1)?, (blocks - 1) * REWARD + 6999 * COIN);
+        assert_eq!(
+            index.utxos(2)?,
+            vec![(display_hash(&tx.txid()), 0, 3000 * COIN)]
+        );
+        let query = format!("/balance?address={}", address(NETWORK, 2));
+        assert_eq!(get(&index, &query)?, json!(3000 * COIN));
+        assert_eq!(
+            get(&index, &format!("{}&byMaturity=true", query))?,
+            json!({ "mature": 3000 * COIN, "immature": 0 })
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn reorg_restores_spent_outputs() -> Result<(), Error> {
+        for &mode in MODES.iter() {
+            let mut chain = Chain::new(NETWORK);
+            chain.mine_empty(1, 12);
+            let coinbase = chain.block(1).coinbase().clone();
+            chain.mine(1, vec![spend(&[outpoint(&coinbase, 0)], &[(2, REWARD)])]);
+            chain.mine_empty(1, 2);
+            let node = MockNode::start(chain.clone())?;
+            let index = TestIndex::start(&node, NETWORK, mode)?;
+            index.sync_to(&chain)?;
+            assert_eq!(index.balance(1)?, 14 * REWARD);
+            assert_eq!(index.balance(2)?, REWARD);
+            // the spent coinbase's height is pruned along with it
+            let mut txid = [0_u8; 32];
+            txid.copy_from_slice(&hex::decode(display_hash(&coinbase.txid()))?);
+            let height = index.db.as_read().get(&crate::block::coinbase_key(&txid))?;
+            assert_eq!(height.is_none(), mode == StorageMode::Utxo);
+
+            // replaces blocks 12 to 15 with a longer branch without the spend
+            let mut fork = chain.fork(11);
+            fork.mine_empty(3, 5);
+            node.set_chain(fork.clone());
+            index.sync_to(&fork)?;
+            assert_eq!(index.balance(1)?, 11 * REWARD, "{:?}", mode);
+            assert_eq!(index.balance(2)?, 0, "{:?}", mode);
+            assert_eq!(index.balance(3)?, 5 * REWARD, "{:?}", mode);
+            assert!(index
+                .utxos(1)?
+                .contains(&(display_hash(&coinbase.txid()), 0, REWARD)));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn reorg_after_restart_restores_spent_outputs() -> Result<(), Error> {
+        for &mode in MODES.iter() {
+            let mut chain = Chain::new(NETWORK);
+            chain.mine_empty(1, 12);
+            let coinbase = chain.block(1).coinbase().clone();
+            chain.mine(1, vec![spend(&[outpoint(&coinbase, 0)], &[(2, REWARD)])]);
+            let node = MockNode::start(chain.clone())?;
+            let mut index = TestIndex::start(&node, NETWORK, mode)?;
+            index.sync_to(&chain)?;
+            // the undo record of the spend has to outlive the process that wrote it
+            index.restart(&node)?;
+
+            let mut fork = chain.fork(12);
+            fork.mine_empty(3, 2);
+            node.set_chain(fork.clone());
+            index.sync_to(&fork)?;
+            assert_eq!(index.balance(2)?, 0, "{:?}", mode);
+            assert!(index
+                .utxos(1)?
+                .contains(&(display_hash(&coinbase.txid()), 0, REWARD)));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn reorg_restores_spent_outputs_without_address() -> Result<(), Error> {
+        for &mode in MODES.iter() {
+            let mut chain = Chain::new(NETWORK);
+            chain.mine_empty(1, 12);
+            let mut pay = spend(&[outpoint(chain.block(1).coinbase(), 0)], &[]);
+            pay.output.push(p2pk(2, REWARD));
+            chain.mine(1, vec![pay.clone()]);
+            chain.mine(1, vec![spend(&[outpoint(&pay, 0)], &[(3, REWARD)])]);
+            let node = MockNode::start(chain.clone())?;
+            let index = TestIndex::start(&node, NETWORK, mode)?;
+            index.sync_to(&chain)?;
+            assert_eq!(index.balance(3)?, REWARD);
+
+            let mut fork = chain.fork(13);
+            fork.mine_empty(4, 2);
+            node.set_chain(fork.clone());
+            index.sync_to(&fork)?;
+            assert_eq!(index.balance(3)?, 0, "{:?}", mode);
+            assert!(
+                index.query().transaction(&pay.txid())?.is_some(),
+                "{:?}",
+                mode
+            );
+
+            // and spent again on the new branch
+            fork.mine(4, vec![spend(&[outpoint(&pay, 0)], &[(5, REWARD)])]);
+            node.set_chain(fork.clone());
+            index.sync_to(&fork)?;
+            assert_eq!(index.balance(5)?, REWARD, "{:?}", mode);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn reorg_moves_transactions_between_branches() -> Result<(), Error> {
+        for &mode in MODES.iter() {
+            let mut chain = Chain::new(NETWORK);
+            chain.mine_empty(1, 5);
+            let tx = spend(
+                &[outpoint(chain.block(2).coinbase(), 0)],
+                &[(2, REWARD / 2), (3, REWARD / 2)],
+            );
+            chain.mine(1, vec![tx.clone()]);
+            chain.mine_empty(1, 1);
+            let node = MockNode::start(chain.clone())?;
+            let index = TestIndex::start(&node, NETWORK, mode)?;
+            index.sync_to(&chain)?;
+
+            // the same transaction, one block later on the competing branch
+            let mut fork = chain.fork(5);
+            fork.mine_empty(4, 1);
+            fork.mine(4, vec![tx.clone()]);
+            fork.mine_empty(4, 1);
+            node.set_chain(fork.clone());
+            index.sync_to(&fork)?;
+            assert_eq!(index.balance(1)?, 4 * REWARD, "{:?}", mode);
+            assert_eq!(index.balance(4)?, 3 * REWARD, "{:?}", mode);
+            assert_eq!(
+                index.utxos(2)?,
+                vec![(display_hash(&tx.txid()), 0, REWARD / 2)]
+            );
+
+            // and back to the first branch, which has grown meanwhile
+            chain.mine_empty(1, 2);
+            node.set_chain(chain.clone());
+            index.sync_to(&chain)?;
+            assert_eq!(index.balance(1)?, 8 * REWARD, "{:?}", mode);
+            assert_eq!(index.balance(3)?, REWARD / 2, "{:?}", mode);
+            assert_eq!(index.balance(4)?, 0, "{:?}", mode);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn recovers_from_a_failed_block() -> Result<(), Error> {
+        let mut chain = Chain::new(NETWORK);
+        chain.mine_empty(1, 6);
+        let node = MockNode::start(chain.clone())?;
+        let index = TestIndex::start(&node, NETWORK, StorageMode::Archive)?;
+        index.sync_to(&chain)?;
+
+        // block 7 of the fork fails while block 6 is reverted, with block 8 queued behind it
+        node.fail_once(&chain.hash(6));
+        let mut fork = chain.fork(4);
+        fork.mine_empty(2, 4);
+        node.set_chain(fork.clone());
+        index.sync_to(&fork)?;
+        assert_eq!(index.balance(1)?, 4 * REWARD);
+        assert_eq!(index.balance(2)?, 4 * REWARD);
+        Ok(())
+    }
+
+    #[test]
+    fn subscribers_follow_reorgs() -> Result<(), Error> {
+        let mut chain = Chain::new(NETWORK);
+        chain.mine_empty(1, 6);
+        let node = MockNode::start(chain.clone())?;
+        let index = TestIndex::start(&node, NETWORK, StorageMode::Archive)?;
+        index.sync_to(&chain)?;
+        let tips = index.indexer.as_ref().unwrap().subscribe();
+
+        let mut fork = chain.fork(3);
+        fork.mine_empty(2, 5);
+        node.set_chain(fork.clone());
+        let mut last = chain.height();
+        loop {
+            let tip = tips.recv_timeout(std::time::Duration::from_secs(30))?;
+            assert_eq!(tip.height, last + 1);
+            last = tip.height;
+            if last == fork.height() {
+                assert_eq!(display_hash(&tip.hash), fork.hash(last));
+                break;
+            }
+        }
+        assert_eq!(index.query().tip()?.map(|t| t.height), Some(fork.height()));
+        Ok(())
+    }
+
+    #[test]
+    fn deepest_reorg_within_rewind() -> Result<(), Error> {
+        let depth = crate::CONFIRMATIONS as u32 - 1;
+        for &mode in MODES.iter() {
+            let mut chain = Chain::new(NETWORK);
+            chain.mine_empty(1, 3);
+            let mut prev = chain.block(1).coinbase().clone();
+            // a chain of spends, each of the previous one's output, in every reverted block
+            for _ in 0..depth {
+                let tx = spend(&[outpoint(&prev, 0)], &[(2, REWARD)]);
+                chain.mine(1, vec![tx.clone()]);
+                prev = tx;
+            }
+            let node = MockNode::start(chain.clone())?;
+            let index = TestIndex::start(&node, NETWORK, mode)?;
+            index.sync_to(&chain)?;
+            assert_eq!(
+                index.utxos(2)?,
+                vec![(display_hash(&prev.txid()), 0, REWARD)]
+            );
+
+            let mut fork = chain.fork(3);
+            fork.mine_empty(3, depth + 1);
+            node.set_chain(fork.clone());
+            index.sync_to(&fork)?;
+            assert_eq!(index.balance(1)?, 3 * REWARD, "{:?}", mode);
+            assert_eq!(index.balance(2)?, 0, "{:?}", mode);
+            assert_eq!(index.balance(3)?, (depth + 1) as u64 * REWARD, "{:?}", mode);
+        }
+        Ok(())
+    }
+
+    fn get(index: &TestIndex, query: &str) -> Result<Value, Error> {
+        let res = api::handle_request(index.db.as_read(), NETWORK, &query.parse()?)?;
+        Ok(serde_json::from_str(&res.to_json()?)?)
+    }
+
+    #[test]
+    fn serves_blocks_and_headers_across_reorg() -> Result<(), Error> {
+        let mut chain = Chain::new(NETWORK);
+        chain.mine_empty(1, 11);
+        let tx = spend(&[outpoint(chain.block(1).coinbase(), 0)], &[(2, REWARD)]);
+        chain.mine(1, vec![tx.clone()]);
+        let node = MockNode::start(chain.clone())?;
+        let index = TestIndex::start(&node, NETWORK, StorageMode::Archive)?;
+        index.sync_to(&chain)?;
+
+        let block = get(&index, "/block/12")?;
+        assert_eq!(block["hash"], json!(chain.hash(12)));
+        assert_eq!(block["prev_hash"], json!(chain.hash(11)));
+        assert_eq!(
+            block["txids"],
+            json!([
+                display_hash(&chain.block(12).coinbase().txid()),
+                display_hash(&tx.txid())
+            ])
+        );
+        let by_hash = get(&index, &format!("/block/{}", chain.hash(5)))?;
+        assert_eq!(by_hash["height"], json!(5));
+        assert_eq!(
+            get(&index, "/headers?from=11&count=5")?,
+            json!([
+                hex::encode(serialize(&chain.block(11).header)),
+                hex::encode(serialize(&chain.block(12).header))
+            ])
+        );
+
+        let stale = format!("/block/{}", chain.hash(10));
+        let mut fork = chain.fork(9);
+        fork.mine_empty(3, 5);
+        node.set_chain(fork.clone());
+        index.sync_to(&fork)?;
+        assert!(api::handle_request(index.db.as_read(), NETWORK, &stale.parse()?).is_err());
+        assert_eq!(get(&index, "/block/10")?["hash"], json!(fork.hash(10)));
+        let headers = get(&index, "/headers?from=9")?;
+        assert_eq!(headers.as_array().map(|h| h.len()), Some(6));
+        Ok(())
+    }
+
+    #[test]
+    fn holds_back_immature_coinbase_outputs() -> Result<(), Error> {
+        let maturity = NETWORK.coinbase_maturity(1);
+        let mut chain = Chain::new(NETWORK);
+        chain.mine_empty(1, maturity);
+        let node = MockNode::start(chain.clone())?;
+        let index = TestIndex::start(&node, NETWORK, StorageMode::Archive)?;
+        index.sync_to(&chain)?;
+
+        // only the first coinbase can be spent in the next block
+        let miner = address(NETWORK, 1);
+        let balance = index.query().balance(&miner)?;
+        assert_eq!(balance.mature, REWARD);
+        assert_eq!(balance.immature, (maturity as u64 - 1) * REWARD);
+        let spendable = index
+            .query()
+            .utxos(&miner, std::u64::MAX, Some(std::usize::MAX), false)?;
+        assert_eq!(spendable.len(), 1);
+        assert_eq!(spendable[0].txid, chain.block(1).coinbase().txid());
+        let all = index
+            .query()
+            .utxos(&miner, std::u64::MAX, Some(std::usize::MAX), true)?;
+        assert_eq!(
+            all.iter().filter(|u| u.immature).count(),
+            maturity as usize - 1
+        );
+        let utxos = get(&index, &format!("/utxos?address={}&amount=1", miner))?;
+        assert_eq!(utxos.as_array().map(|u| u.len()), Some(1));
+        assert_eq!(utxos[0]["immature"], json!(false));
+        let query = format!(
+            "/utxos?address={}&amount={}&includeImmature=true",
+            miner,
+            std::u64::MAX
+        );
+        let bytes = api::handle_request(index.db.as_read(), NETWORK, &query.parse()?)?.to_bytes();
+        let mut pos = 8;
+        let mut marks = Vec::new();
+        for height in 1..=maturity {
+            pos += 32 + 4 + 8;
+            marks.push(bytes[pos]);
+            pos += 1 + serialize(chain.block(height).coinbase()).len();
+        }
+        assert_eq!(pos, bytes.len());
+        assert_eq!(marks[0], 0);
+        assert!(marks[1..].iter().all(|&m| m == 1));
+
+        chain.mine_empty(1, 1);
+        node.set_chain(chain.clone());
+        index.sync_to(&chain)?;
+        assert_eq!(index.query().balance(&miner)?.mature, 2 * REWARD);
+        Ok(())
+    }
+
+    #[test]
+    fn reindex_restores_immature_coinbase_heights() -> Result<(), Error> {
+        let maturity = NETWORK.coinbase_maturity(1);
+        for &mode in MODES.iter() {
+            let mut chain = Chain::new(NETWORK);
+            chain.mine_empty(1, maturity);
+            let coinbase = chain.block(1).coinbase().clone();
+            chain.mine(1, vec![spend(&[outpoint(&coinbase, 0)], &[(2, REWARD)])]);
+            let node = MockNode::start(chain.clone())?;
+            let mut index = TestIndex::start(&node, NETWORK, mode)?;
+            index.sync_to(&chain)?;
+            index.indexer.take().unwrap().stop();
+
+            // the first coinbase comes back 29 blocks deep, which is still immature
+            let pool = Pool::new(NETWORK, vec![node.config()])?;
+            pool.check();
+            super::reindex(&pool, &*index.db, 30, mode, &Shutdown::default())?;
+            let balance = index.query().balance(&address(NETWORK, 1))?;
+            assert_eq!(balance.mature, 0, "{:?}", mode);
+            assert_eq!(balance.immature, 29 * REWARD, "{:?}", mode);
+
+            index.restart(&node)?;
+            index.sync_to(&chain)?;
+            assert_eq!(index.balance(2)?, REWARD, "{:?}", mode);
+        }
+        Ok(())
+    }
+
+    fn borker_payload(kind: Kind, reference: Option<&Transaction>, content: &[u8]) -> Vec<u8> {
+        let mut payload = borker::MAGIC.to_vec();
+        payload.extend(&[kind as u8, 0]);
+        if let Some(tx) = reference {
+            payload.extend(hex::decode(display_hash(&tx.txid())).unwrap());
+        }
+        payload.extend(content);
+        payload
+    }
+
+    #[test]
+    fn indexes_borker_feeds_across_reorg() -> Result<(), Error> {
+        let mut chain = Chain::new(NETWORK);
+        chain.mine_empty(1, 3);
+        chain.mine_empty(2, 1);
+        let mut bork = spend(&[outpoint(chain.block(1).coinbase(), 0)], &[(1, REWARD)]);
+        bork.output
+            .push(op_return(&borker_payload(Kind::Bork, None, b"such wow")));
+        chain.mine(3, vec![bork.clone()]);
+        let mut comment = spend(&[outpoint(chain.block(4).coinbase(), 0)], &[(2, REWARD)]);
+        comment.output.push(op_return(&borker_payload(
+            Kind::Comment,
+            Some(&bork),
+            b"much reply",
+        )));
+        let mut like = spend(&[outpoint(chain.block(2).coinbase(), 0)], &[(1, REWARD)]);
+        like.output
+            .push(op_return(&borker_payload(Kind::Like, Some(&bork), b"")));
+        chain.mine(3, vec![comment.clone(), like.clone()]);
+        let node = MockNode::start(chain.clone())?;
+        let index = TestIndex::start(&node, NETWORK, StorageMode::Archive)?;
+        index.sync_to(&chain)?;
+
+        let txids = |feed: &Value| -> Vec<String> {
+            let mut txids: Vec<_> = feed
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|m| m["txid"].as_str().unwrap().to_owned())
+                .collect();
+            txids.sort();
+            txids
+        };
+        let sorted = |txs: &[&Transaction]| -> Vec<String> {
+            let mut txids: Vec<_> = txs.iter().map(|tx| display_hash(&tx.txid())).collect();
+            txids.sort();
+            txids
+        };
+        let posts = get(
+            &index,
+            &format!("/borker/posts?address={}", address(NETWORK, 1)),
+        )?;
+        assert_eq!(posts[0]["kind"], json!("like"));
+        assert_eq!(posts[1]["content"], json!("such wow"));
+        let replies = format!("/borker/replies?txid={}", display_hash(&bork.txid()));
+        assert_eq!(txids(&get(&index, &replies)?), sorted(&[&comment, &like]));
+        let timeline = get(&index, "/borker/timeline")?;
+        assert_eq!(timeline[0]["author"], json!(address(NETWORK, 2)));
+        assert_eq!(timeline[0]["reference"], json!(display_hash(&bork.txid())));
+        assert_eq!(txids(&timeline), sorted(&[&bork, &comment]));
+        let before = format!("/borker/timeline?before={}", chain.block(6).header.time);
+        assert_eq!(txids(&get(&index, &before)?), sorted(&[&bork]));
+        // the comment and the like share a block, so the second page starts inside it
+        let first = get(&index, &format!("{}&limit=1", replies))?;
+        let next = format!(
+            "{}&limit=1&before={}&afterTxid={}",
+            replies,
+            first[0]["time"],
+            first[0]["txid"].as_str().unwrap()
+        );
+        let second = get(&index, &next)?;
+        let mut pages = txids(&first);
+        pages.extend(txids(&second));
+        pages.sort();
+        assert_eq!(pages, sorted(&[&comment, &like]));
+        let last = format!(
+            "{}&before={}&afterTxid={}",
+            replies,
+            second[0]["time"],
+            second[0]["txid"].as_str().unwrap()
+        );
+        assert_eq!(txids(&get(&index, &last)?), sorted(&[]));
+
+        let mut fork = chain.fork(5);
+        fork.mine_empty(3, 2);
+        node.set_chain(fork.clone());
+        index.sync_to(&fork)?;
+        assert_eq!(txids(&get(&index, &replies)?), sorted(&[]));
+        assert_eq!(txids(&get(&index, "/borker/timeline")?), sorted(&[&bork]));
+        let posts = get(
+            &index,
+            &format!("/borker/posts?address={}", address(NETWORK, 1)),
+        )?;
+        assert_eq!(txids(&posts), sorted(&[&bork]));
+        Ok(())
+    }
+
+    // Mines `height` good blocks and one more changed by `tamper`, which must halt indexing.
+    fn halts_on(height: u32, tamper: impl FnOnce(&mut BlockHeader)) -> Result<(), Error> {
+        let mut chain = Chain::new(NETWORK);
+        chain.mine_empty(1, height);
+        let good = chain.clone();
+        chain.mine_with(1, Vec::new(), tamper);
+        let node = MockNode::start(chain)?;
+        let index = TestIndex::start(&node, NETWORK, StorageMode::Archive)?;
+        index.sync_to(&good)?;
+        index.wait_halted()?;
+        assert_eq!(index.query().tip()?.map(|t| t.height), Some(height));
+        assert_eq!(index.balance(1)?, height as u64 * REWARD);
+        Ok(())
+    }
+
+    #[test]
+    fn halts_on_wrong_merkle_root() -> Result<(), Error> {
+        halts_on(12, |h| h.merkle_root = sha256d::Hash::hash(b"other"))?;
+        halts_on(NETWORK.auxpow_height(), |h| {
+            h.merkle_root = sha256d::Hash::hash(b"other")
+        })
+    }
+
+    #[test]
+    fn halts_on_time_before_median() -> Result<(), Error> {
+        halts_on(12, |h| h.time -= 10 * 60)
+    }
+
+    #[test]
+    fn halts_on_unexpected_bits() -> Result<(), Error> {
+        halts_on(12, |h| h.bits = 0x207f_ff00)
+    }
+}
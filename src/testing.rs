@@ -0,0 +1,542 @@
+// A mock dogecoind and synthetic chains to run the indexer against in tests.
+
+use crate::auxpow::{self, AuxPow, CHAIN_ID, MERGED_MINING_HEADER, VERSION_AUXPOW};
+use crate::cache::{CacheConfig, RpcCache};
+use crate::codec::Encoding;
+use crate::indexer::{Indexer, SyncConfig};
+use crate::network::Network;
+use crate::query::{Query, Tip};
+use crate::shutdown::Shutdown;
+use crate::store::{MemStore, Store};
+use crate::upstream::{NodeConfig, Pool};
+use crate::utxo::StorageMode;
+use bitcoin::consensus::encode::{serialize, VarInt};
+use bitcoin::util::hash::{bitcoin_merkle_root, BitcoinHash};
+use bitcoin::{BlockHeader, OutPoint, Script, Transaction, TxIn, TxOut};
+use bitcoin_hashes::{sha256d, Hash};
+use failure::Error;
+use futures::{Future, Stream};
+use hyper::service::service_fn;
+use hyper::{Body, Request, Response, Server};
+use parking_lot::{Mutex, RwLock};
+use serde_json::{json, Value};
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+pub const COIN: u64 = 100_000_000;
+pub const REWARD: u64 = 10_000 * COIN;
+
+// Mixed into every block, so that blocks mined on competing branches never share a hash.
+static NONCE: AtomicUsize = AtomicUsize::new(0);
+
+// Hashes and txids as dogecoind reports them: the double sha256 reversed.
+pub fn display_hash(hash: &sha256d::Hash) -> String {
+    let mut buf = hash[..].to_vec();
+    buf.reverse();
+    hex::encode(buf)
+}
+
+fn from_display(hash: &str) -> sha256d::Hash {
+    let mut buf = hex::decode(hash).unwrap();
+    buf.reverse();
+    sha256d::Hash::from_slice(&buf).unwrap()
+}
+
+// Keys are numbered; key `n` has the pubkey hash [n; 20].
+pub fn p2pkh(n: u8) -> Script {
+    let mut script = vec![0x76, 0xa9, 0x14];
+    script.extend(&[n; 20]);
+    script.extend(&[0x88, 0xac]);
+    Script::from(script)
+}
+
+pub fn address(network: Network, n: u8) -> String {
+    let mut buf = vec![network.p2pkh()];
+    buf.extend(&[n; 20]);
+    bitcoin::util::base58::check_encode_slice(&buf)
+}
+
+pub fn outpoint(tx: &Transaction, vout: u32) -> OutPoint {
+    OutPoint {
+        txid: tx.txid(),
+        vout,
+    }
+}
+
+// Spends `inputs` to `(key, value)` outputs. Nothing checks signatures or amounts.
+pub fn spend(inputs: &[OutPoint], outputs: &[(u8, u64)]) -> Transaction {
+    Transaction {
+        version: 1,
+        lock_time: 0,
+        input: inputs
+            .iter()
+            .map(|&previous_output| TxIn {
+                previous_output,
+                script_sig: Script::from(vec![0x51]),
+                sequence: 0xffff_ffff,
+                witness: Vec::new(),
+            })
+            .collect(),
+        output: outputs
+            .iter()
+            .map(|&(key, value)| TxOut {
+                value,
+                script_pubkey: p2pkh(key),
+            })
+            .collect(),
+    }
+}
+
+// A pay-to-pubkey output, which has no address.
+pub fn p2pk(n: u8, value: u64) -> TxOut {
+    let mut script = vec![0x21, 0x02];
+    script.extend(&[n; 32]);
+    script.push(0xac);
+    TxOut {
+        value,
+        script_pubkey: Script::from(script),
+    }
+}
+
+pub fn op_return(payload: &[u8]) -> TxOut {
+    let mut script = vec![0x6a, payload.len() as u8];
+    script.extend(payload);
+    TxOut {
+        value: 0,
+        script_pubkey: Script::from(script),
+    }
+}
+
+#[derive(Clone)]
+pub struct TestBlock {
+    pub hash: String,
+    pub header: BlockHeader,
+    pub auxpow: Option<AuxPow>,
+    pub txs: Vec<Transaction>,
+    pub raw: Vec<u8>,
+}
+
+impl TestBlock {
+    pub fn coinbase(&self) -> &Transaction {
+        &self.txs[0]
+    }
+}
+
+// Merge mined by a parent chain block whose coinbase commits to `header` directly, with empty
+// merkle branches, and whose nonce is ground until it meets `header`'s target.
+pub fn auxpow(header: &BlockHeader) -> AuxPow {
+    let mut hash = header.bitcoin_hash()[..].to_vec();
+    hash.reverse();
+    let mut script = MERGED_MINING_HEADER.to_vec();
+    script.extend(hash);
+    script.extend(&1_u32.to_le_bytes());
+    script.extend(&0_u32.to_le_bytes());
+    let coinbase = Transaction {
+        version: 1,
+        lock_time: 0,
+        input: vec![TxIn {
+            previous_output: OutPoint::null(),
+            script_sig: Script::from(script),
+            sequence: 0xffff_ffff,
+            witness: Vec::new(),
+        }],
+        output: vec![TxOut {
+            value: 0,
+            script_pubkey: Script::new(),
+        }],
+    };
+    let mut parent = BlockHeader {
+        version: 2,
+        prev_blockhash: sha256d::Hash::default(),
+        merkle_root: coinbase.txid(),
+        time: header.time,
+        bits: header.bits,
+        nonce: 0,
+    };
+    while !auxpow::meets_target(&auxpow::pow_hash(&parent), header.bits) {
+        parent.nonce += 1;
+    }
+    AuxPow {
+        coinbase,
+        parent_hash: parent.bitcoin_hash(),
+        coinbase_branch: Vec::new(),
+        coinbase_index: 0,
+        chain_branch: Vec::new(),
+        chain_index: 0,
+        parent,
+    }
+}
+
+// A raw block as dogecoind serves it.
+pub fn raw_block(header: &BlockHeader, auxpow: Option<&AuxPow>, txs: &[Transaction]) -> Vec<u8> {
+    let mut raw = serialize(header);
+    if let Some(auxpow) = auxpow {
+        raw.extend(auxpow.encode());
+    }
+    raw.extend(serialize(&VarInt(txs.len() as u64)));
+    for tx in txs.iter() {
+        raw.extend(serialize(tx));
+    }
+    raw
+}
+
+#[derive(Clone)]
+pub struct Chain {
+    network: Network,
+    // blocks[0] is at height 1
+    blocks: Vec<TestBlock>,
+}
+
+impl Chain {
+    pub fn new(network: Network) -> Self {
+        Chain {
+            network,
+            blocks: Vec::new(),
+        }
+    }
+
+    pub fn height(&self) -> u32 {
+        self.blocks.len() as u32
+    }
+
+    pub fn block(&self, height: u32) -> &TestBlock {
+        &self.blocks[height as usize - 1]
+    }
+
+    pub fn hash(&self, height: u32) -> String {
+        match height {
+            0 => self.network.genesis_hash().to_owned(),
+            h => self.block(h).hash.clone(),
+        }
+    }
+
+    // The chain up to `height`, to mine a competing branch on.
+    pub fn fork(&self, height: u32) -> Chain {
+        Chain {
+            network: self.network,
+            blocks: self.blocks[..height as usize].to_vec(),
+        }
+    }
+
+    // Mines a block whose coinbase pays the reward to `miner`, followed by `txs`. Blocks from
+    // the network's auxpow height on are merge mined.
+    pub fn mine(&mut self, miner: u8, txs: Vec<Transaction>) -> &TestBlock {
+        self.mine_with(miner, txs, |_| ())
+    }
+
+    // Like `mine`, with the header changed by `tamper` before its proof of work is found.
+    pub fn mine_with(
+        &mut self,
+        miner: u8,
+        txs: Vec<Transaction>,
+        tamper: impl FnOnce(&mut BlockHeader),
+    ) -> &TestBlock {
+        let height = self.height() + 1;
+        let nonce = NONCE.fetch_add(1, Ordering::SeqCst) as u32;
+        let mut script_sig = vec![4];
+        script_sig.extend(&height.to_le_bytes());
+        script_sig.push(4);
+        script_sig.extend(&nonce.to_le_bytes());
+        let coinbase = Transaction {
+            version: 1,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: OutPoint::null(),
+                script_sig: Script::from(script_sig),
+                sequence: 0xffff_ffff,
+                witness: Vec::new(),
+            }],
+            output: vec![TxOut {
+                value: REWARD,
+                script_pubkey: p2pkh(miner),
+            }],
+        };
+        let txs: Vec<_> = std::iter::once(coinbase).chain(txs).collect();
+        let merged = height >= self.network.auxpow_height();
+        let mut header = BlockHeader {
+            // chain id 0x62, with the auxpow flag once merge mining is active
+            version: if merged {
+                CHAIN_ID << 16 | VERSION_AUXPOW | 2
+            } else {
+                CHAIN_ID << 16 | 2
+            },
+            prev_blockhash: from_display(&self.hash(height - 1)),
+            merkle_root: bitcoin_merkle_root(txs.iter().map(|tx| tx.txid()).collect()),
+            time: 1_500_000_000 + height * 60,
+            bits: 0x207f_ffff,
+            nonce: 0,
+        };
+        tamper(&mut header);
+        let auxpow = if merged { Some(auxpow(&header)) } else { None };
+        while !merged && !auxpow::meets_target(&auxpow::pow_hash(&header), header.bits) {
+            header.nonce += 1;
+        }
+        let raw = raw_block(&header, auxpow.as_ref(), &txs);
+        self.blocks.push(TestBlock {
+            hash: display_hash(&header.bitcoin_hash()),
+            header,
+            auxpow,
+            txs,
+            raw,
+        });
+        self.blocks.last().unwrap()
+    }
+
+    pub fn mine_empty(&mut self, miner: u8, count: u32) {
+        for _ in 0..count {
+            self.mine(miner, Vec::new());
+        }
+    }
+}
+
+#[derive(Default)]
+struct NodeState {
+    chain: Option<Chain>,
+    // every block and transaction ever served, like the stale blocks a node keeps after a reorg
+    blocks: HashMap<String, Vec<u8>>,
+    txs: HashMap<String, Vec<u8>>,
+    // blocks whose next getblock fails
+    failing: Mutex<HashSet<String>>,
+}
+
+impl NodeState {
+    fn call(&self, call: &Value) -> Value {
+        let chain = self.chain.as_ref().unwrap();
+        let params = call["params"].as_array().cloned().unwrap_or_default();
+        let result = match call["method"].as_str().unwrap_or("") {
+            "getblockcount" => Ok(json!(chain.height())),
+            "getblockhash" => match params.get(0).and_then(|h| h.as_u64()) {
+                Some(h) if h <= chain.height() as u64 => Ok(json!(chain.hash(h as u32))),
+                _ => Err((-8, "Block height out of range")),
+            },
+            "getblock" => match (params.get(0).and_then(|h| h.as_str()), params.get(1)) {
+                (_, Some(v)) if v != &json!(false) && v != &json!(0) => {
+                    Err((-8, "only raw blocks are supported"))
+                }
+                (Some(hash), _) if self.failing.lock().remove(hash) => {
+                    Err((-1, "temporary failure"))
+                }
+                (Some(hash), _) => self
+                    .blocks
+                    .get(hash)
+                    .map(|b| json!(hex::encode(b)))
+                    .ok_or((-5, "Block not found")),
+                (None, _) => Err((-8, "missing block hash")),
+            },
+            "getrawtransaction" => params
+                .get(0)
+                .and_then(|t| t.as_str())
+                .and_then(|t| self.txs.get(t))
+                .map(|t| json!(hex::encode(t)))
+                .ok_or((-5, "No such mempool or blockchain transaction")),
+            _ => Err((-32601, "Method not found")),
+        };
+        match result {
+            Ok(result) => json!({ "result": result, "error": null, "id": call["id"] }),
+            Err((code, message)) => json!({
+                "result": null,
+                "error": { "code": code, "message": message },
+                "id": call["id"],
+            }),
+        }
+    }
+
+    fn handle(&self, body: &[u8]) -> Value {
+        match serde_json::from_slice(body) {
+            Ok(Value::Array(calls)) => calls.iter().map(|c| self.call(c)).collect(),
+            Ok(call) => self.call(&call),
+            Err(e) => json!({
+                "result": null,
+                "error": { "code": -32700, "message": e.to_string() },
+                "id": null,
+            }),
+        }
+    }
+}
+
+// Serves getblockcount, getblockhash, getblock and getrawtransaction for a chain, on a local
+// port, until dropped.
+pub struct MockNode {
+    pub uri: String,
+    state: Arc<RwLock<NodeState>>,
+    runtime: Option<tokio::runtime::Runtime>,
+}
+
+impl MockNode {
+    pub fn start(chain: Chain) -> Result<Self, Error> {
+        let state = Arc::new(RwLock::new(NodeState::default()));
+        let s = state.clone();
+        let server = Server::try_bind(&([127, 0, 0, 1], 0).into())?.serve(move || {
+            let s = s.clone();
+            service_fn(move |req: Request<Body>| {
+                let s = s.clone();
+                req.into_body()
+                    .concat2()
+                    .map(move |body| Response::new(Body::from(s.read().handle(&body).to_string())))
+            })
+        });
+        let uri = format!("http://{}", server.local_addr());
+        let mut runtime = tokio::runtime::Runtime::new()?;
+        runtime.spawn(server.map_err(|e| error!("mock node: {}", e)));
+        let node = MockNode {
+            uri,
+            state,
+            runtime: Some(runtime),
+        };
+        node.set_chain(chain);
+        Ok(node)
+    }
+
+    // Switches the node to `chain`, as a reorg would. Blocks of the chains it served before
+    // can still be fetched by hash.
+    pub fn set_chain(&self, chain: Chain) {
+        let mut state = self.state.write();
+        for block in chain.blocks.iter() {
+            state.blocks.insert(block.hash.clone(), block.raw.clone());
+            for tx in block.txs.iter() {
+                state.txs.insert(display_hash(&tx.txid()), serialize(tx));
+            }
+        }
+        state.chain = Some(chain);
+    }
+
+    // Makes the next getblock of the block at `hash` fail.
+    pub fn fail_once(&self, hash: &str) {
+        self.state.read().failing.lock().insert(hash.to_owned());
+    }
+
+    pub fn config(&self) -> NodeConfig {
+        NodeConfig {
+            uri: self.uri.clone(),
+            user: None,
+            password: None,
+        }
+    }
+}
+
+impl Drop for MockNode {
+    fn drop(&mut self) {
+        if let Some(runtime) = self.runtime.take() {
+            let _ = runtime.shutdown_now().wait();
+        }
+    }
+}
+
+// Runs an indexer against a node, validating headers and indexing into memory, until dropped.
+pub struct TestIndex {
+    pub network: Network,
+    pub db: Arc<dyn Store>,
+    pub indexer: Option<Indexer>,
+    mode: StorageMode,
+}
+
+fn start_indexer(
+    db: &Arc<dyn Store>,
+    node: &MockNode,
+    network: Network,
+    mode: StorageMode,
+) -> Result<Indexer, Error> {
+    let pool = Arc::new(Pool::new(network, vec![node.config()])?);
+    pool.check();
+    Ok(Indexer::start(
+        db.clone(),
+        pool,
+        Arc::new(RpcCache::new(CacheConfig::default())),
+        SyncConfig {
+            mode,
+            validate_headers: true,
+        },
+        Shutdown::default(),
+    ))
+}
+
+impl TestIndex {
+    pub fn start(node: &MockNode, network: Network, mode: StorageMode) -> Result<Self, Error> {
+        let db: Arc<dyn Store> = Arc::new(MemStore::new());
+        network.check_db(&*db, true)?;
+        Encoding::init(&*db, Encoding::default())?;
+        crate::history::init(&*db)?;
+        let indexer = start_indexer(&db, node, network, mode)?;
+        Ok(TestIndex {
+            network,
+            db,
+            indexer: Some(indexer),
+            mode,
+        })
+    }
+
+    // Stops the indexer and starts another on the same store, as a restarted process would.
+    pub fn restart(&mut self, node: &MockNode) -> Result<(), Error> {
+        if let Some(indexer) = self.indexer.take() {
+            indexer.stop();
+        }
+        self.indexer = Some(start_indexer(&self.db, node, self.network, self.mode)?);
+        Ok(())
+    }
+
+    pub fn query(&self) -> Query {
+        Query::new(&*self.db, self.network)
+    }
+
+    // Waits for the index to reach the tip of `chain`.
+    pub fn sync_to(&self, chain: &Chain) -> Result<(), Error> {
+        let expected = Some(Tip {
+            height: chain.height(),
+            hash: from_display(&chain.hash(chain.height())),
+        });
+        let start = Instant::now();
+        loop {
+            let tip = self.query().tip()?;
+            if tip == expected {
+                return Ok(());
+            }
+            if start.elapsed() > Duration::from_secs(30) {
+                bail!("index stuck at {:?}, expected {:?}", tip, expected);
+            }
+            std::thread::sleep(Duration::from_millis(50));
+        }
+    }
+
+    // Waits for the indexer to halt, as it does on an invalid block.
+    pub fn wait_halted(&self) -> Result<(), Error> {
+        let start = Instant::now();
+        while self.indexer.as_ref().unwrap().is_running() {
+            if start.elapsed() > Duration::from_secs(30) {
+                bail!("indexer still running");
+            }
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        Ok(())
+    }
+
+    pub fn balance(&self, key: u8) -> Result<u64, Error> {
+        Ok(self.query().balance(&address(self.network, key))?.total())
+    }
+
+    // Every utxo of `key`, as (txid, vout, value) with the txid as dogecoind shows it.
+    pub fn utxos(&self, key: u8) -> Result<Vec<(String, u32, u64)>, Error> {
+        let mut utxos: Vec<_> = self
+            .query()
+            .utxos(
+                &address(self.network, key),
+                std::u64::MAX,
+                Some(std::usize::MAX),
+                true,
+            )?
+            .into_iter()
+            .map(|u| (display_hash(&u.txid), u.vout, u.value))
+            .collect();
+        utxos.sort();
+        Ok(utxos)
+    }
+}
+
+impl Drop for TestIndex {
+    fn drop(&mut self) {
+        if let Some(indexer) = self.indexer.take() {
+            indexer.stop();
+        }
+    }
+}
@@ -0,0 +1,201 @@
+use crate::server::{self, Context, Peer};
+use crate::shutdown::Shutdown;
+use bitcoin_hashes::{sha256, Hash};
+use failure::Error;
+use futures::future::{self, Either};
+use futures::sync::mpsc;
+use futures::{Future, Stream};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Server};
+use parking_lot::RwLock;
+use rustls::internal::pemfile;
+use rustls::{AllowAnyAuthenticatedClient, NoClientAuth, RootCertStore, ServerConfig, Session};
+use std::fs::File;
+use std::io::BufReader;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::timer::{Delay, Timeout};
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+
+fn default_bind() -> SocketAddr {
+    ([0, 0, 0, 0], 11022).into()
+}
+
+fn default_reload_interval() -> u64 {
+    60
+}
+
+fn default_handshake_timeout() -> u64 {
+    10
+}
+
+// How long to wait after a failed accept, as hyper does, so that running out of file
+// descriptors does not spin.
+const ACCEPT_ERROR_DELAY: Duration = Duration::from_millis(100);
+
+#[derive(Clone, Deserialize)]
+pub struct TlsConfig {
+    #[serde(default = "default_bind")]
+    pub bind: SocketAddr,
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    #[serde(default)]
+    pub client_ca: Option<PathBuf>,
+    #[serde(default = "default_reload_interval")]
+    pub reload_interval: u64,
+    #[serde(default = "default_handshake_timeout")]
+    pub handshake_timeout: u64,
+}
+impl TlsConfig {
+    fn files(&self) -> impl Iterator<Item = &PathBuf> {
+        std::iter::once(&self.cert)
+            .chain(std::iter::once(&self.key))
+            .chain(self.client_ca.iter())
+    }
+
+    fn modified(&self) -> Vec<Option<SystemTime>> {
+        self.files()
+            .map(|p| std::fs::metadata(p).and_then(|m| m.modified()).ok())
+            .collect()
+    }
+
+    fn load(&self) -> Result<ServerConfig, Error> {
+        fn open(path: &Path) -> Result<BufReader<File>, Error> {
+            Ok(BufReader::new(
+                File::open(path).map_err(|e| format_err!("{}: {}", path.display(), e))?,
+            ))
+        }
+        let certs = pemfile::certs(&mut open(&self.cert)?)
+            .map_err(|_| format_err!("invalid certificate: {}", self.cert.display()))?;
+        let mut keys = pemfile::pkcs8_private_keys(&mut open(&self.key)?)
+            .map_err(|_| format_err!("invalid private key: {}", self.key.display()))?;
+        if keys.is_empty() {
+            keys = pemfile::rsa_private_keys(&mut open(&self.key)?)
+                .map_err(|_| format_err!("invalid private key: {}", self.key.display()))?;
+        }
+        let key = keys
+            .into_iter()
+            .next()
+            .ok_or(format_err!("no private key in {}", self.key.display()))?;
+        let verifier = match self.client_ca {
+            Some(ref ca) => {
+                let mut roots = RootCertStore::empty();
+                roots
+                    .add_pem_file(&mut open(ca)?)
+                    .map_err(|_| format_err!("invalid client ca: {}", ca.display()))?;
+                AllowAnyAuthenticatedClient::new(roots)
+            }
+            None => NoClientAuth::new(),
+        };
+        let mut config = ServerConfig::new(verifier);
+        config.set_single_cert(certs, key)?;
+        Ok(config)
+    }
+}
+
+pub struct Acceptor {
+    conf: TlsConfig,
+    current: RwLock<Arc<ServerConfig>>,
+}
+
+impl Acceptor {
+    pub fn new(conf: TlsConfig) -> Result<Arc<Self>, Error> {
+        let current = RwLock::new(Arc::new(conf.load()?));
+        Ok(Arc::new(Acceptor { conf, current }))
+    }
+
+    // Polls the certificate, key and client ca files, and swaps in a new configuration when
+    // any of them changes. New connections use it; established ones are unaffected.
+    pub fn spawn_reload(self: Arc<Self>) -> std::thread::JoinHandle<()> {
+        std::thread::spawn(move || {
+            let mut modified = self.conf.modified();
+            loop {
+                std::thread::sleep(Duration::from_secs(self.conf.reload_interval));
+                let new_modified = self.conf.modified();
+                if new_modified == modified {
+                    continue;
+                }
+                match self.conf.load() {
+                    Ok(config) => {
+                        info!("reloaded tls certificates");
+                        *self.current.write() = Arc::new(config);
+                        modified = new_modified;
+                    }
+                    Err(e) => error!("failed to reload tls certificates: {}", e),
+                }
+            }
+        })
+    }
+
+    fn acceptor(&self) -> TlsAcceptor {
+        TlsAcceptor::from(self.current.read().clone())
+    }
+}
+
+pub fn serve(
+    acceptor: Arc<Acceptor>,
+    ctx: Arc<Context>,
+    shutdown: &Shutdown,
+) -> Result<impl Future<Item = (), Error = ()>, Error> {
+    let listener = TcpListener::bind(&acceptor.conf.bind)?;
+    let handshake_timeout = Duration::from_secs(acceptor.conf.handshake_timeout);
+    // each handshake runs in its own task and hands the stream over once it completes, so that
+    // slow or idle clients cannot hold up the others
+    let (streams, incoming) = mpsc::unbounded();
+    let accept = listener
+        .incoming()
+        .then(|res| match res {
+            Ok(stream) => Either::A(future::ok(Some(stream))),
+            Err(e) => {
+                warn!("tls accept failed: {}", e);
+                Either::B(
+                    Delay::new(Instant::now() + ACCEPT_ERROR_DELAY)
+                        .then(|_| Ok::<_, std::io::Error>(None)),
+                )
+            }
+        })
+        .filter_map(|s| s)
+        .for_each(move |stream| {
+            let streams = streams.clone();
+            let handshake = Timeout::new(acceptor.acceptor().accept(stream), handshake_timeout)
+                .then(move |res| {
+                    match res {
+                        Ok(stream) => {
+                            let _ = streams.unbounded_send(stream);
+                        }
+                        Err(e) => debug!("tls handshake failed: {}", e),
+                    }
+                    Ok(())
+                });
+            tokio::spawn(handshake);
+            Ok(())
+        })
+        .map_err(|e| error!("https listener error: {}", e))
+        .select(shutdown.wait())
+        .map(|_| ())
+        .map_err(|_| ());
+    let incoming = incoming.map_err(|()| -> std::io::Error { unreachable!() });
+    let make_service = make_service_fn(move |conn: &TlsStream<TcpStream>| {
+        let (stream, session) = conn.get_ref();
+        let peer = Peer {
+            addr: stream
+                .peer_addr()
+                .unwrap_or_else(|_| ([0, 0, 0, 0], 0).into()),
+            certificate: session
+                .get_peer_certificates()
+                .and_then(|certs| certs.into_iter().next())
+                .map(|cert| hex::encode(&sha256::Hash::hash(&cert.0)[..])),
+        };
+        let ctx = ctx.clone();
+        service_fn(move |req: Request<Body>| server::handle(&ctx, peer.clone(), req))
+    });
+    let server = Server::builder(incoming)
+        .serve(make_service)
+        .with_graceful_shutdown(shutdown.wait())
+        .map_err(|e| error!("https server error: {}", e));
+    Ok(server.join(accept).map(|_| ()))
+}
@@ -0,0 +1,191 @@
+use crate::network::Network;
+use crate::shutdown::Shutdown;
+use failure::Error;
+use hyper::header::HeaderValue;
+use parking_lot::RwLock;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use throttled_bitcoin_rpc::BitcoinRpcClient;
+
+#[derive(Clone, Deserialize)]
+pub struct NodeConfig {
+    pub uri: String,
+    #[serde(default)]
+    pub user: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+}
+
+#[derive(Clone, Default)]
+pub struct NodeStatus {
+    pub healthy: bool,
+    pub height: u32,
+    pub tip: String,
+}
+
+pub struct Node {
+    pub id: usize,
+    pub uri: hyper::Uri,
+    pub auth: Option<HeaderValue>,
+    pub client: Arc<BitcoinRpcClient>,
+    status: RwLock<NodeStatus>,
+}
+impl Node {
+    fn new(id: usize, conf: NodeConfig) -> Result<Self, Error> {
+        let auth = match conf.user {
+            Some(ref u) => Some(HeaderValue::from_str(&format!(
+                "Basic {}",
+                base64::encode(&format!(
+                    "{}:{}",
+                    u,
+                    conf.password.as_ref().map(|p| p.as_str()).unwrap_or("")
+                ))
+            ))?),
+            None => None,
+        };
+        Ok(Node {
+            id,
+            uri: hyper::Uri::from_str(&conf.uri)?,
+            auth,
+            client: BitcoinRpcClient::new(conf.uri, conf.user, conf.password, 0, 0, 0),
+            status: RwLock::new(NodeStatus {
+                healthy: true,
+                ..Default::default()
+            }),
+        })
+    }
+
+    pub fn status(&self) -> NodeStatus {
+        self.status.read().clone()
+    }
+
+    fn check(&self, network: Network) -> Result<NodeStatus, Error> {
+        let genesis = self.client.getblockhash(0)?;
+        if genesis != network.genesis_hash() {
+            bail!("genesis {} is not {:?}", genesis, network);
+        }
+        let height = self.client.getblockcount()?;
+        let tip = self.client.getblockhash(height)?;
+        Ok(NodeStatus {
+            healthy: true,
+            height,
+            tip,
+        })
+    }
+}
+
+pub struct Pool {
+    network: Network,
+    nodes: Vec<Arc<Node>>,
+    active: AtomicUsize,
+}
+
+impl Pool {
+    pub fn new(network: Network, confs: Vec<NodeConfig>) -> Result<Self, Error> {
+        if confs.is_empty() {
+            bail!("no upstream nodes configured");
+        }
+        Ok(Pool {
+            network,
+            nodes: confs
+                .into_iter()
+                .enumerate()
+                .map(|(id, conf)| Node::new(id, conf).map(Arc::new))
+                .collect::<Result<_, _>>()?,
+            active: AtomicUsize::new(0),
+        })
+    }
+
+    pub fn network(&self) -> Network {
+        self.network
+    }
+
+    pub fn nodes(&self) -> &[Arc<Node>] {
+        &self.nodes
+    }
+
+    pub fn active(&self) -> Arc<Node> {
+        self.nodes[self.active.load(Ordering::SeqCst)].clone()
+    }
+
+    pub fn mark_failed(&self, node: &Node) {
+        crate::metrics::UPSTREAM_ERRORS
+            .with_label_values(&[&node.id.to_string()])
+            .inc();
+        node.status.write().healthy = false;
+        if self.active.load(Ordering::SeqCst) == node.id {
+            self.elect();
+        }
+    }
+
+    // Stays on the active node unless another healthy node is strictly ahead of it,
+    // so that ties between nodes on different tips do not flap the selection.
+    fn elect(&self) {
+        let current = self.active.load(Ordering::SeqCst);
+        let current_status = self.nodes[current].status();
+        let best = self
+            .nodes
+            .iter()
+            .map(|n| (n.id, n.status()))
+            .filter(|(_, s)| s.healthy)
+            .max_by_key(|(id, s)| (s.height, *id == current));
+        match best {
+            Some((id, ref s)) if id != current => {
+                if !current_status.healthy || s.height > current_status.height {
+                    warn!("switching upstream node {} -> {}", current, id);
+                    self.active.store(id, Ordering::SeqCst);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    pub fn check(&self) {
+        for node in self.nodes.iter() {
+            let status = match node.check(self.network) {
+                Ok(a) => a,
+                Err(e) => {
+                    warn!("upstream node {} unhealthy: {}", node.id, e);
+                    NodeStatus {
+                        healthy: false,
+                        ..node.status()
+                    }
+                }
+            };
+            *node.status.write() = status;
+        }
+        self.elect();
+        crate::metrics::NODE_HEIGHT.set(self.active().status().height as i64);
+    }
+
+    pub fn spawn_health_check(
+        self: Arc<Self>,
+        interval: Duration,
+        shutdown: Shutdown,
+    ) -> std::thread::JoinHandle<()> {
+        std::thread::spawn(move || {
+            while !shutdown.requested() {
+                self.check();
+                shutdown.sleep(interval);
+            }
+        })
+    }
+
+    // Tries the active node first, then every other node, so that blocks that have been
+    // reorged out of the active node's chain can still be fetched to be undone.
+    pub fn getblock(&self, hash: &str) -> Result<Vec<u8>, Error> {
+        let active = self.active();
+        let mut last_err = None;
+        for node in std::iter::once(&active).chain(self.nodes.iter().filter(|n| n.id != active.id))
+        {
+            match node.client.getblock(hash, false) {
+                Ok(throttled_bitcoin_rpc::reply::getblock::False(a)) => return Ok(hex::decode(a)?),
+                Ok(_) => last_err = Some(format_err!("unexpected response")),
+                Err(e) => last_err = Some(Error::from(e)),
+            }
+        }
+        Err(last_err.unwrap_or_else(|| format_err!("no upstream nodes")))
+    }
+}
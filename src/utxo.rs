@@ -1,9 +1,23 @@
-use crate::key::Bytes;
+use crate::block::coinbase_key;
+use crate::codec::Encoding;
+use crate::network::Network;
+use crate::store::Store;
 use crate::Rewind;
 use failure::Error;
-use leveldb::database::Database;
-use leveldb::kv::KV;
-use leveldb::options::*;
+
+// Archive keeps the raw bytes of every transaction under prefix 4. Utxo deletes them once a
+// transaction has no unspent outputs left; spent ones are kept in the rewind for undo.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageMode {
+    Archive,
+    Utxo,
+}
+impl Default for StorageMode {
+    fn default() -> Self {
+        StorageMode::Archive
+    }
+}
 
 #[derive(Clone, Deserialize, Serialize, Hash, PartialEq, Eq)]
 pub struct UTXOID {
@@ -49,41 +63,70 @@ impl<'a> From<UTXO<'a>> for (UTXOID, UTXOData) {
 }
 
 impl<'a> UTXO<'a> {
-    pub fn add(self, db: &Database<Bytes>, raw: Option<(&[u8], u32)>) -> Result<(), Error> {
+    pub fn address(&self) -> Option<&[u8; 21]> {
+        self.address.as_ref()
+    }
+
+    pub fn new(address: [u8; 21], txid: &'a [u8; 32], vout: u32, value: u64) -> Self {
+        UTXO {
+            address: Some(address),
+            txid,
+            vout,
+            value,
+        }
+    }
+
+    pub(crate) fn add(
+        self,
+        db: &dyn Store,
+        encoding: Encoding,
+        raw: Option<&[u8]>,
+    ) -> Result<(), Error> {
         let mut utxoid_key = Vec::with_capacity(37);
         utxoid_key.push(5_u8);
         utxoid_key.extend(self.txid);
-        if let Some((raw, c)) = raw {
-            ldb_try!(db.put(WriteOptions::new(), Bytes::from(&utxoid_key), &c.to_ne_bytes()));
+        if let Some(raw) = raw {
+            let unspents = db
+                .get(&utxoid_key)?
+                .map(|c| {
+                    let mut buf = [0_u8; 4];
+                    buf.copy_from_slice(&c);
+                    u32::from_ne_bytes(buf)
+                })
+                .unwrap_or(0)
+                + 1;
+            db.put(&utxoid_key, &unspents.to_ne_bytes())?;
             utxoid_key[0] = 4;
-            ldb_try!(db.put(WriteOptions::new(), Bytes::from(&utxoid_key), raw));
+            db.put(&utxoid_key, raw)?;
         }
         if let Some(address) = self.address {
             let mut addr_key = Vec::with_capacity(26);
             addr_key.push(1_u8);
             addr_key.extend(address.as_ref());
-            let len = ldb_try!(db.get(ReadOptions::new(), Bytes::from(&addr_key))).unwrap_or([0_u8; 4].to_vec());
+            let len = db.get(&addr_key)?.unwrap_or([0_u8; 4].to_vec());
             let mut buf = [0_u8; 4];
             if len.len() == 4 {
                 buf.clone_from_slice(&len);
             }
-            ldb_try!(db.put(WriteOptions::new(), Bytes::from(&addr_key), &(u32::from_ne_bytes(buf) + 1).to_ne_bytes()));
+            db.put(&addr_key, &(u32::from_ne_bytes(buf) + 1).to_ne_bytes())?;
             addr_key.extend(&len);
 
             utxoid_key[0] = 2;
             utxoid_key.extend(&self.vout.to_ne_bytes());
-            ldb_try!(db.put(WriteOptions::new(), Bytes::from(&utxoid_key), &addr_key));
+            db.put(&utxoid_key, &addr_key)?;
 
-            let mut addr_value = Vec::with_capacity(44);
-            addr_value.extend(self.txid);
-            addr_value.extend(&self.vout.to_ne_bytes());
-            addr_value.extend(&self.value.to_ne_bytes());
-            ldb_try!(db.put(WriteOptions::new(), Bytes::from(&addr_key), &addr_value));
+            let addr_value = encoding.encode_slot(self.txid, self.vout, self.value);
+            db.put(&addr_key, &addr_value)?;
         }
         Ok(())
     }
 
-    pub fn from_txout(txid: &'a [u8; 32], out: &'a bitcoin::TxOut, vout: u32) -> Self {
+    pub fn from_txout(
+        network: Network,
+        txid: &'a [u8; 32],
+        out: &'a bitcoin::TxOut,
+        vout: u32,
+    ) -> Self {
         UTXO {
             txid,
             vout,
@@ -98,7 +141,7 @@ impl<'a> UTXO<'a> {
                             _ => None,
                         })
                         .next();
-                    let mut buf = [crate::P2PKH; 21];
+                    let mut buf = [network.p2pkh(); 21];
                     addr.map(|a| {
                         buf[1..].clone_from_slice(a);
                         buf
@@ -112,7 +155,7 @@ impl<'a> UTXO<'a> {
                             _ => None,
                         })
                         .next();
-                    let mut buf = [crate::P2SH; 21];
+                    let mut buf = [network.p2sh(); 21];
                     addr.map(|a| {
                         buf[1..].clone_from_slice(a);
                         buf
@@ -124,70 +167,73 @@ impl<'a> UTXO<'a> {
         }
     }
 
-    pub fn from_kv(addr_key: &[u8], addr_value: &[u8]) -> Result<(UTXOID, UTXOData), Error> {
+    pub(crate) fn from_kv(
+        encoding: Encoding,
+        addr_key: &[u8],
+        addr_value: &[u8],
+    ) -> Result<(UTXOID, UTXOData), Error> {
         let mut address = [0_u8; 21];
         address.clone_from_slice(
             &addr_key
                 .get(1..22)
                 .ok_or(format_err!("unexpected end of input"))?,
         );
-        let mut txid = [0_u8; 32];
-        txid.clone_from_slice(
-            &addr_value
-                .get(0..32)
-                .ok_or(format_err!("unexpected end of input"))?,
-        );
-        let mut vout = [0_u8; 4];
-        vout.clone_from_slice(
-            &addr_value
-                .get(32..36)
-                .ok_or(format_err!("unexpected end of input"))?,
-        );
-        let mut value = [0_u8; 8];
-        value.clone_from_slice(
-            &addr_value
-                .get(36..44)
-                .ok_or(format_err!("unexpected end of input"))?,
-        );
+        let (txid, vout, value) = encoding.decode_slot(addr_value)?;
         Ok((
-            UTXOID {
-                txid,
-                vout: u32::from_ne_bytes(vout),
-            },
+            UTXOID { txid, vout },
             UTXOData {
                 address: Some(address),
-                value: u64::from_ne_bytes(value),
+                value,
             },
         ))
     }
 }
 
 impl UTXOID {
-    pub fn rem(self, db: &Database<Bytes>, idx: u32, rewind: &mut Rewind) -> Result<(), Error> {
+    pub(crate) fn rem(
+        self,
+        db: &dyn Store,
+        encoding: Encoding,
+        idx: u32,
+        rewind: &mut Rewind,
+        mode: StorageMode,
+    ) -> Result<Option<[u8; 21]>, Error> {
         let mut utxoid_key = Vec::with_capacity(37);
         utxoid_key.push(4_u8);
         utxoid_key.extend(&self.txid);
-        let raw = ldb_try!(db.get(ReadOptions::new(), Bytes::from(&utxoid_key)));
+        let raw = db.get(&utxoid_key)?;
         utxoid_key[0] = 5;
-        let unspents = ldb_try!(db.get(ReadOptions::new(), Bytes::from(&utxoid_key)))
+        let unspents = db
+            .get(&utxoid_key)?
             .map(|c| {
                 let mut buf = [0_u8; 4];
                 buf.copy_from_slice(&c);
                 u32::from_ne_bytes(buf)
             })
             .unwrap_or(0)
-            - 1;
-        if unspents == 0 {
-            ldb_try!(db.delete(WriteOptions::new(), Bytes::from(&utxoid_key)));
+            .saturating_sub(1);
+        if unspents == 0 && mode == StorageMode::Utxo {
+            db.delete(&utxoid_key)?;
+            utxoid_key[0] = 4;
+            db.delete(&utxoid_key)?;
+            // undo puts the height back if the coinbase is restored while still immature
+            db.delete(&coinbase_key(&self.txid))?;
+        } else {
+            db.put(&utxoid_key, &unspents.to_ne_bytes())?;
         }
-        ldb_try!(db.put(WriteOptions::new(), Bytes::from(&utxoid_key), &unspents.to_ne_bytes()));
         utxoid_key[0] = 2;
         utxoid_key.extend(&self.vout.to_ne_bytes());
-        let addr_key = match ldb_try!(db.get(ReadOptions::new(), Bytes::from(&utxoid_key))) {
+        let addr_key = match db.get(&utxoid_key)? {
             Some(a) => a,
-            None => return Ok(()),
+            None => {
+                // outputs without an address are only restored from the transaction
+                rewind[idx as usize % crate::CONFIRMATIONS].insert(self, (None, raw));
+                return Ok(None);
+            }
         };
-        let len = ldb_try!(db.get(ReadOptions::new(), Bytes::from(&addr_key[0..22]))).ok_or(format_err!("missing addr length"))?;
+        let len = db
+            .get(&addr_key[0..22])?
+            .ok_or(format_err!("missing addr length"))?;
         let mut buf = [0_u8; 4];
         if len.len() == 4 {
             buf.clone_from_slice(&len);
@@ -199,31 +245,34 @@ impl UTXOID {
         replacement_addr_key.extend(&addr_key[0..22]);
         replacement_addr_key.extend(&replacement_idx.to_ne_bytes());
 
-        let kv = match ldb_try!(db.get(ReadOptions::new(), Bytes::from(&addr_key))) {
+        let kv = match db.get(&addr_key)? {
             Some(addr_val) => {
-                let a = UTXO::from_kv(&addr_key, &addr_val)?;
+                let a = UTXO::from_kv(encoding, &addr_key, &addr_val)?;
                 (a.0, Some(a.1))
             }
             None => (self, None),
         };
         rewind[idx as usize % crate::CONFIRMATIONS].insert(kv.0, (kv.1, raw));
         if &replacement_idx.to_ne_bytes() != &addr_key[22..] {
-            let replacement_addr_value = ldb_try!(db.get(ReadOptions::new(), Bytes::from(&replacement_addr_key)));
+            let replacement_addr_value = db.get(&replacement_addr_key)?;
             if let Some(replacement_addr_value) = replacement_addr_value {
-                let update_index = UTXO::from_kv(&replacement_addr_key, &replacement_addr_value)?;
+                let update_index =
+                    UTXO::from_kv(encoding, &replacement_addr_key, &replacement_addr_value)?;
                 let mut replacement_utxoid_key = Vec::with_capacity(37);
                 replacement_utxoid_key.push(2_u8);
                 replacement_utxoid_key.extend(&update_index.0.txid);
                 replacement_utxoid_key.extend(&update_index.0.vout.to_ne_bytes());
-                ldb_try!(db.put(WriteOptions::new(), Bytes::from(&replacement_utxoid_key), &addr_key));
-                ldb_try!(db.put(WriteOptions::new(), Bytes::from(&addr_key), &replacement_addr_value));
+                db.put(&replacement_utxoid_key, &addr_key)?;
+                db.put(&addr_key, &replacement_addr_value)?;
             }
         }
-        ldb_try!(db.delete(WriteOptions::new(), Bytes::from(&replacement_addr_key)));
-        ldb_try!(db.delete(WriteOptions::new(), Bytes::from(&utxoid_key)));
-        ldb_try!(db.put(WriteOptions::new(), Bytes::from(&addr_key[0..22]), &replacement_idx.to_ne_bytes()));
+        db.delete(&replacement_addr_key)?;
+        db.delete(&utxoid_key)?;
+        db.put(&addr_key[0..22], &replacement_idx.to_ne_bytes())?;
 
-        Ok(())
+        let mut address = [0_u8; 21];
+        address.clone_from_slice(&addr_key[1..22]);
+        Ok(Some(address))
     }
 }
 impl<'a> From<&'a bitcoin::TxIn> for UTXOID {
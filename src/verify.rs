@@ -0,0 +1,476 @@
+use crate::codec::Encoding;
+use crate::store::Store;
+use crate::upstream::Pool;
+use failure::Error;
+use std::collections::{HashMap, HashSet};
+use throttled_bitcoin_rpc::BatchRequest;
+
+// Compares the block hash indexed at every height with the node's. Returns the number of
+// mismatches found.
+pub fn verify_chain(pool: &Pool, db: &dyn Store) -> Result<usize, Error> {
+    let next = match db.get(&[0_u8])? {
+        Some(b) => {
+            let mut buf = [0_u8; 4];
+            if b.len() != 4 {
+                bail!("invalid size for u32");
+            }
+            buf.clone_from_slice(&b);
+            u32::from_ne_bytes(buf)
+        }
+        None => 1,
+    };
+    let client = pool.active().client.clone();
+    let mut problems = 0;
+    let mut start = 1;
+    while start < next {
+        let idxs = start..std::cmp::min(start + 500, next);
+        let mut batcher = client.batcher::<String>();
+        for i in idxs.clone() {
+            batcher.getblockhash(i)?;
+        }
+        for (i, hash) in idxs.zip(batcher.send()?) {
+            let mut block_key = Vec::with_capacity(5);
+            block_key.push(3_u8);
+            block_key.extend(&i.to_ne_bytes());
+            match db.get(&block_key)? {
+                Some(ref h) if hex::encode(h) == hash => (),
+                Some(h) => {
+                    warn!(
+                        "height {}: indexed {}, node has {}",
+                        i,
+                        hex::encode(h),
+                        hash
+                    );
+                    problems += 1;
+                }
+                None => {
+                    warn!("height {}: missing block_hash", i);
+                    problems += 1;
+                }
+            }
+        }
+        start += 500;
+    }
+    info!("verified {} blocks, {} problems", next - 1, problems);
+    Ok(problems)
+}
+
+fn read_u32(b: &[u8]) -> Option<u32> {
+    if b.len() != 4 {
+        return None;
+    }
+    let mut buf = [0_u8; 4];
+    buf.clone_from_slice(b);
+    Some(u32::from_ne_bytes(buf))
+}
+
+fn output_count(db: &dyn Store, encoding: Encoding, txid: &[u8]) -> Result<Option<usize>, Error> {
+    let mut tx_key = Vec::with_capacity(33);
+    tx_key.push(4_u8);
+    tx_key.extend(txid);
+    Ok(match db.get(&tx_key)? {
+        Some(raw) => Some(encoding.decode_tx(&raw)?.output.len()),
+        None => None,
+    })
+}
+
+fn output_value(
+    db: &dyn Store,
+    encoding: Encoding,
+    txid: &[u8],
+    vout: u32,
+) -> Result<Option<u64>, Error> {
+    let mut tx_key = Vec::with_capacity(33);
+    tx_key.push(4_u8);
+    tx_key.extend(txid);
+    Ok(match db.get(&tx_key)? {
+        Some(raw) => encoding
+            .decode_tx(&raw)?
+            .output
+            .get(vout as usize)
+            .map(|o| o.value),
+        None => None,
+    })
+}
+
+struct IndexCheck<'a> {
+    db: &'a dyn Store,
+    encoding: Encoding,
+    problems: usize,
+    // addresses whose prefix 1 slots need to be rebuilt from prefix 2
+    addresses: HashSet<[u8; 21]>,
+    // txids whose prefix 5 counter should be rewritten, with the corrected value
+    counters: Vec<(Vec<u8>, u32)>,
+    stale_blocks: Vec<Vec<u8>>,
+}
+
+impl<'a> IndexCheck<'a> {
+    fn problem(&mut self, msg: String) {
+        warn!("{}", msg);
+        self.problems += 1;
+    }
+
+    fn bad_address(&mut self, address: &[u8], msg: String) {
+        let mut buf = [0_u8; 21];
+        buf.clone_from_slice(address);
+        self.addresses.insert(buf);
+        self.problem(format!("address {}: {}", hex::encode(address), msg));
+    }
+
+    fn check_meta(&mut self) -> Result<u32, Error> {
+        let db = self.db;
+        let next = match db.get(&[0_u8])? {
+            Some(b) => match read_u32(&b) {
+                Some(n) => n,
+                None => bail!("invalid height in meta key, cannot continue"),
+            },
+            None => 1,
+        };
+        match db.get(&[0_u8, 1])? {
+            Some(ref g) if g.len() == 32 => (),
+            Some(_) => self.problem("invalid genesis hash in meta key".to_owned()),
+            None if next > 1 => self.problem("missing genesis hash in meta key".to_owned()),
+            None => (),
+        }
+        Ok(next)
+    }
+
+    fn check_blocks(&mut self, next: u32) -> Result<(), Error> {
+        let db = self.db;
+        let mut found = 0;
+        for (key, value) in db.iter(&[3_u8]) {
+            if key.get(0) != Some(&3) {
+                break;
+            }
+            match key.get(1..).and_then(read_u32) {
+                Some(h) if h >= 1 && h < next && value.len() == 32 => found += 1,
+                Some(h) if h >= next => {
+                    self.problem(format!("block_hash at {} is above the tip", h));
+                    self.stale_blocks.push(key.to_vec());
+                }
+                _ => self.problem(format!("invalid block_hash entry {}", hex::encode(&*key))),
+            }
+        }
+        if found + 1 < next {
+            self.problem(format!(
+                "{} block_hashes missing below the tip",
+                next - 1 - found
+            ));
+        }
+        Ok(())
+    }
+
+    // Walks the address counters and slots, which sort together: a counter is immediately
+    // followed by the slots of the same address.
+    fn check_addresses(&mut self) -> Result<(), Error> {
+        let db = self.db;
+        let mut current: Option<(Vec<u8>, u32, u32)> = None;
+        let iter = db.iter(&[1_u8]);
+        for (key, value) in iter {
+            if key.get(0) != Some(&1) {
+                break;
+            }
+            match key.len() {
+                22 => {
+                    if let Some((address, count, seen)) = current.take() {
+                        if seen != count {
+                            self.bad_address(
+                                &address,
+                                format!("counter is {} but {} slots exist", count, seen),
+                            );
+                        }
+                    }
+                    match read_u32(&value) {
+                        Some(count) => current = Some((key[1..22].to_vec(), count, 0)),
+                        None => self.bad_address(&key[1..22], "invalid counter".to_owned()),
+                    }
+                }
+                26 => {
+                    let idx = read_u32(&key[22..26]).unwrap_or(0);
+                    let count = match current {
+                        Some((ref address, count, ref mut seen))
+                            if address.as_slice() == &key[1..22] =>
+                        {
+                            *seen += 1;
+                            count
+                        }
+                        _ => {
+                            self.bad_address(&key[1..22], format!("slot {} has no counter", idx));
+                            continue;
+                        }
+                    };
+                    if idx >= count {
+                        self.bad_address(&key[1..22], format!("slot {} is past the counter", idx));
+                        continue;
+                    }
+                    let (txid, vout, _) = match self.encoding.decode_slot(&value) {
+                        Ok(slot) => slot,
+                        Err(_) => {
+                            self.bad_address(&key[1..22], format!("slot {} is invalid", idx));
+                            continue;
+                        }
+                    };
+                    let mut utxoid_key = Vec::with_capacity(37);
+                    utxoid_key.push(2_u8);
+                    utxoid_key.extend(&txid);
+                    utxoid_key.extend(&vout.to_ne_bytes());
+                    match db.get(&utxoid_key)? {
+                        Some(ref a) if a.as_slice() == &*key => (),
+                        Some(_) => self.bad_address(
+                            &key[1..22],
+                            format!("outpoint in slot {} points to another slot", idx),
+                        ),
+                        None => self.bad_address(
+                            &key[1..22],
+                            format!("outpoint in slot {} is not in the utxo index", idx),
+                        ),
+                    }
+                }
+                _ => self.problem(format!("invalid address entry {}", hex::encode(&*key))),
+            }
+        }
+        if let Some((address, count, seen)) = current {
+            if seen != count {
+                self.bad_address(
+                    &address,
+                    format!("counter is {} but {} slots exist", count, seen),
+                );
+            }
+        }
+        Ok(())
+    }
+
+    // Walks the outpoints, which are grouped by txid, checking that each one's slot holds it
+    // and that the txid's unspent counter covers them all.
+    fn check_outpoints(&mut self) -> Result<(), Error> {
+        let db = self.db;
+        let mut group: Option<(Vec<u8>, u32)> = None;
+        let iter = db.iter(&[2_u8]);
+        for (key, addr_key) in iter {
+            if key.get(0) != Some(&2) {
+                break;
+            }
+            if key.len() != 37 || addr_key.len() != 26 || addr_key[0] != 1 {
+                self.problem(format!("invalid outpoint entry {}", hex::encode(&*key)));
+                continue;
+            }
+            match group {
+                Some((ref txid, ref mut n)) if txid.as_slice() == &key[1..33] => *n += 1,
+                _ => {
+                    if let Some((txid, n)) = group.take() {
+                        self.check_counter(&txid, n)?;
+                    }
+                    group = Some((key[1..33].to_vec(), 1));
+                }
+            }
+            let slot = db
+                .get(&addr_key)?
+                .and_then(|s| self.encoding.decode_slot(&s).ok());
+            match slot {
+                Some((txid, vout, _))
+                    if &txid[..] == &key[1..33] && Some(vout) == read_u32(&key[33..37]) => {}
+                _ => self.bad_address(
+                    &addr_key[1..22],
+                    format!(
+                        "slot {} does not hold {}:{}",
+                        read_u32(&addr_key[22..26]).unwrap_or(0),
+                        hex::encode(&key[1..33]),
+                        read_u32(&key[33..37]).unwrap_or(0)
+                    ),
+                ),
+            }
+        }
+        if let Some((txid, n)) = group {
+            self.check_counter(&txid, n)?;
+        }
+        Ok(())
+    }
+
+    // Only checks that the counter covers the indexed outputs: the upper bound and the raw
+    // transaction are checked for every counter by `check_counters`.
+    fn check_counter(&mut self, txid: &[u8], indexed: u32) -> Result<(), Error> {
+        let db = self.db;
+        let mut tx_key = Vec::with_capacity(33);
+        tx_key.push(5_u8);
+        tx_key.extend(txid);
+        let counter = db.get(&tx_key)?;
+        match counter.as_ref().map(|c| read_u32(c)) {
+            Some(Some(c)) if c >= indexed => (),
+            Some(c) => {
+                self.problem(format!(
+                    "unspent counter of {} is {:?}, {} outputs indexed",
+                    hex::encode(txid),
+                    c,
+                    indexed
+                ));
+                self.counters.push((txid.to_vec(), indexed));
+            }
+            None => {
+                self.problem(format!(
+                    "unspent counter of {} missing, {} outputs indexed",
+                    hex::encode(txid),
+                    indexed
+                ));
+                if output_count(db, self.encoding, txid)?.is_none() {
+                    self.problem(format!("raw transaction {} missing", hex::encode(txid)));
+                }
+                self.counters.push((txid.to_vec(), indexed));
+            }
+        }
+        Ok(())
+    }
+
+    // Counters of transactions with no address outputs left are not reached through prefix 2.
+    fn check_counters(&mut self) -> Result<(), Error> {
+        let db = self.db;
+        let iter = db.iter(&[5_u8]);
+        for (key, value) in iter {
+            if key.get(0) != Some(&5) {
+                break;
+            }
+            if key.len() != 33 {
+                self.problem(format!("invalid counter entry {}", hex::encode(&*key)));
+                continue;
+            }
+            let c = match read_u32(&value) {
+                Some(c) => c,
+                None => {
+                    self.problem(format!(
+                        "invalid unspent counter of {}",
+                        hex::encode(&key[1..])
+                    ));
+                    continue;
+                }
+            };
+            match output_count(db, self.encoding, &key[1..])? {
+                Some(o) if c as usize > o => {
+                    self.problem(format!(
+                        "unspent counter of {} is {}, above its {} outputs",
+                        hex::encode(&key[1..]),
+                        c,
+                        o
+                    ));
+                    self.counters.push((key[1..].to_vec(), o as u32));
+                }
+                Some(_) => (),
+                None => self.problem(format!(
+                    "raw transaction {} missing",
+                    hex::encode(&key[1..])
+                )),
+            }
+        }
+        Ok(())
+    }
+
+    fn repair(&mut self) -> Result<(), Error> {
+        let db = self.db;
+        for key in self.stale_blocks.drain(..) {
+            db.delete(&key)?;
+        }
+        for (txid, c) in self.counters.drain(..) {
+            let mut tx_key = Vec::with_capacity(33);
+            tx_key.push(5_u8);
+            tx_key.extend(&txid);
+            self.db.put(&tx_key, &c.to_ne_bytes())?;
+        }
+        if self.addresses.is_empty() {
+            return Ok(());
+        }
+        // prefix 2 is the source of truth for which outpoints an address holds
+        let mut outpoints: HashMap<[u8; 21], Vec<(Vec<u8>, Vec<u8>)>> = HashMap::new();
+        let iter = db.iter(&[2_u8]);
+        for (key, addr_key) in iter {
+            if key.get(0) != Some(&2) {
+                break;
+            }
+            if key.len() != 37 || addr_key.len() != 26 {
+                continue;
+            }
+            let mut address = [0_u8; 21];
+            address.clone_from_slice(&addr_key[1..22]);
+            if self.addresses.contains(&address) {
+                outpoints
+                    .entry(address)
+                    .or_default()
+                    .push((key.to_vec(), addr_key));
+            }
+        }
+        for address in self.addresses.drain() {
+            let mut prefix = Vec::with_capacity(22);
+            prefix.push(1_u8);
+            prefix.extend(address.as_ref());
+            let encoding = self.encoding;
+            let old: HashMap<([u8; 32], u32), u64> = db
+                .iter(&prefix)
+                .take_while(|(k, _)| k.starts_with(&prefix))
+                .filter(|(k, _)| k.len() == 26)
+                .filter_map(|(_, v)| encoding.decode_slot(&v).ok())
+                .map(|(txid, vout, value)| ((txid, vout), value))
+                .collect();
+            let keys: Vec<Vec<u8>> = db
+                .iter(&prefix)
+                .map(|(k, _)| k)
+                .take_while(|k| k.starts_with(&prefix))
+                .collect();
+            for key in keys {
+                db.delete(&key)?;
+            }
+            let mut idx = 0_u32;
+            for (utxoid_key, _) in outpoints.remove(&address).unwrap_or_default() {
+                let vout = read_u32(&utxoid_key[33..37]).unwrap_or(0);
+                let mut txid = [0_u8; 32];
+                txid.clone_from_slice(&utxoid_key[1..33]);
+                let value = match old.get(&(txid, vout)) {
+                    Some(v) => Some(*v),
+                    None => output_value(db, encoding, &txid, vout)?,
+                };
+                let value = match value {
+                    Some(v) => v,
+                    None => {
+                        warn!(
+                            "dropping {}:{}, its value is unknown",
+                            hex::encode(txid),
+                            vout
+                        );
+                        self.db.delete(&utxoid_key)?;
+                        continue;
+                    }
+                };
+                let mut addr_key = prefix.clone();
+                addr_key.extend(&idx.to_ne_bytes());
+                let addr_value = encoding.encode_slot(&txid, vout, value);
+                self.db.put(&addr_key, &addr_value)?;
+                self.db.put(&utxoid_key, &addr_key)?;
+                idx += 1;
+            }
+            db.put(&prefix, &idx.to_ne_bytes())?;
+            info!("rebuilt {} slots of address {}", idx, hex::encode(address));
+        }
+        Ok(())
+    }
+}
+
+// Checks that the meta keys, block hashes, address slots, outpoints and unspent counters agree
+// with each other. Returns the number of inconsistencies found; with `repair`, rebuilds the
+// affected address slots from the outpoint index and rewrites bad counters.
+pub fn verify_index(db: &dyn Store, repair: bool) -> Result<usize, Error> {
+    let mut check = IndexCheck {
+        db,
+        encoding: Encoding::load(db.as_read())?,
+        problems: 0,
+        addresses: HashSet::new(),
+        counters: Vec::new(),
+        stale_blocks: Vec::new(),
+    };
+    let next = check.check_meta()?;
+    check.check_blocks(next)?;
+    check.check_addresses()?;
+    check.check_outpoints()?;
+    check.check_counters()?;
+    info!("checked index, {} problems", check.problems);
+    if repair && check.problems > 0 {
+        check.repair()?;
+        info!("repaired index");
+    }
+    Ok(check.problems)
+}